@@ -3,6 +3,18 @@
 set -ex
 
 rustfmt src/lib.rs
+
+# The browser only logs shader errors, so check the shaders before building.
+mkdir -p target/shaders
+awk '
+    /^const [A-Z_]*SHADER_SOURCE: &str = r"/ {
+        file = "target/shaders/" substr($2, 1, length($2) - 1) (/VERTEX/ ? ".vert" : ".frag")
+        sub(/^[^"]*"/, "")
+    }
+    /^";$/ { close(file); file = "" }
+    file { print > file }
+' src/render.rs
+glslangValidator target/shaders/*
 wasm-pack build --release --target web
 rm pkg/.gitignore   # I need the code for GitHub Pages
 python3 -m http.server
\ No newline at end of file
@@ -0,0 +1,114 @@
+use crate::sandpile::{PackedWorld, World};
+
+use std::collections::VecDeque;
+
+/// Earlier and later states of the world, for undo and redo.
+/// The oldest states are forgotten once they take up more than the memory budget.
+pub struct History {
+    /// The oldest state is at the front.
+    undo: VecDeque<Saved>,
+    /// The state that would be redone next is at the back.
+    redo: Vec<Saved>,
+    /// The most bytes of world state to keep.
+    budget: usize,
+}
+
+impl History {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            budget,
+        }
+    }
+
+    /// Call this with the world as it was before it changed.
+    /// Anything that could have been redone is forgotten.
+    pub fn push(&mut self, world: World) {
+        self.redo.clear();
+        self.undo.push_back(Saved::new(world));
+        self.evict();
+    }
+
+    /// Go back to the previous state, if there is one. Returns whether the world changed.
+    pub fn undo(&mut self, world: &mut World) -> bool {
+        match self.undo.pop_back() {
+            Some(previous) => {
+                self.redo
+                    .push(Saved::new(std::mem::replace(world, previous.into_world())));
+                self.evict();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Go forward to the state before the last undo, if there is one. Returns whether the world changed.
+    pub fn redo(&mut self, world: &mut World) -> bool {
+        match self.redo.pop() {
+            Some(next) => {
+                self.undo
+                    .push_back(Saved::new(std::mem::replace(world, next.into_world())));
+                self.evict();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// How many states can be undone, and how many redone.
+    pub fn steps(&self) -> (usize, usize) {
+        (self.undo.len(), self.redo.len())
+    }
+
+    /// Forget the oldest states until the rest fit in the budget,
+    /// and then the states furthest in the future.
+    fn evict(&mut self) {
+        let mut used: usize = self
+            .undo
+            .iter()
+            .chain(&self.redo)
+            .map(Saved::memory_size)
+            .sum();
+        while used > self.budget {
+            let world = match self.undo.pop_front() {
+                Some(world) => world,
+                None => self.redo.remove(0),
+            };
+            used -= world.memory_size();
+        }
+    }
+}
+
+/// A state of the world, kept in as little memory as it can be.
+enum Saved {
+    /// Dense worlds are packed, so that more of them fit in the budget.
+    Packed(PackedWorld),
+    /// Chunked and symmetric worlds are kept as they are, since packing makes them dense,
+    /// and so are packed worlds, which already are.
+    Whole(Box<World>),
+}
+
+impl Saved {
+    fn new(world: World) -> Self {
+        if world.is_chunked() || world.is_symmetric() || world.is_packed() {
+            Saved::Whole(Box::new(world))
+        } else {
+            Saved::Packed(world.to_packed())
+        }
+    }
+
+    fn into_world(self) -> World {
+        match self {
+            Saved::Packed(packed) => packed.to_world(),
+            Saved::Whole(world) => *world,
+        }
+    }
+
+    fn memory_size(&self) -> usize {
+        match self {
+            Saved::Packed(packed) => packed.memory_size(),
+            Saved::Whole(world) => world.memory_size(),
+        }
+    }
+}
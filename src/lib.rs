@@ -1,10 +1,9 @@
 #![forbid(unsafe_code)]
 
 mod fps;
+mod history;
 mod render;
-mod sandpile;
-
-use render::WORLD_SIZE;
+pub mod sandpile;
 
 use std::cell::RefCell;
 use std::collections::HashSet;
@@ -39,10 +38,35 @@ struct Model {
     info_box: web_sys::HtmlParagraphElement,
     brightness_slider: web_sys::HtmlInputElement,
     opacity_slider: web_sys::HtmlInputElement,
+    speed_slider: web_sys::HtmlInputElement,
     color_sliders: [[web_sys::HtmlInputElement; 3]; 5],
 
     camera: nalgebra::Isometry3<f32>,
     world: sandpile::World,
+    /// The world as it was before each change, or after each undone change.
+    history: history::History,
+    /// Shown in the info box, until the next successful addition of sand.
+    error_message: Option<String>,
+    random_drive: sandpile::RandomDrive,
+    /// Whether grains are being dropped on random cells every frame.
+    driving: bool,
+    /// While the cells that don't burn are highlighted, how many there are.
+    unburnt: Option<usize>,
+    /// Whether cells are colored by how many times they have toppled, rather than by their heights.
+    showing_odometer: bool,
+    /// While it is shown instead of the world, the divisible sandpile with as much mass as the world has grains.
+    divisible: Option<sandpile::DivisibleWorld>,
+    /// Whether sand that is added topples a little every frame, rather than all at once.
+    playing_back: bool,
+    /// The avalanche being played back, if any, and the world as it was before it.
+    avalanche: Option<(sandpile::Avalanche, sandpile::World)>,
+    /// While sand is being added a little every frame, the world as it was before.
+    before_adding: Option<sandpile::World>,
+    /// Whether sand that is added topples a wave at a time, so the waves can be shown.
+    splitting_waves: bool,
+    /// While one of them is highlighted, the cells that toppled in each wave of the last avalanche,
+    /// and which wave it is.
+    waves: Option<(Vec<sandpile::Wave>, usize)>,
 }
 
 enum Msg {
@@ -125,7 +149,7 @@ impl State {
                     let mut layer = web_sys::VrLayer::new();
                     layer.source(Some(&model.canvas));
                     let layers = js_sys::Array::new();
-                    layers.set(0, layer.as_ref().clone());
+                    layers.set(0, AsRef::<JsValue>::as_ref(&layer).clone());
 
                     let state = self.clone();
                     let display_ = display.clone();
@@ -144,23 +168,68 @@ impl State {
                 model.keys.insert(k.to_lowercase());
 
                 match &k as &str {
-                    "Enter" => {
-                        model.world.add_sand(1);
-                        model.renderer.set_world_tex(model.world.to_color_array());
+                    "Enter" => model.add_sand(&[([0; 3], 1)]),
+                    "k" => model.add_sand(&[([0; 3], 1_000)]),
+                    "m" => model.add_sand(&[([0; 3], 1_000_000)]),
+                    "h" => {
+                        let pos = model.position_in_front();
+                        model.add_sand(&[(pos, 1_000)]);
+                    }
+                    "n" => model.add_sand(&[([0; 3], -1_000)]),
+                    "g" => {
+                        let pos = model.position_in_front();
+                        model.add_sand(&[(pos, -1_000)]);
+                    }
+                    "r" if model.world.is_symmetric() => {
+                        model.error_message = Some(SYMMETRIC_ONLY.to_string())
+                    }
+                    "r" if !model.driving && model.busy() => {}
+                    "r" => {
+                        if !model.driving {
+                            model.finish_avalanche();
+                            model.history.push(model.world.clone());
+                            model.show_divisible(false);
+                        }
+                        model.driving = !model.driving;
+                    }
+                    "p" if model.world.is_symmetric()
+                        || model.world.is_packed()
+                        || model.world.is_manna() =>
+                    {
+                        model.error_message = Some(ABELIAN_ONLY.to_string())
                     }
-                    "k" => {
-                        model.world.add_sand(1_000);
-                        model.renderer.set_world_tex(model.world.to_color_array());
+                    "p" => {
+                        model.finish_avalanche();
+                        model.playing_back = !model.playing_back;
+                        model.splitting_waves = false;
+                        model.hide_waves();
                     }
-                    "m" => {
-                        model.world.add_sand(1_000_000);
-                        model.renderer.set_world_tex(model.world.to_color_array());
+                    "i" if model.world.is_symmetric()
+                        || model.world.is_packed()
+                        || model.world.is_manna() =>
+                    {
+                        model.error_message = Some(WAVES_ABELIAN_ONLY.to_string())
                     }
+                    "i" => {
+                        model.finish_avalanche();
+                        model.playing_back = false;
+                        model.splitting_waves = !model.splitting_waves;
+                        model.hide_waves();
+                    }
+                    "[" => model.step_waves(-1),
+                    "]" => model.step_waves(1),
+                    "Escape" => model.cancel(),
+                    "z" if model.keys.contains("control") => model.undo(false),
+                    "y" if model.keys.contains("control") => model.undo(true),
+                    "b" => model.show_unburnt(model.unburnt.is_none()),
+                    "u" => model.show_odometer(!model.showing_odometer),
+                    "v" => model.show_divisible(model.divisible.is_none()),
                     "o" => {
+                        let center = model.shown_center();
                         let mut isom = nalgebra::Isometry3::translation(
-                            -((WORLD_SIZE / 2) as f32 + 0.5),
-                            -((WORLD_SIZE / 2) as f32 + 0.5),
-                            -((WORLD_SIZE / 2) as f32 + 0.5),
+                            -(center[0] as f32 + 0.5),
+                            -(center[1] as f32 + 0.5),
+                            -(center[2] as f32 + 0.5),
                         );
                         isom.append_rotation_mut(&model.camera.rotation);
                         model.camera = isom;
@@ -206,16 +275,76 @@ impl State {
                 .unwrap_throw();
         }
 
+        if model.driving {
+            let old_center = model.world.center();
+            let result = model.random_drive.drive(&mut model.world, 100);
+            model.driving = result.is_ok();
+            model.world_changed(old_center, result);
+        }
+        model.play_back();
+        if model.world.is_adding() {
+            model.keep_adding();
+        }
+
         let brightness = model.brightness();
+        let speed = model.playback_speed();
         if let Some(fps) = &mut model.fps {
             let dt = fps.frame(timestamp);
 
+            let (undo, redo) = model.history.steps();
             model.info_box.set_inner_text(&format!(
-                "{}\ntotal grains: {}\nbrightness: {}\nopacity: {}% per block",
+                "{}\ntotal grains: {}{}{}{}{}{}{}\nundo: {}, redo: {}\nbrightness: {}\nopacity: {}% per block{}",
                 fps,
                 model.world.total_grains(),
+                if model.driving { " (driving)" } else { "" },
+                match (&model.avalanche, speed) {
+                    (Some((avalanche, _)), _) => format!(
+                        "\nplaying back generation {}: {} unstable cells, {} grains left to drop",
+                        avalanche.generations(),
+                        avalanche.unstable(),
+                        avalanche.grains_left()
+                    ),
+                    (None, _) if !model.playing_back => String::new(),
+                    (None, None) => "\nplaying back a generation per frame".to_string(),
+                    (None, Some(n)) => format!("\nplaying back {} topples per frame", n),
+                },
+                match &model.waves {
+                    Some((waves, i)) => format!(
+                        "\nwave {} of {}: {} cells toppled ([ and ] to step through)",
+                        i + 1,
+                        waves.len(),
+                        waves[*i].len()
+                    ),
+                    None if model.splitting_waves => "\nsplitting avalanches into waves".to_string(),
+                    None => String::new(),
+                },
+                match model.world.progress() {
+                    Some(progress) => format!(
+                        "\nadding sand: {} of {} grains dropped, {} unstable cells (Escape to cancel)",
+                        progress.grains_processed, progress.total, progress.unstable
+                    ),
+                    None => String::new(),
+                },
+                match model.unburnt {
+                    Some(0) => "\nrecurrent".to_string(),
+                    Some(n) => format!("\n{} unburnt cells", n),
+                    None => String::new(),
+                },
+                match &model.divisible {
+                    Some(divisible) => format!(
+                        "\nshowing the divisible sandpile, in a {:?} world",
+                        divisible.size()
+                    ),
+                    None => String::new(),
+                },
+                undo,
+                redo,
                 brightness,
                 model.opacity_slider.value(),
+                match &model.error_message {
+                    Some(message) => format!("\n{}", message),
+                    None => String::new(),
+                },
             ));
 
             {
@@ -290,15 +419,22 @@ impl State {
                         viewport_size: [model.canvas.width() as i32, model.canvas.height() as i32],
                     }]
                 };
+                let colors = palette(
+                    &model
+                        .color_sliders
+                        .iter()
+                        .map(|[r, g, b]| [r, g, b].map(|x| x.value_as_number() as f32 * 0.2))
+                        .collect::<Vec<_>>(),
+                    model.world.lattice().threshold() as usize - 1,
+                );
+                // Holes are paler versions of the piles as high as they are deep.
+                let hole_colors = colors.iter().map(|c| 0.5 + 0.5 * c);
                 model.renderer.render(
                     views,
                     model.brightness(),
                     model.opacity_slider.value_as_number() as f32 * 0.01,
-                    model
-                        .color_sliders
-                        .iter()
-                        .flatten()
-                        .map(|x| x.value_as_number() as f32 * 0.2),
+                    colors.iter().copied(),
+                    hole_colors,
                 );
 
                 if let VrStatus::Presenting(display) = &model.vr_status {
@@ -342,6 +478,7 @@ padding: 10px;
 grid-template-areas:
     'canvas brightness brightness brightness'
     'canvas opacity    opacity    opacity   '
+    'canvas speed      speed      speed     '
     'canvas color_r1   color_g1   color_b1  '
     'canvas color_r2   color_g2   color_b2  '
     'canvas color_r3   color_g3   color_b3  '
@@ -411,17 +548,71 @@ bottom: 0;
             .unwrap_throw();
         body.append_child(&opacity_slider).unwrap_throw();
 
-        let mut world = sandpile::World::default();
-        world.add_sand(1);
+        let speed_slider = document
+            .create_element("input")
+            .unwrap_throw()
+            .dyn_into::<web_sys::HtmlInputElement>()
+            .unwrap_throw();
+        speed_slider.set_type("range");
+        speed_slider.set_min("0");
+        speed_slider.set_max("20");
+        speed_slider.set_value("0");
+        speed_slider
+            .style()
+            .set_property("grid-area", "speed")
+            .unwrap_throw();
+        body.append_child(&speed_slider).unwrap_throw();
+
+        let query = window.location().search().unwrap_throw();
+        let size = query_param(&query, "size")
+            .and_then(parse_world_size)
+            .unwrap_or([128, 128, 128]);
+
+        let mut renderer = render::Renderer::new(&canvas, size);
 
-        let mut renderer = render::Renderer::new(&canvas);
-        renderer.set_world_tex(world.to_color_array());
+        let lattice = query_param(&query, "lattice")
+            .and_then(parse_lattice)
+            .unwrap_or(sandpile::Lattice::Cubic6);
+        let boundary = if query_param(&query, "boundary") == Some("sink") {
+            sandpile::Boundary::Sink
+        } else {
+            sandpile::Boundary::Wall
+        };
+        let seed = query_param(&query, "seed")
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or(0);
+
+        let world = if query_param(&query, "start") == Some("identity") {
+            sandpile::World::identity(size, lattice)
+        } else {
+            let mut world = match query_param(&query, "storage") {
+                Some("chunked") => sandpile::World::chunked(size, renderer.max_world_size()),
+                Some("packed") => sandpile::World::packed(size, renderer.max_world_size()),
+                // A cube, an odd number of cells across, has the same symmetries as the pile.
+                Some("symmetric") => {
+                    sandpile::World::symmetric([(size[0] - 1) | 1; 3], renderer.max_world_size())
+                }
+                _ => sandpile::World::growable(size, renderer.max_world_size()),
+            }
+            .with_lattice(lattice)
+            .with_boundary(boundary);
+            if query_param(&query, "toppling") == Some("manna") && !world.is_symmetric() {
+                world = world.with_manna(seed);
+            }
+            world.add_sand(1);
+            world
+        };
+        if world.size() != renderer.world_size() {
+            renderer.resize_world(world.size());
+        }
+        renderer.set_world_tex(|x, out| world.unpack_slab(x, out));
 
         let camera = {
-            let x = (WORLD_SIZE / 2) as f32;
+            let center = world.center();
+            let [x, y, z] = [center[0] as f32, center[1] as f32, center[2] as f32];
             nalgebra::Isometry3::look_at_rh(
-                &nalgebra::Point3::new(x + 1.499, x + 1.499, x + 2.499),
-                &nalgebra::Point3::new(x + 0.5, x + 0.5, x + 0.5),
+                &nalgebra::Point3::new(x + 1.499, y + 1.499, z + 2.499),
+                &nalgebra::Point3::new(x + 0.5, y + 0.5, z + 0.5),
                 &nalgebra::Vector3::y(),
             )
         };
@@ -485,14 +676,466 @@ bottom: 0;
             info_box,
             brightness_slider,
             opacity_slider,
+            speed_slider,
             color_sliders,
 
             camera,
             world,
+            history: history::History::new(256 << 20),
+            error_message: None,
+            random_drive: sandpile::RandomDrive::uniform(seed),
+            driving: false,
+            unburnt: None,
+            showing_odometer: false,
+            divisible: None,
+            playing_back: false,
+            avalanche: None,
+            before_adding: None,
+            splitting_waves: false,
+            waves: None,
+        }
+    }
+
+    /// Add grains to cells relative to the center of the world, or take them away if negative.
+    fn add_sand(&mut self, drops: &[([isize; 3], isize)]) {
+        if self.world.is_symmetric() && drops.iter().any(|&(pos, _)| pos != [0; 3]) {
+            self.error_message = Some(SYMMETRIC_ONLY.to_string());
+            return;
+        }
+        if self.busy() {
+            return;
+        }
+        self.finish_avalanche();
+        let old_center = self.world.center();
+        let old_world = self.world.clone();
+        if self.splitting_waves {
+            let (pos, num_grains) = match *drops {
+                [(pos, n)] if n > 0 => (pos, n as usize),
+                _ => {
+                    self.error_message = Some(ONE_CELL_ONLY.to_string());
+                    return;
+                }
+            };
+            match self.world.try_add_sand_in_waves(pos, num_grains) {
+                Ok((_, waves)) => {
+                    self.show_divisible(false);
+                    self.driving = false;
+                    self.history.push(old_world);
+                    self.world_changed(old_center, Ok(()));
+                    self.show_wave(waves, 0);
+                }
+                Err(err) => self.world_changed(old_center, Err(err.into())),
+            }
+            return;
+        }
+        if self.playing_back {
+            match self.world.start_avalanche(drops) {
+                Ok(avalanche) => {
+                    self.show_unburnt(false);
+                    self.show_odometer(false);
+                    self.show_divisible(false);
+                    self.driving = false;
+                    self.avalanche = Some((avalanche, old_world));
+                    self.world_changed(old_center, Ok(()));
+                }
+                Err(err) => self.world_changed(old_center, Err(err.into())),
+            }
+            return;
+        }
+        self.world.start_adding(drops);
+        self.driving = false;
+        self.before_adding = Some(old_world);
+        self.keep_adding();
+    }
+
+    /// Carry on adding sand, for as long as a frame can spare.
+    fn keep_adding(&mut self) {
+        let old_center = self.world.center();
+        let deadline = js_sys::Date::now() + ADDING_TIME;
+        match self.world.keep_adding(|| js_sys::Date::now() > deadline) {
+            // Show how far the avalanche has got, leaving everything else until it is over.
+            None => {
+                if self.divisible.is_none() {
+                    self.show_heights(old_center);
+                }
+            }
+            // On failure, the world is back the way it was, but it may have been shown partway.
+            Some(result) => {
+                let old_world = self.before_adding.take().unwrap();
+                if result.is_ok() {
+                    self.history.push(old_world);
+                }
+                self.world_changed(old_center, Ok(()));
+                if let Err(err) = result {
+                    self.error_message = Some(err.to_string());
+                }
+            }
+        }
+    }
+
+    /// Stop adding sand, or playing back an avalanche, and go back to the world as it was before.
+    fn cancel(&mut self) {
+        let old_center = self.world.center();
+        if self.world.is_adding() {
+            self.world.cancel_adding();
+            self.before_adding = None;
+        } else if let Some((avalanche, _)) = self.avalanche.take() {
+            avalanche.cancel(&mut self.world);
+        } else {
+            return;
+        }
+        self.world_changed(old_center, Ok(()));
+    }
+
+    /// Whether sand is still being added, in which case the world can't change any other way yet.
+    fn busy(&mut self) -> bool {
+        let busy = self.world.is_adding();
+        if busy {
+            self.error_message = Some(BUSY.to_string());
+        }
+        busy
+    }
+
+    /// Carry on the avalanche being played back, if any, as far as the speed slider says.
+    fn play_back(&mut self) {
+        let speed = self.playback_speed();
+        if let Some((avalanche, _)) = &mut self.avalanche {
+            let old_center = self.world.center();
+            let result = match speed {
+                Some(n) => avalanche.advance(&mut self.world, n),
+                None => avalanche.next_generation(&mut self.world),
+            };
+            self.avalanche_changed(old_center, result);
+        }
+    }
+
+    /// Play the rest of the avalanche back at once, if there is one.
+    fn finish_avalanche(&mut self) {
+        if let Some((avalanche, _)) = &mut self.avalanche {
+            let old_center = self.world.center();
+            let result = avalanche.advance(&mut self.world, u64::MAX);
+            self.avalanche_changed(old_center, result);
+        }
+    }
+
+    /// Show the world, after playing back some of the avalanche.
+    fn avalanche_changed(
+        &mut self,
+        old_center: [usize; 3],
+        result: Result<bool, sandpile::OverflowError>,
+    ) {
+        match result {
+            Ok(false) => self.world_changed(old_center, Ok(())),
+            Ok(true) => {
+                let (avalanche, old_world) = self.avalanche.take().unwrap();
+                avalanche.finish(&mut self.world);
+                self.history.push(old_world);
+                self.world_changed(old_center, Ok(()));
+            }
+            // The world is back the way it was, so show that, along with the error.
+            Err(err) => {
+                self.avalanche = None;
+                self.world_changed(old_center, Ok(()));
+                self.error_message = Some(err.to_string());
+            }
+        }
+    }
+
+    /// How many topples to play back every frame, or `None` for a generation every frame.
+    fn playback_speed(&self) -> Option<u64> {
+        match self.speed_slider.value_as_number() as u32 {
+            0 => None,
+            n => Some(1 << (n - 1)),
+        }
+    }
+
+    /// Go back to the world before the last change, or forward again if `redo`.
+    /// Sand being added, or an avalanche being played back, is cancelled instead.
+    fn undo(&mut self, redo: bool) {
+        if self.world.is_adding() || self.avalanche.is_some() {
+            self.cancel();
+            return;
+        }
+        let old_center = self.world.center();
+        let moved = if redo {
+            self.history.redo(&mut self.world)
+        } else {
+            self.history.undo(&mut self.world)
+        };
+        if moved {
+            // A world from before the odometer was shown, or hidden, may or may not be counting.
+            self.world.set_odometer(self.showing_odometer);
+            self.driving = false;
+            self.world_changed(old_center, Ok(()));
+        }
+    }
+
+    /// Show the world, after trying to add sand to it.
+    fn world_changed(
+        &mut self,
+        old_center: [usize; 3],
+        result: Result<(), sandpile::ArithmeticError>,
+    ) {
+        match result {
+            Ok(()) => {
+                self.error_message = None;
+                self.hide_waves();
+                if self.divisible.is_some() {
+                    self.show_divisible(true);
+                    return;
+                }
+                self.show_heights(old_center);
+                if self.unburnt.is_some() {
+                    self.show_unburnt(true);
+                }
+                if self.showing_odometer {
+                    self.show_odometer(true);
+                }
+            }
+            Err(err) => self.error_message = Some(err.to_string()),
         }
     }
 
+    /// Upload the heights of the world, resizing it first if it has grown.
+    fn show_heights(&mut self, old_center: [usize; 3]) {
+        if self.world.size() != self.renderer.world_size() {
+            self.renderer.resize_world(self.world.size());
+            self.recenter_camera(old_center, self.world.center());
+        }
+        let world = &self.world;
+        self.renderer
+            .set_world_tex(|x, out| world.unpack_slab(x, out));
+    }
+
+    /// Keep the camera in the same place relative to the pile, after the cell at its center moves.
+    fn recenter_camera(&mut self, old_center: [usize; 3], center: [usize; 3]) {
+        self.camera *= nalgebra::Translation3::new(
+            old_center[0] as f32 - center[0] as f32,
+            old_center[1] as f32 - center[1] as f32,
+            old_center[2] as f32 - center[2] as f32,
+        );
+    }
+
+    /// Highlight the cells that don't burn, or stop highlighting them.
+    fn show_unburnt(&mut self, show: bool) {
+        if show && self.busy() {
+            return;
+        }
+        if show && self.divisible.is_some() {
+            self.show_divisible(false);
+        }
+        if show {
+            self.finish_avalanche();
+            self.waves = None;
+            let unburnt = self.world.unburnt();
+            let mut mask = self.empty_mask();
+            self.mark(&mut mask, &unburnt, 1);
+            self.renderer.set_mask_tex(Some(&mask));
+            self.unburnt = Some(unburnt.len());
+        } else {
+            self.renderer.set_mask_tex(None);
+            self.unburnt = None;
+        }
+    }
+
+    /// Highlight the cells that toppled in one of the waves of an avalanche in red,
+    /// and those that toppled in earlier waves in blue. Nothing is shown if no wave toppled.
+    fn show_wave(&mut self, waves: Vec<sandpile::Wave>, index: usize) {
+        self.show_unburnt(false);
+        if waves.is_empty() {
+            return;
+        }
+        let mut mask = self.empty_mask();
+        for wave in &waves[..index] {
+            self.mark(&mut mask, wave, 2);
+        }
+        self.mark(&mut mask, &waves[index], 1);
+        self.renderer.set_mask_tex(Some(&mask));
+        self.waves = Some((waves, index));
+    }
+
+    /// Show the wave `step` later than the one shown, or earlier if negative, stopping at the first and last.
+    fn step_waves(&mut self, step: isize) {
+        if let Some((waves, index)) = self.waves.take() {
+            let index = (index as isize + step).max(0).min(waves.len() as isize - 1);
+            self.show_wave(waves, index as usize);
+        }
+    }
+
+    fn hide_waves(&mut self) {
+        if self.waves.take().is_some() {
+            self.renderer.set_mask_tex(None);
+        }
+    }
+
+    /// A mask laid out like the world texture, with nothing highlighted.
+    fn empty_mask(&self) -> Vec<u8> {
+        let size = self.world.size();
+        vec![0; size[0] * size[1] * size[2]]
+    }
+
+    /// Set `mask` to `value` at each of `cells`, relative to the center.
+    fn mark(&self, mask: &mut [u8], cells: &[[isize; 3]], value: u8) {
+        let (size, center) = (self.world.size(), self.world.center());
+        for pos in cells {
+            let [x, y, z] = [0, 1, 2].map(|i| (center[i] as isize + pos[i]) as usize);
+            mask[(x * size[1] + y) * size[2] + z] = value;
+        }
+    }
+
+    /// Color cells by how many times they topple from now on, or go back to coloring them by height.
+    /// The world only counts topples while they are shown.
+    fn show_odometer(&mut self, show: bool) {
+        if show && self.busy() {
+            return;
+        }
+        if show && self.divisible.is_some() {
+            self.show_divisible(false);
+        }
+        if show {
+            self.finish_avalanche();
+        }
+        self.world.set_odometer(show);
+        self.renderer
+            .set_odometer_tex(self.world.odometer().as_deref());
+        self.showing_odometer = show;
+    }
+
+    /// Show the divisible sandpile with as much mass as the world has grains, or go back to showing the world.
+    /// The divisible sandpile is wider, so it gets a world of its own, with the same center.
+    fn show_divisible(&mut self, show: bool) {
+        if show && self.busy() {
+            return;
+        }
+        if show {
+            self.finish_avalanche();
+        }
+        let old_center = self.shown_center();
+        self.divisible = None;
+        if show {
+            self.show_unburnt(false);
+            self.hide_waves();
+            self.show_odometer(false);
+            let max_size = self.renderer.max_world_size();
+            let mut divisible = sandpile::DivisibleWorld::growable(self.world.size(), max_size)
+                .with_lattice(self.world.lattice());
+            let mass = self.world.total_grains().max(0) as f32;
+            match divisible.try_add_mass_at([0; 3], mass) {
+                Ok(()) => self.divisible = Some(divisible),
+                Err(err) => self.error_message = Some(err.to_string()),
+            }
+        }
+
+        let size = match &self.divisible {
+            Some(divisible) => divisible.size(),
+            None => self.world.size(),
+        };
+        if size != self.renderer.world_size() {
+            self.renderer.resize_world(size);
+        }
+        self.recenter_camera(old_center, self.shown_center());
+        match &self.divisible {
+            Some(divisible) => self.renderer.set_mass_tex(Some(divisible.masses())),
+            None => {
+                self.renderer.set_mass_tex(None);
+                let world = &self.world;
+                self.renderer
+                    .set_world_tex(|x, out| world.unpack_slab(x, out));
+            }
+        }
+    }
+
+    /// The cell where the center of the shown pile is.
+    fn shown_center(&self) -> [usize; 3] {
+        match &self.divisible {
+            Some(divisible) => divisible.center(),
+            None => self.world.center(),
+        }
+    }
+
+    /// The cell a few steps in front of the camera, relative to the center of the world.
+    fn position_in_front(&self) -> [isize; 3] {
+        let point = self
+            .camera
+            .inverse_transform_point(&nalgebra::Point3::new(0., 0., -4.));
+        let center = self.shown_center();
+        [
+            point[0].floor() as isize - center[0] as isize,
+            point[1].floor() as isize - center[1] as isize,
+            point[2].floor() as isize - center[2] as isize,
+        ]
+    }
+
     fn brightness(&self) -> f32 {
         ((self.brightness_slider.value_as_number() as f32 - 22.) * 0.2).exp()
     }
 }
+
+const SYMMETRIC_ONLY: &str = "Symmetric worlds only take sand at the center.";
+const ABELIAN_ONLY: &str =
+    "Only abelian worlds that are not symmetric or packed can be played back.";
+const WAVES_ABELIAN_ONLY: &str =
+    "Only abelian worlds that are not symmetric or packed can be split into waves.";
+const ONE_CELL_ONLY: &str = "Only sand added to a single cell can be split into waves.";
+const BUSY: &str = "Still adding sand. Press Escape to cancel.";
+
+/// How many milliseconds of every frame can be spent adding sand.
+const ADDING_TIME: f64 = 10.;
+
+/// Look up `key` in a query string like `?size=32&storage=chunked`.
+fn query_param<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+    query.trim_start_matches('?').split('&').find_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        if parts.next() == Some(key) {
+            parts.next()
+        } else {
+            None
+        }
+    })
+}
+
+/// Parse world dimensions like `32` or `64x32x64`.
+fn parse_world_size(value: &str) -> Option<[usize; 3]> {
+    let dims = value
+        .split('x')
+        .map(|n| n.parse().ok().filter(|&n| n >= 3))
+        .collect::<Option<Vec<usize>>>()?;
+
+    match dims[..] {
+        [n] => Some([n, n, n]),
+        [nx, ny, nz] => Some([nx, ny, nz]),
+        _ => None,
+    }
+}
+
+/// Parse a lattice name like `cubic6` or `fcc12`.
+fn parse_lattice(value: &str) -> Option<sandpile::Lattice> {
+    use sandpile::Lattice::*;
+    match value {
+        "cubic6" => Some(Cubic6),
+        "cubic18" => Some(Cubic18),
+        "cubic26" => Some(Cubic26),
+        "bcc8" => Some(Bcc8),
+        "fcc12" => Some(Fcc12),
+        _ => None,
+    }
+}
+
+/// The colors of heights `1..=max_height`, blending evenly between the `keys`.
+/// With as many heights as keys, these are just the keys.
+fn palette(keys: &[[f32; 3]], max_height: usize) -> Vec<f32> {
+    let mut out = Vec::with_capacity(3 * max_height);
+    for height in 1..=max_height {
+        let t = if max_height > 1 {
+            (height - 1) as f32 * (keys.len() - 1) as f32 / (max_height - 1) as f32
+        } else {
+            0.
+        };
+        let i = (t as usize).min(keys.len() - 2);
+        let frac = t - i as f32;
+        for (a, b) in keys[i].iter().zip(&keys[i + 1]) {
+            out.push(a * (1. - frac) + b * frac);
+        }
+    }
+    out
+}
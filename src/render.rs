@@ -12,7 +12,18 @@ pub struct View {
 pub struct Renderer {
     canvas: web_sys::HtmlCanvasElement,
     gl: GL,
+    world_size: [usize; 3],
     world_tex: web_sys::WebGlTexture,
+    /// Cells to highlight, laid out like the world texture.
+    mask_tex: web_sys::WebGlTexture,
+    /// How many times each cell has toppled, laid out like the world texture.
+    odometer_tex: web_sys::WebGlTexture,
+    /// The largest value in the odometer texture, or zero to show the world texture instead.
+    odometer_max: u32,
+    /// The mass of each cell of a divisible sandpile, which has its own size.
+    mass_tex: web_sys::WebGlTexture,
+    /// Whether to show the mass texture, rather than the world or odometer textures.
+    showing_mass: bool,
 
     program: web_sys::WebGlProgram,
     vao: web_sys::WebGlVertexArrayObject,
@@ -29,6 +40,9 @@ pub struct Renderer {
 impl Drop for Renderer {
     fn drop(&mut self) {
         self.gl.delete_texture(Some(&self.world_tex));
+        self.gl.delete_texture(Some(&self.mask_tex));
+        self.gl.delete_texture(Some(&self.odometer_tex));
+        self.gl.delete_texture(Some(&self.mass_tex));
 
         self.gl.delete_program(Some(&self.program));
         self.gl.delete_vertex_array(Some(&self.vao));
@@ -45,7 +59,7 @@ impl Drop for Renderer {
 
 impl Renderer {
     /// LEAVES WORLD TEXTURE UNINITIALIZED.
-    pub fn new(canvas: &web_sys::HtmlCanvasElement) -> Self {
+    pub fn new(canvas: &web_sys::HtmlCanvasElement, world_size: [usize; 3]) -> Self {
         let gl = canvas
             .get_context("webgl2")
             .unwrap_throw()
@@ -80,50 +94,22 @@ impl Renderer {
         gl.enable_vertex_attrib_array(attribute_normal);
         gl.vertex_attrib_pointer_with_i32(attribute_normal, 3, GL::FLOAT, false, 6 * 4, 3 * 4);
 
-        {
-            let mut data: Vec<f32> = Vec::new();
-
-            #[rustfmt::skip]
-            for i in 0..=WORLD_SIZE {
-                data.extend_from_slice(&[
-                    i as f32, 0.               , 0.               , 1., 0., 0.,
-                    i as f32, WORLD_SIZE as f32, 0.               , 1., 0., 0.,
-                    i as f32, WORLD_SIZE as f32, WORLD_SIZE as f32, 1., 0., 0.,
-                    i as f32, WORLD_SIZE as f32, WORLD_SIZE as f32, 1., 0., 0.,
-                    i as f32, 0.               , WORLD_SIZE as f32, 1., 0., 0.,
-                    i as f32, 0.               , 0.               , 1., 0., 0.,
-
-                    0.               , i as f32, 0.               , 0., 1., 0.,
-                    WORLD_SIZE as f32, i as f32, 0.               , 0., 1., 0.,
-                    WORLD_SIZE as f32, i as f32, WORLD_SIZE as f32, 0., 1., 0.,
-                    WORLD_SIZE as f32, i as f32, WORLD_SIZE as f32, 0., 1., 0.,
-                    0.               , i as f32, WORLD_SIZE as f32, 0., 1., 0.,
-                    0.               , i as f32, 0.               , 0., 1., 0.,
-
-                    0.               , 0.               , i as f32, 0., 0., 1.,
-                    WORLD_SIZE as f32, 0.               , i as f32, 0., 0., 1.,
-                    WORLD_SIZE as f32, WORLD_SIZE as f32, i as f32, 0., 0., 1.,
-                    WORLD_SIZE as f32, WORLD_SIZE as f32, i as f32, 0., 0., 1.,
-                    0.               , WORLD_SIZE as f32, i as f32, 0., 0., 1.,
-                    0.               , 0.               , i as f32, 0., 0., 1.,
-
-                ]);
-            };
-
-            gl.buffer_data_with_array_buffer_view(
-                GL::ARRAY_BUFFER,
-                &as_f32_array(&data).into(),
-                GL::STATIC_DRAW,
-            );
-        }
+        gl.buffer_data_with_array_buffer_view(
+            GL::ARRAY_BUFFER,
+            &as_f32_array(&slice_vertices(world_size)).into(),
+            GL::STATIC_DRAW,
+        );
 
-        let world_tex = gl.create_texture().unwrap_throw();
-        gl.bind_texture(GL::TEXTURE_3D, Some(&world_tex));
-        gl.tex_parameteri(GL::TEXTURE_3D, GL::TEXTURE_MIN_FILTER, GL::NEAREST as i32);
-        gl.tex_parameteri(GL::TEXTURE_3D, GL::TEXTURE_MAG_FILTER, GL::NEAREST as i32);
-        gl.tex_parameteri(GL::TEXTURE_3D, GL::TEXTURE_WRAP_S, GL::CLAMP_TO_EDGE as i32);
-        gl.tex_parameteri(GL::TEXTURE_3D, GL::TEXTURE_WRAP_T, GL::CLAMP_TO_EDGE as i32);
-        gl.tex_parameteri(GL::TEXTURE_3D, GL::TEXTURE_WRAP_R, GL::CLAMP_TO_EDGE as i32);
+        let [world_tex, mask_tex, odometer_tex, mass_tex] = [(); 4].map(|()| {
+            let tex = gl.create_texture().unwrap_throw();
+            gl.bind_texture(GL::TEXTURE_3D, Some(&tex));
+            gl.tex_parameteri(GL::TEXTURE_3D, GL::TEXTURE_MIN_FILTER, GL::NEAREST as i32);
+            gl.tex_parameteri(GL::TEXTURE_3D, GL::TEXTURE_MAG_FILTER, GL::NEAREST as i32);
+            gl.tex_parameteri(GL::TEXTURE_3D, GL::TEXTURE_WRAP_S, GL::CLAMP_TO_EDGE as i32);
+            gl.tex_parameteri(GL::TEXTURE_3D, GL::TEXTURE_WRAP_T, GL::CLAMP_TO_EDGE as i32);
+            gl.tex_parameteri(GL::TEXTURE_3D, GL::TEXTURE_WRAP_R, GL::CLAMP_TO_EDGE as i32);
+            tex
+        });
 
         /*
 
@@ -183,10 +169,16 @@ impl Renderer {
             GL::STATIC_DRAW,
         );
 
-        Self {
+        let mut renderer = Self {
             canvas: canvas.clone(),
             gl,
+            world_size,
             world_tex,
+            mask_tex,
+            odometer_tex,
+            odometer_max: 0,
+            mass_tex,
+            showing_mass: false,
 
             program,
             vao,
@@ -198,35 +190,185 @@ impl Renderer {
             postprocess_program,
             postprocess_vao,
             postprocess_vertex_buffer,
-        }
+        };
+        renderer.set_mask_tex(None);
+        renderer.set_odometer_tex(None);
+        renderer.set_mass_tex(None);
+        renderer
+    }
+
+    /// The largest world the GPU can hold in a texture.
+    pub fn max_world_size(&self) -> [usize; 3] {
+        let n = self
+            .gl
+            .get_parameter(GL::MAX_3D_TEXTURE_SIZE)
+            .unwrap_throw()
+            .as_f64()
+            .unwrap_throw() as usize;
+        [n, n, n]
+    }
+
+    pub fn world_size(&self) -> [usize; 3] {
+        self.world_size
+    }
+
+    /// Call this when the world changes size, before the next call to `set_world_tex`.
+    pub fn resize_world(&mut self, world_size: [usize; 3]) {
+        self.world_size = world_size;
+
+        self.gl
+            .bind_buffer(GL::ARRAY_BUFFER, Some(&self.vertex_buffer));
+        self.gl.buffer_data_with_array_buffer_view(
+            GL::ARRAY_BUFFER,
+            &as_f32_array(&slice_vertices(world_size)),
+            GL::STATIC_DRAW,
+        );
     }
 
     /// BORDER MUST BE BLACK
-    pub fn set_world_tex(&mut self, data: &[u8]) {
+    ///
+    /// Uploads the heights a slab at a time, so that a big world never has to be unpacked all at once:
+    /// `slab(x, out)` fills `out` with the cells whose first coordinate is `x`,
+    /// laid out as in `sandpile::World`, so the last coordinate varies fastest.
+    pub fn set_world_tex(&mut self, mut slab: impl FnMut(usize, &mut [i8])) {
+        let [nx, ny, nz] = self.world_size;
+
+        // The texture's width, height and depth are the world's z, y and x dimensions.
         self.gl.bind_texture(GL::TEXTURE_3D, Some(&self.world_tex));
         self.gl.pixel_storei(GL::UNPACK_ALIGNMENT, 1);
+        self.gl
+            .tex_image_3d_with_opt_array_buffer_view(
+                GL::TEXTURE_3D,
+                0,               // level
+                GL::R8I as i32,  // internal_format
+                nz as i32,       // width
+                ny as i32,       // height
+                nx as i32,       // depth
+                0,               // border
+                GL::RED_INTEGER, // format
+                GL::BYTE,        // type
+                None,
+            )
+            .unwrap_throw();
+
+        let mut data = vec![0; ny * nz];
+        for x in 0..nx {
+            slab(x, &mut data);
+            self.gl
+                .tex_sub_image_3d_with_opt_array_buffer_view(
+                    GL::TEXTURE_3D,
+                    0,               // level
+                    0,               // xoffset
+                    0,               // yoffset
+                    x as i32,        // zoffset
+                    nz as i32,       // width
+                    ny as i32,       // height
+                    1,               // depth
+                    GL::RED_INTEGER, // format
+                    GL::BYTE,        // type
+                    Some(&as_i8_array(&data)),
+                )
+                .unwrap_throw();
+        }
+    }
+
+    /// Highlight the cells where `mask` is nonzero, or none of them: in red where it is 1, and in blue where it is 2.
+    /// The mask is laid out like the world texture, and must be set again after the world changes size.
+    pub fn set_mask_tex(&mut self, mask: Option<&[u8]>) {
+        let [nx, ny, nz] = match mask {
+            Some(_) => self.world_size,
+            None => [1; 3],
+        };
+        let mask = mask.unwrap_or(&[0]);
+        assert_eq!(mask.len(), nx * ny * nz);
+
+        self.gl.bind_texture(GL::TEXTURE_3D, Some(&self.mask_tex));
+        self.gl.pixel_storei(GL::UNPACK_ALIGNMENT, 1);
         self.gl
             .tex_image_3d_with_opt_u8_array(
                 GL::TEXTURE_3D,
                 0,                 // level
                 GL::R8UI as i32,   // internal_format
-                WORLD_SIZE as i32, // width
-                WORLD_SIZE as i32, // height
-                WORLD_SIZE as i32, // depth
+                nz as i32,         // width
+                ny as i32,         // height
+                nx as i32,         // depth
                 0,                 // border
                 GL::RED_INTEGER,   // format
                 GL::UNSIGNED_BYTE, // type
-                Some(data),
+                Some(mask),
+            )
+            .unwrap_throw();
+    }
+
+    /// Color each cell by how many times it has toppled, on a continuous scale, instead of by its height.
+    /// `None` goes back to showing heights.
+    /// The odometer is laid out like the world texture, and must be set again after the world changes size.
+    pub fn set_odometer_tex(&mut self, odometer: Option<&[u32]>) {
+        let [nx, ny, nz] = match odometer {
+            Some(_) => self.world_size,
+            None => [1; 3],
+        };
+        let odometer = odometer.unwrap_or(&[0]);
+        assert_eq!(odometer.len(), nx * ny * nz);
+        self.odometer_max = odometer.iter().copied().max().unwrap_or(0);
+
+        self.gl
+            .bind_texture(GL::TEXTURE_3D, Some(&self.odometer_tex));
+        self.gl.pixel_storei(GL::UNPACK_ALIGNMENT, 4);
+        self.gl
+            .tex_image_3d_with_opt_array_buffer_view(
+                GL::TEXTURE_3D,
+                0,                // level
+                GL::R32UI as i32, // internal_format
+                nz as i32,        // width
+                ny as i32,        // height
+                nx as i32,        // depth
+                0,                // border
+                GL::RED_INTEGER,  // format
+                GL::UNSIGNED_INT, // type
+                Some(&as_u32_array(odometer)),
+            )
+            .unwrap_throw();
+    }
+
+    /// Color each cell by its mass, on a continuous scale from 0 to 1, instead of by its height or odometer.
+    /// `None` goes back to showing those.
+    /// The masses are laid out like the world texture, whose size must be changed to match first.
+    pub fn set_mass_tex(&mut self, mass: Option<&[f32]>) {
+        let [nx, ny, nz] = match mass {
+            Some(_) => self.world_size,
+            None => [1; 3],
+        };
+        self.showing_mass = mass.is_some();
+        let mass = mass.unwrap_or(&[0.]);
+        assert_eq!(mass.len(), nx * ny * nz);
+
+        self.gl.bind_texture(GL::TEXTURE_3D, Some(&self.mass_tex));
+        self.gl.pixel_storei(GL::UNPACK_ALIGNMENT, 4);
+        self.gl
+            .tex_image_3d_with_opt_array_buffer_view(
+                GL::TEXTURE_3D,
+                0,               // level
+                GL::R32F as i32, // internal_format
+                nz as i32,       // width
+                ny as i32,       // height
+                nx as i32,       // depth
+                0,               // border
+                GL::RED,         // format
+                GL::FLOAT,       // type
+                Some(&as_f32_array(mass)),
             )
             .unwrap_throw();
     }
 
+    /// `colors` are for heights from 1 up, and `hole_colors` for heights from -1 down.
     pub fn render(
         &self,
         views: Vec<View>,
         brightness: f32,
         opacity: f32,
         colors: impl IntoIterator<Item = f32>,
+        hole_colors: impl IntoIterator<Item = f32>,
     ) {
         self.gl
             .bind_framebuffer(GL::FRAMEBUFFER, Some(&self.framebuffer));
@@ -241,12 +383,66 @@ impl Renderer {
             0,
         );
 
-        #[rustfmt::skip]
+        self.gl.active_texture(GL::TEXTURE1);
+        self.gl.bind_texture(GL::TEXTURE_3D, Some(&self.mask_tex));
+        self.gl.uniform1i(
+            self.gl.get_uniform_location(&self.program, "mask").as_ref(),
+            1,
+        );
+
+        self.gl.active_texture(GL::TEXTURE2);
+        self.gl
+            .bind_texture(GL::TEXTURE_3D, Some(&self.odometer_tex));
+        self.gl.uniform1i(
+            self.gl
+                .get_uniform_location(&self.program, "odometer")
+                .as_ref(),
+            2,
+        );
+        self.gl.uniform1f(
+            self.gl
+                .get_uniform_location(&self.program, "odometer_max")
+                .as_ref(),
+            self.odometer_max as f32,
+        );
+
+        self.gl.active_texture(GL::TEXTURE3);
+        self.gl.bind_texture(GL::TEXTURE_3D, Some(&self.mass_tex));
+        self.gl.uniform1i(
+            self.gl.get_uniform_location(&self.program, "mass").as_ref(),
+            3,
+        );
+        self.gl.uniform1i(
+            self.gl
+                .get_uniform_location(&self.program, "showing_mass")
+                .as_ref(),
+            self.showing_mass as i32,
+        );
+        self.gl.active_texture(GL::TEXTURE0);
+
+        self.gl.uniform3f(
+            self.gl
+                .get_uniform_location(&self.program, "world_size")
+                .as_ref(),
+            self.world_size[0] as f32,
+            self.world_size[1] as f32,
+            self.world_size[2] as f32,
+        );
+
+        // Height `h` is at index `h + MAX_HEIGHT`, so the deepest holes come first. Empty cells are black.
+        let colors: Vec<f32> = colors.into_iter().collect();
+        let hole_colors: Vec<f32> = hole_colors.into_iter().collect();
+        let mut all_colors = vec![0.0; 3 * (2 * MAX_HEIGHT + 1)];
+        all_colors[3 * (MAX_HEIGHT + 1)..][..colors.len()].copy_from_slice(&colors);
+        for (depth, color) in hole_colors.chunks(3).enumerate() {
+            let i = MAX_HEIGHT - 1 - depth;
+            all_colors[3 * i..3 * i + 3].copy_from_slice(color);
+        }
         self.gl.uniform3fv_with_f32_array(
             self.gl
                 .get_uniform_location(&self.program, "colors")
                 .as_ref(),
-            &[0.0, 0.0, 0.0].iter().copied().chain(colors).collect::<Vec<_>>()
+            &all_colors,
         );
 
         self.gl.clear_color(0., 0., 0., 1.);
@@ -292,8 +488,11 @@ impl Renderer {
                 view.viewport_size[0],
                 view.viewport_size[1],
             );
-            self.gl
-                .draw_arrays(GL::TRIANGLES, 0, 6 * 3 * (WORLD_SIZE + 1) as i32);
+            self.gl.draw_arrays(
+                GL::TRIANGLES,
+                0,
+                6 * self.world_size.iter().map(|&n| n + 1).sum::<usize>() as i32,
+            );
         }
 
         self.gl.bind_framebuffer(GL::FRAMEBUFFER, None);
@@ -348,6 +547,10 @@ fn compile_program(gl: &GL, v_source: &str, f_source: &str) -> web_sys::WebGlPro
     program
 }
 
+/// The highest stable height on any lattice, and the depth of the deepest hole.
+/// The fragment shader has this built in too, and clamps heights to it.
+const MAX_HEIGHT: usize = 25;
+
 const VERTEX_SHADER_SOURCE: &str = r"#version 300 es
 
 in vec3 pos;
@@ -378,6 +581,8 @@ void main() {
 const FRAGMENT_SHADER_SOURCE: &str = r"#version 300 es
 precision mediump float;
 precision mediump usampler3D;
+precision mediump isampler3D;
+precision mediump sampler3D;
 
 in vec3 vpos;
 in vec3 vnormal;
@@ -385,15 +590,66 @@ in vec3 vnormal;
 out vec4 color;
 
 uniform vec3 camera_pos;
-uniform usampler3D world;
+uniform isampler3D world;
+uniform usampler3D mask;
+uniform usampler3D odometer;
+// Zero to color cells by height instead.
+uniform float odometer_max;
+uniform sampler3D mass;
+uniform bool showing_mass;
 uniform float brightness;
 uniform float opacity;
-uniform vec3 colors[6];
+// The highest stable height on any lattice, and the depth of the deepest hole.
+const int MAX_HEIGHT = 25;
+// Enough for every lattice, from the deepest hole to the highest pile.
+uniform vec3 colors[2 * MAX_HEIGHT + 1];
+uniform vec3 world_size;
+
+// From blue through cyan, green and yellow to red, as t goes from 0 to 1.
+vec3 colormap(float t) {
+    vec3 keys[5] = vec3[5](
+        vec3(0.0, 0.0, 1.0),
+        vec3(0.0, 0.8, 0.8),
+        vec3(0.0, 1.0, 0.0),
+        vec3(0.8, 0.8, 0.0),
+        vec3(1.0, 0.0, 0.0)
+    );
+    float x = clamp(t, 0.0, 1.0) * 4.0;
+    int i = min(int(x), 3);
+    return mix(keys[i], keys[i + 1], x - float(i));
+}
 
-const float world_size = 128.0;
+// Cells that never toppled are black, and the rest are colored on a log scale.
+vec3 odometer_color(vec3 tex_pos) {
+    uint n = texture(odometer, tex_pos).r;
+    if (n == 0u) {
+        return vec3(0.0);
+    }
+    return colormap(log(float(n)) / max(log(odometer_max), 1.0));
+}
+
+// Empty cells are black, and the rest go from blue to red as they fill up.
+vec3 mass_color(vec3 tex_pos) {
+    float m = texture(mass, tex_pos).r;
+    if (m <= 0.0) {
+        return vec3(0.0);
+    }
+    return colormap(m);
+}
 
+// Highlighted cells are tinted red, or blue, and shown even if empty.
 vec3 get_color(vec3 pos) {
-    return colors[texture(world, pos / world_size).r];
+    vec3 tex_pos = (pos / world_size).zyx;
+    vec3 color = showing_mass ? mass_color(tex_pos)
+        : odometer_max > 0.0 ? odometer_color(tex_pos)
+        : colors[clamp(texture(world, tex_pos).r, -MAX_HEIGHT, MAX_HEIGHT) + MAX_HEIGHT];
+    uint highlight = texture(mask, tex_pos).r;
+    if (highlight == 1u) {
+        color = mix(color, vec3(1.0, 0.0, 0.0), 0.75);
+    } else if (highlight != 0u) {
+        color = mix(color, vec3(0.0, 0.3, 1.0), 0.75);
+    }
+    return color;
 }
 
 // ∫opacity^x dx
@@ -414,8 +670,55 @@ void main() {
 }
 ";
 
-// Value separately defined in fragment shader above.
-pub const WORLD_SIZE: usize = 128;
+/// One square for each plane between layers of cells, in each of the three directions.
+/// Each vertex is a position followed by a normal.
+fn slice_vertices(world_size: [usize; 3]) -> Vec<f32> {
+    let [nx, ny, nz] = [
+        world_size[0] as f32,
+        world_size[1] as f32,
+        world_size[2] as f32,
+    ];
+
+    let mut data: Vec<f32> = Vec::new();
+
+    #[rustfmt::skip]
+    for i in 0..=world_size[0] {
+        data.extend_from_slice(&[
+            i as f32, 0., 0., 1., 0., 0.,
+            i as f32, ny, 0., 1., 0., 0.,
+            i as f32, ny, nz, 1., 0., 0.,
+            i as f32, ny, nz, 1., 0., 0.,
+            i as f32, 0., nz, 1., 0., 0.,
+            i as f32, 0., 0., 1., 0., 0.,
+        ]);
+    };
+
+    #[rustfmt::skip]
+    for i in 0..=world_size[1] {
+        data.extend_from_slice(&[
+            0., i as f32, 0., 0., 1., 0.,
+            nx, i as f32, 0., 0., 1., 0.,
+            nx, i as f32, nz, 0., 1., 0.,
+            nx, i as f32, nz, 0., 1., 0.,
+            0., i as f32, nz, 0., 1., 0.,
+            0., i as f32, 0., 0., 1., 0.,
+        ]);
+    };
+
+    #[rustfmt::skip]
+    for i in 0..=world_size[2] {
+        data.extend_from_slice(&[
+            0., 0., i as f32, 0., 0., 1.,
+            nx, 0., i as f32, 0., 0., 1.,
+            nx, ny, i as f32, 0., 0., 1.,
+            nx, ny, i as f32, 0., 0., 1.,
+            0., ny, i as f32, 0., 0., 1.,
+            0., 0., i as f32, 0., 0., 1.,
+        ]);
+    };
+
+    data
+}
 
 fn as_f32_array(v: &[f32]) -> js_sys::Float32Array {
     let memory_buffer = wasm_bindgen::memory()
@@ -428,6 +731,28 @@ fn as_f32_array(v: &[f32]) -> js_sys::Float32Array {
     js_sys::Float32Array::new(&memory_buffer).subarray(location, location + v.len() as u32)
 }
 
+fn as_i8_array(v: &[i8]) -> js_sys::Int8Array {
+    let memory_buffer = wasm_bindgen::memory()
+        .dyn_into::<js_sys::WebAssembly::Memory>()
+        .unwrap_throw()
+        .buffer();
+
+    let location = v.as_ptr() as u32;
+
+    js_sys::Int8Array::new(&memory_buffer).subarray(location, location + v.len() as u32)
+}
+
+fn as_u32_array(v: &[u32]) -> js_sys::Uint32Array {
+    let memory_buffer = wasm_bindgen::memory()
+        .dyn_into::<js_sys::WebAssembly::Memory>()
+        .unwrap_throw()
+        .buffer();
+
+    let location = v.as_ptr() as u32 / 4;
+
+    js_sys::Uint32Array::new(&memory_buffer).subarray(location, location + v.len() as u32)
+}
+
 const POSTPROCESS_VERTEX_SHADER_SOURCE: &str = r"#version 300 es
 
 in vec2 coord;
@@ -1,70 +1,1186 @@
-use super::render::WORLD_SIZE;
-use std::ops::{Index, IndexMut};
+mod adding;
+mod avalanche;
+mod burning;
+mod chunks;
+mod divisible;
+mod drive;
+mod estimate;
+mod fixed_energy;
+mod manna;
+mod packed;
+mod stats;
+mod symmetric;
+mod topple;
+mod waves;
+
+pub use adding::Progress;
+pub use avalanche::Avalanche;
+pub use divisible::DivisibleWorld;
+pub use drive::RandomDrive;
+pub use fixed_energy::FixedEnergyWorld;
+pub use packed::PackedWorld;
+pub use stats::{AvalancheHistograms, AvalancheStats, Histogram};
+use topple::{Bounds, Tally, Toppling};
+pub use waves::Wave;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::{Add, Index, IndexMut, Mul};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 
 pub struct World {
-    data: Vec<u8>,
-    total_grains: usize,
+    size: [usize; 3],
+    /// The world grows, up to this size, rather than letting an avalanche reach the boundary layer.
+    max_size: [usize; 3],
+    /// Where sand is added.
+    center: [usize; 3],
+    storage: Storage,
+    lattice: Lattice,
+    boundary: Boundary,
+    /// The number of grains in the world, less the depth of any holes,
+    /// not counting any that fell into a sink.
+    total_grains: isize,
+    /// How many times each cell has toppled, stored like the heights, if that is being counted.
+    odometer: Option<Vec<u32>>,
+    /// With the Manna rule, the seed for the random numbers of the next addition of sand.
+    manna: Option<u64>,
+    /// Only kept while adding sand, so that a failed addition can be undone.
+    backup: Option<Backup>,
+    /// What is left to do, while sand is being added a little at a time.
+    pending: Option<Box<adding::Pending>>,
+}
+
+/// What happens to grains that topple onto the outermost layer of cells.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Boundary {
+    /// They never do. The world grows to keep them in, up to its maximum size,
+    /// and adding sand fails beyond that.
+    Wall,
+    /// They are lost. The world never grows, and adding sand inside it always succeeds.
+    Sink,
+}
+
+/// Which cells are neighbors. Each is named after the lattice it models, and its number of neighbors.
+/// A cell topples when it has a grain for every neighbor,
+/// and untopples, taking a grain from each neighbor, when it has a hole that deep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lattice {
+    /// Neighbors share a face.
+    Cubic6,
+    /// Neighbors share a face or an edge.
+    Cubic18,
+    /// Neighbors share a face, an edge, or a corner.
+    Cubic26,
+    /// Body-centered cubic. Neighbors share only a corner,
+    /// so sand stays on the cells whose coordinates all have the same parities as the center.
+    Bcc8,
+    /// Face-centered cubic. Neighbors share only an edge,
+    /// so sand stays on the cells whose coordinates add up to the same parity as the center.
+    Fcc12,
+}
+
+impl Lattice {
+    pub const ALL: [Lattice; 5] = [
+        Lattice::Cubic6,
+        Lattice::Cubic18,
+        Lattice::Cubic26,
+        Lattice::Bcc8,
+        Lattice::Fcc12,
+    ];
+
+    /// The offsets from a cell to each of its neighbors.
+    pub fn neighbors(self) -> Vec<[isize; 3]> {
+        let mut out = Vec::new();
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    let num_nonzero = [dx, dy, dz].iter().filter(|&&d| d != 0).count();
+                    let is_neighbor = match self {
+                        Lattice::Cubic6 => num_nonzero == 1,
+                        Lattice::Cubic18 => num_nonzero == 1 || num_nonzero == 2,
+                        Lattice::Cubic26 => num_nonzero != 0,
+                        Lattice::Bcc8 => num_nonzero == 3,
+                        Lattice::Fcc12 => num_nonzero == 2,
+                    };
+                    if is_neighbor {
+                        out.push([dx, dy, dz]);
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// How many grains make a cell topple. Stable cells hold fewer than this, and have shallower holes.
+    pub fn threshold(self) -> u8 {
+        self.neighbors().len() as u8
+    }
+}
+
+const CHUNK_SIZE: usize = 16;
+type Chunk = [i8; CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE];
+
+#[derive(Clone)]
+enum Storage {
+    /// Every cell of the world, in the same order as `World::to_color_array`.
+    Dense(Vec<i8>),
+    /// Cubes of `CHUNK_SIZE` cells on a side, keyed by their signed position relative to the center.
+    /// Chunks that have never been touched are empty, and are not stored.
+    Chunked(HashMap<[isize; 3], Box<Chunk>>),
+    /// Only the cells with `x >= y >= z >= 0` relative to the center, stored as in `symmetric::wedge_index`.
+    /// The rest are reflections of these, as long as sand is only added at the center.
+    Symmetric(Vec<i8>),
+    /// Every cell of the world, in the same order as `Dense`, packed into a few bits each.
+    /// Sand is added to a dense window onto the cells around it; see the `packed` module.
+    Packed(packed::PackedHeights),
+}
+
+/// Which chunk a position is in, and where in that chunk.
+fn chunk_location(pos: [isize; 3]) -> ([isize; 3], usize) {
+    let n = CHUNK_SIZE as isize;
+    let key = [
+        pos[0].div_euclid(n),
+        pos[1].div_euclid(n),
+        pos[2].div_euclid(n),
+    ];
+    let offset = (pos[0].rem_euclid(n) * n + pos[1].rem_euclid(n)) * n + pos[2].rem_euclid(n);
+    (key, offset as usize)
+}
+
+fn chunk_cell(chunks: &HashMap<[isize; 3], Box<Chunk>>, pos: [isize; 3]) -> &i8 {
+    let (key, offset) = chunk_location(pos);
+    chunks.get(&key).map_or(&0, |chunk| &chunk[offset])
+}
+
+fn symmetric_cell(wedge: &[i8], pos: [isize; 3]) -> &i8 {
+    wedge
+        .get(symmetric::wedge_index(symmetric::canonical(pos)))
+        .unwrap_or(&0)
+}
+
+const SYMMETRIC_CHANGE: &str = "Symmetric worlds only take sand at the center.";
+
+const PACKED_CHANGE: &str = "Packed worlds can't change a cell at a time.";
+
+fn chunk_cell_mut(chunks: &mut HashMap<[isize; 3], Box<Chunk>>, pos: [isize; 3]) -> &mut i8 {
+    let (key, offset) = chunk_location(pos);
+    &mut chunks
+        .entry(key)
+        .or_insert_with(|| Box::new([0; CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE]))[offset]
 }
 
 impl Default for World {
     fn default() -> Self {
+        Self::new([128, 128, 128])
+    }
+}
+
+impl Clone for World {
+    fn clone(&self) -> Self {
         Self {
-            data: vec![0; WORLD_SIZE * WORLD_SIZE * WORLD_SIZE],
-            total_grains: 0,
+            storage: self.storage.clone(),
+            odometer: self.odometer.clone(),
+            backup: None,
+            pending: None,
+            ..*self
         }
     }
 }
 
+/// The stabilization of the sum of two worlds; see `World::add_world`.
+impl Add<&World> for World {
+    type Output = World;
+    fn add(mut self, other: &World) -> World {
+        self.add_world(other);
+        self
+    }
+}
+impl Add for World {
+    type Output = World;
+    fn add(self, other: World) -> World {
+        self + &other
+    }
+}
+
+/// The stabilization of every height times a factor; see `World::multiply`.
+impl Mul<usize> for World {
+    type Output = World;
+    fn mul(mut self, factor: usize) -> World {
+        self.multiply(factor);
+        self
+    }
+}
+
 impl Index<[usize; 3]> for World {
-    type Output = u8;
-    fn index(&self, idx: [usize; 3]) -> &u8 {
-        &self.data[(idx[0] * WORLD_SIZE + idx[1]) * WORLD_SIZE + idx[2]]
+    type Output = i8;
+    fn index(&self, idx: [usize; 3]) -> &i8 {
+        match &self.storage {
+            Storage::Dense(data) => &data[self.linear_index(idx)],
+            Storage::Chunked(chunks) => chunk_cell(chunks, self.position(idx)),
+            Storage::Symmetric(wedge) => symmetric_cell(wedge, self.position(idx)),
+            Storage::Packed(heights) => packed::height_ref(heights.get(self.linear_index(idx))),
+        }
     }
 }
 impl IndexMut<[usize; 3]> for World {
-    fn index_mut(&mut self, idx: [usize; 3]) -> &mut u8 {
-        &mut self.data[(idx[0] * WORLD_SIZE + idx[1]) * WORLD_SIZE + idx[2]]
+    fn index_mut(&mut self, idx: [usize; 3]) -> &mut i8 {
+        let linear_index = self.linear_index(idx);
+        let pos = self.position(idx);
+        match &mut self.storage {
+            Storage::Dense(data) => &mut data[linear_index],
+            Storage::Chunked(chunks) => chunk_cell_mut(chunks, pos),
+            Storage::Symmetric(_) => panic!("{}", SYMMETRIC_CHANGE),
+            Storage::Packed(_) => panic!("{}", PACKED_CHANGE),
+        }
+    }
+}
+
+/// Signed coordinates, relative to the center.
+/// In a dense world, these must lie within the world's bounds;
+/// in a chunked world, they are unbounded.
+impl Index<[isize; 3]> for World {
+    type Output = i8;
+    fn index(&self, pos: [isize; 3]) -> &i8 {
+        match &self.storage {
+            Storage::Dense(data) => &data[self.linear_index(self.grid_index(pos))],
+            Storage::Chunked(chunks) => chunk_cell(chunks, pos),
+            Storage::Symmetric(wedge) => symmetric_cell(wedge, pos),
+            Storage::Packed(heights) => {
+                packed::height_ref(heights.get(self.linear_index(self.grid_index(pos))))
+            }
+        }
+    }
+}
+impl IndexMut<[isize; 3]> for World {
+    fn index_mut(&mut self, pos: [isize; 3]) -> &mut i8 {
+        match self.storage {
+            Storage::Dense(_) => {
+                let idx = self.grid_index(pos);
+                &mut self[idx]
+            }
+            Storage::Chunked(ref mut chunks) => chunk_cell_mut(chunks, pos),
+            Storage::Symmetric(_) => panic!("{}", SYMMETRIC_CHANGE),
+            Storage::Packed(_) => panic!("{}", PACKED_CHANGE),
+        }
     }
 }
 
 impl World {
-    pub fn add_sand(&mut self, num_grains: usize) {
-        self.total_grains += num_grains;
-
-        let mut todo: Vec<([usize; 3], usize)> =
-            vec![([WORLD_SIZE / 2, WORLD_SIZE / 2, WORLD_SIZE / 2], num_grains)];
-
-        while let Some((loc, num_grains)) = todo.pop() {
-            if loc.iter().all(|&x| 0 < x && x < WORLD_SIZE - 1) {
-                let pile = &mut self[loc];
-
-                let pile_grains = *pile as usize + num_grains;
-
-                let num_topples = pile_grains / 6;
-                *pile = (pile_grains % 6) as u8;
-
-                if num_topples > 0 {
-                    #[rustfmt::skip]
-                    todo.extend_from_slice(&[
-                        {let mut loc = loc; loc[0] += 1; (loc, num_topples)},
-                        {let mut loc = loc; loc[0] -= 1; (loc, num_topples)},
-                        {let mut loc = loc; loc[1] += 1; (loc, num_topples)},
-                        {let mut loc = loc; loc[1] -= 1; (loc, num_topples)},
-                        {let mut loc = loc; loc[2] += 1; (loc, num_topples)},
-                        {let mut loc = loc; loc[2] -= 1; (loc, num_topples)},
-                    ]);
+    /// Create an empty world with the given dimensions.
+    /// The outermost layer of cells is never filled, so each dimension must be at least 3.
+    pub fn new(size: [usize; 3]) -> Self {
+        Self::growable(size, size)
+    }
+
+    /// Create an empty world with the given dimensions,
+    /// which doubles in size whenever an avalanche reaches its boundary, until it reaches `max_size`.
+    pub fn growable(size: [usize; 3], max_size: [usize; 3]) -> Self {
+        Self::with_storage(
+            size,
+            max_size,
+            Storage::Dense(vec![0; size[0] * size[1] * size[2]]),
+        )
+    }
+
+    /// Like `growable`, but only allocates memory for the parts of the world that sand has reached.
+    /// Growing a chunked world is free, so `max_size` may be as large as you like.
+    pub fn chunked(size: [usize; 3], max_size: [usize; 3]) -> Self {
+        Self::with_storage(size, max_size, Storage::Chunked(HashMap::new()))
+    }
+
+    /// Like `growable`, but only stores about one cell in 48, using the symmetries of a cube.
+    /// Sand can only be added at the center, so `try_add_world` and `try_multiply` return an error instead.
+    /// The world must be a cube, and with a sink, an odd number of cells across.
+    pub fn symmetric(size: [usize; 3], max_size: [usize; 3]) -> Self {
+        assert!(
+            size.iter().all(|&n| n == size[0]) && max_size.iter().all(|&n| n == max_size[0]),
+            "Symmetric worlds must be cubes."
+        );
+        Self::with_storage(size, max_size, Storage::Symmetric(Vec::new()))
+    }
+
+    /// Like `growable`, but with heights packed into as few bits as they need, which is 3 for a stable pile
+    /// with no holes on `Lattice::Cubic6`. Sand is added in a dense window about as big as the avalanche,
+    /// so only that much is ever unpacked. Cells can't be changed one at a time, with `IndexMut`.
+    pub fn packed(size: [usize; 3], max_size: [usize; 3]) -> Self {
+        let len = size.iter().product();
+        Self::with_storage(
+            size,
+            max_size,
+            Storage::Packed(packed::PackedHeights::new(len)),
+        )
+    }
+
+    fn with_storage(size: [usize; 3], max_size: [usize; 3], storage: Storage) -> Self {
+        assert!(
+            size.iter().all(|&n| n >= 3),
+            "World dimensions must be at least 3."
+        );
+
+        Self {
+            size,
+            max_size,
+            center: [size[0] / 2, size[1] / 2, size[2] / 2],
+            storage,
+            lattice: Lattice::Cubic6,
+            boundary: Boundary::Wall,
+            total_grains: 0,
+            odometer: None,
+            manna: None,
+            backup: None,
+            pending: None,
+        }
+    }
+
+    /// The stabilization of the sum of two worlds, which must have the same lattice and boundary.
+    /// The result is shaped like `a`, grown if need be. See `add_world`.
+    pub fn stabilized_sum(a: &World, b: &World) -> World {
+        a.clone() + b
+    }
+
+    /// The identity of the sandpile group of a box of the given dimensions, with a sink boundary.
+    /// Adding it to any world that can be reached by adding sand to the maximal stable world leaves that world unchanged.
+    ///
+    /// It is the stabilization of `2m - stab(2m)`, where `m` is the maximal stable world,
+    /// with every cell one grain short of toppling.
+    pub fn identity(size: [usize; 3], lattice: Lattice) -> Self {
+        let mut world = Self::new(size)
+            .with_lattice(lattice)
+            .with_boundary(Boundary::Sink);
+        let twice_max = 2 * (lattice.threshold() as i8 - 1);
+        let interior = Bounds {
+            lo: [1; 3],
+            hi: [size[0] - 2, size[1] - 2, size[2] - 2],
+        };
+
+        world.fill(interior, |_| twice_max);
+        world
+            .stabilize(interior, Tally::new(size, Vec::new(), false))
+            .unwrap();
+        world.fill(interior, |h| twice_max - h);
+        world
+            .stabilize(interior, Tally::new(size, Vec::new(), false))
+            .unwrap();
+
+        world.total_grains = world.dense().iter().map(|&h| h as isize).sum();
+        world
+    }
+
+    /// Use a different lattice than the default `Lattice::Cubic6`.
+    /// The world must not have any sand yet.
+    pub fn with_lattice(mut self, lattice: Lattice) -> Self {
+        assert_eq!(
+            self.total_grains, 0,
+            "Cannot change the lattice of a sandpile."
+        );
+        self.lattice = lattice;
+        self
+    }
+
+    /// Use a different boundary than the default `Boundary::Wall`.
+    /// The world must not have any sand yet.
+    pub fn with_boundary(mut self, boundary: Boundary) -> Self {
+        assert_eq!(
+            self.total_grains, 0,
+            "Cannot change the boundary of a sandpile."
+        );
+        assert!(
+            !self.is_symmetric() || boundary == Boundary::Wall || self.size[0] % 2 == 1,
+            "Symmetric worlds with a sink must be an odd number of cells across."
+        );
+        self.boundary = boundary;
+        self
+    }
+
+    /// Count how many times each cell topples, from now on; see `odometer`.
+    pub fn with_odometer(mut self) -> Self {
+        self.set_odometer(true);
+        self
+    }
+
+    /// Count how many times each cell topples from now on, keeping any counts so far,
+    /// or stop counting and forget them.
+    /// Avalanches are cheaper in big worlds when they aren't counted.
+    pub fn set_odometer(&mut self, on: bool) {
+        if !on {
+            self.odometer = None;
+        } else if self.odometer.is_none() {
+            self.odometer = Some(match &self.storage {
+                Storage::Symmetric(wedge) => vec![0; wedge.len()],
+                _ => vec![0; self.size.iter().product()],
+            });
+        }
+    }
+
+    /// Topple by the Manna rule, sending each grain to a random neighbor, with random numbers from `seed`.
+    /// The same seed and the same additions always give the same world. See the `manna` module.
+    /// The world must not have any sand yet, and cannot be symmetric.
+    pub fn with_manna(mut self, seed: u64) -> Self {
+        assert_eq!(
+            self.total_grains, 0,
+            "Cannot change the toppling rule of a sandpile."
+        );
+        assert!(
+            !self.is_symmetric(),
+            "Symmetric worlds cannot use the Manna rule."
+        );
+        self.manna = Some(seed);
+        self
+    }
+
+    /// Add grains to the center pile, and topple until stable.
+    /// Panics if the avalanche reaches the boundary layer; see `try_add_sand`.
+    pub fn add_sand(&mut self, num_grains: usize) -> AvalancheStats {
+        self.add_sand_at([0; 3], num_grains)
+    }
+
+    /// Add grains to the center pile, and topple until stable.
+    /// If the avalanche reaches the boundary layer, and the world cannot grow any further,
+    /// the world is left as it was before the call.
+    pub fn try_add_sand(&mut self, num_grains: usize) -> Result<AvalancheStats, OverflowError> {
+        self.start_adding(&[([0; 3], num_grains as isize)]);
+        self.keep_adding(|| false).unwrap()
+    }
+
+    /// Add grains to the cell at `pos`, relative to the center, and topple until stable.
+    /// Panics if the avalanche reaches the boundary layer; see `try_add_sand_at`.
+    pub fn add_sand_at(&mut self, pos: [isize; 3], num_grains: usize) -> AvalancheStats {
+        self.add_sand_multi(&[(pos, num_grains)])
+    }
+
+    /// Like `try_add_sand`, but adds the grains to the cell at `pos`, relative to the center.
+    /// The world grows to fit `pos` if it can.
+    /// A symmetric world only takes sand at the center, so adding it anywhere else is an error,
+    /// and leaves the world as it was.
+    pub fn try_add_sand_at(
+        &mut self,
+        pos: [isize; 3],
+        num_grains: usize,
+    ) -> Result<AvalancheStats, ArithmeticError> {
+        self.try_add_sand_multi(&[(pos, num_grains)])
+    }
+
+    /// Add grains to several cells, relative to the center, and topple until stable.
+    /// Panics if the avalanche reaches the boundary layer; see `try_add_sand_multi`.
+    pub fn add_sand_multi(&mut self, drops: &[([isize; 3], usize)]) -> AvalancheStats {
+        self.try_add_sand_multi(drops)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Add grains to several cells, relative to the center, and topple once they have all been added.
+    /// If the avalanche reaches the boundary layer, and the world cannot grow any further,
+    /// the world is left as it was before the call.
+    /// Sand away from the center of a symmetric world is an error, as with `try_add_sand_at`.
+    pub fn try_add_sand_multi(
+        &mut self,
+        drops: &[([isize; 3], usize)],
+    ) -> Result<AvalancheStats, ArithmeticError> {
+        let drops: Vec<_> = drops.iter().map(|&(pos, n)| (pos, n as isize)).collect();
+        self.try_add_signed_multi(&drops)
+    }
+
+    /// Take grains away from the cell at `pos`, relative to the center, digging a hole if it runs out,
+    /// and topple until stable. Panics if the avalanche reaches the boundary layer; see `try_remove_sand_at`.
+    pub fn remove_sand_at(&mut self, pos: [isize; 3], num_grains: usize) -> AvalancheStats {
+        self.add_signed_multi(&[(pos, -(num_grains as isize))])
+    }
+
+    /// Like `try_add_sand_at`, but takes grains away instead; see `remove_sand_at`.
+    pub fn try_remove_sand_at(
+        &mut self,
+        pos: [isize; 3],
+        num_grains: usize,
+    ) -> Result<AvalancheStats, ArithmeticError> {
+        self.try_add_signed_multi(&[(pos, -(num_grains as isize))])
+    }
+
+    /// Like `add_sand_multi`, but negative numbers of grains are taken away.
+    pub fn add_signed_multi(&mut self, drops: &[([isize; 3], isize)]) -> AvalancheStats {
+        self.try_add_signed_multi(drops)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `try_add_sand_multi`, but negative numbers of grains are taken away.
+    /// With grains added to some cells and taken from others, the result depends on the order of toppling,
+    /// but is the same every time.
+    pub fn try_add_signed_multi(
+        &mut self,
+        drops: &[([isize; 3], isize)],
+    ) -> Result<AvalancheStats, ArithmeticError> {
+        self.check_symmetric(drops)?;
+        self.start_adding(drops);
+        Ok(self.keep_adding(|| false).unwrap()?)
+    }
+
+    /// Add the heights of `other` to this world's, cell by cell, lined up by their centers,
+    /// and topple until stable. This is the sum in the sandpile group.
+    /// Both worlds must have the same lattice and boundary.
+    /// Panics if the avalanche reaches the boundary layer, or if this world is symmetric; see `try_add_world`.
+    pub fn add_world(&mut self, other: &World) -> AvalancheStats {
+        self.try_add_world(other)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `add_world`, but if the avalanche reaches the boundary layer, and the world cannot grow any further,
+    /// the world is left as it was before the call.
+    /// A symmetric world only takes sand at the center, so adding anything else to it is an error,
+    /// and leaves it as it was too.
+    pub fn try_add_world(&mut self, other: &World) -> Result<AvalancheStats, ArithmeticError> {
+        assert_eq!(
+            (self.lattice, self.boundary),
+            (other.lattice, other.boundary),
+            "Cannot add sandpiles with different lattices or boundaries."
+        );
+        self.try_add_signed_multi(&other.piles(1))
+    }
+
+    /// Multiply every height by `factor`, and topple until stable.
+    /// This is the same as adding the world to an empty one `factor` times.
+    /// Panics if the avalanche reaches the boundary layer, or if the world is symmetric; see `try_multiply`.
+    pub fn multiply(&mut self, factor: usize) -> AvalancheStats {
+        self.try_multiply(factor)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `multiply`, but if the avalanche reaches the boundary layer, and the world cannot grow any further,
+    /// the world is left as it was before the call.
+    /// A stable symmetric world has sand away from the center, so multiplying it is an error, unless it is empty.
+    pub fn try_multiply(&mut self, factor: usize) -> Result<AvalancheStats, ArithmeticError> {
+        let piles = self.piles(factor);
+        self.check_symmetric(&piles)?;
+        let storage = match self.storage {
+            Storage::Dense(_) => Storage::Dense(vec![0; self.size.iter().product()]),
+            Storage::Chunked(_) => Storage::Chunked(HashMap::new()),
+            Storage::Symmetric(_) => Storage::Symmetric(Vec::new()),
+            Storage::Packed(_) => {
+                Storage::Packed(packed::PackedHeights::new(self.size.iter().product()))
+            }
+        };
+        let mut product = World {
+            storage,
+            total_grains: 0,
+            odometer: self.odometer.clone(),
+            backup: None,
+            pending: None,
+            ..*self
+        };
+        let stats = product.try_add_signed_multi(&piles)?;
+        *self = product;
+        Ok(stats)
+    }
+
+    /// Whether `drops` can be added to this world: a symmetric world only takes them at the center.
+    fn check_symmetric(&self, drops: &[([isize; 3], isize)]) -> Result<(), ArithmeticError> {
+        if self.is_symmetric() && drops.iter().any(|&(pos, n)| pos != [0; 3] && n != 0) {
+            return Err(ArithmeticError::Symmetric);
+        }
+        Ok(())
+    }
+
+    /// Every cell with sand or a hole, relative to the center, and `factor` times its height.
+    fn piles(&self, factor: usize) -> Vec<([isize; 3], isize)> {
+        let size = self.size;
+        let mut out = Vec::new();
+        for (i, &height) in self.to_color_array().iter().enumerate() {
+            if height != 0 {
+                let idx = [i / (size[1] * size[2]), i / size[2] % size[1], i % size[2]];
+                out.push((self.position(idx), height as isize * factor as isize));
+            }
+        }
+        out
+    }
+
+    /// Grow the world until every drop is inside the boundary layer.
+    /// On failure, returns the boundary cell nearest the drop that didn't fit.
+    fn fit(&mut self, drops: &[([isize; 3], isize)]) -> Result<(), [usize; 3]> {
+        for &(pos, _) in drops {
+            loop {
+                let mut axes = [false; 3];
+                let mut cell = [0; 3];
+                for i in 0..3 {
+                    let x = pos[i] + self.center[i] as isize;
+                    let last = self.size[i] as isize - 1;
+                    axes[i] = x < 1 || x >= last;
+                    cell[i] = x.max(0).min(last) as usize;
+                }
+                if axes == [false; 3] {
+                    break;
+                }
+                self.grow(axes).ok_or(cell)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// What an avalanche did, after the world has grown from `old_size`.
+    fn finish(
+        &mut self,
+        old_size: [usize; 3],
+        old_center: [usize; 3],
+        tally: Tally,
+    ) -> AvalancheStats {
+        self.add_to_odometer(old_size, old_center, &tally);
+        self.stats(tally)
+    }
+
+    /// Count an avalanche's topples, after the world has grown from `old_size`.
+    fn add_to_odometer(&mut self, old_size: [usize; 3], old_center: [usize; 3], tally: &Tally) {
+        let (size, center) = (self.size, self.center);
+        if let Some(odometer) = &mut self.odometer {
+            if size != old_size {
+                let offset = [0, 1, 2].map(|i| center[i] - old_center[i]);
+                *odometer = regrown(odometer, old_size, size, offset);
+            }
+            tally.add_to(odometer);
+        }
+    }
+
+    fn stats(&self, tally: Tally) -> AvalancheStats {
+        AvalancheStats {
+            topples: tally.topples,
+            sites: tally.sites(),
+            waves: tally.waves,
+            bounds: tally
+                .toppled_bounds
+                .map(|b| (self.position(b.lo), self.position(b.hi))),
+            lost: tally.lost,
+        }
+    }
+
+    /// Topple a dense world until it is stable. `region` must contain every unstable cell.
+    /// The avalanche is added to `tally`.
+    ///
+    /// Returns the finished avalanche, whose bounds contain `region` and every cell that changed,
+    /// in the coordinates of the world as it is afterwards.
+    /// On failure, returns the boundary cell the avalanche reached.
+    fn stabilize(&mut self, region: Bounds, tally: Tally) -> Result<Toppling, [usize; 3]> {
+        let mut toppling = self.toppling(region, tally);
+        while !toppling.is_done() {
+            self.sweep(&mut toppling)?;
+        }
+        Ok(toppling)
+    }
+
+    /// Sweep once through the rows of a dense world that might be unstable, toppling them.
+    /// If the avalanche reaches the boundary layer, the world grows, and the rest waits for the next sweep.
+    /// On failure, returns the boundary cell it reached.
+    fn sweep(&mut self, toppling: &mut Toppling) -> Result<(), [usize; 3]> {
+        let backup = &self.backup;
+        let data = match &mut self.storage {
+            Storage::Dense(data) => data,
+            _ => unreachable!("Only dense worlds are toppled this way."),
+        };
+        let result = toppling.sweep(data, &|row, heights| {
+            if let Some(backup) = backup {
+                backup.keep_row(row, heights);
+            }
+        });
+
+        if let Err(cell) = result {
+            let mut axes = [false; 3];
+            for i in 0..3 {
+                axes[i] = cell[i] == 0 || cell[i] == self.size[i] - 1;
+            }
+            let offset = self.grow(axes).ok_or(cell)?;
+            toppling.resize(self.size, offset);
+        }
+        Ok(())
+    }
+
+    /// Double the world's size along the given axes, keeping the contents centered.
+    /// Returns how far the contents moved, or `None` if the world is already as large as it may be.
+    /// A world with a sink never grows.
+    fn grow(&mut self, axes: [bool; 3]) -> Option<[usize; 3]> {
+        if self.boundary == Boundary::Sink {
+            return None;
+        }
+
+        let old_size = self.size;
+        let mut new_size = old_size;
+        let mut offset = [0; 3];
+        for i in 0..3 {
+            if axes[i] {
+                new_size[i] = (2 * old_size[i]).min(self.max_size[i]);
+                if new_size[i] <= old_size[i] {
+                    return None;
+                }
+                offset[i] = (new_size[i] - old_size[i]) / 2;
+            }
+        }
+
+        // Chunks are stored relative to the center, so they don't need to move, or to be kept.
+        match &mut self.storage {
+            Storage::Dense(data) => {
+                if let Some(backup) = &mut self.backup {
+                    backup.keep_storage(data);
+                }
+                *data = regrown(data, old_size, new_size, offset);
+            }
+            Storage::Packed(heights) => {
+                let grown = heights.regrown(old_size, new_size, offset);
+                let heights = std::mem::replace(heights, grown);
+                if let Some(backup) = &mut self.backup {
+                    backup.storage.get_or_insert(Storage::Packed(heights));
+                }
+            }
+            _ => {}
+        }
+
+        self.size = new_size;
+        self.center = shifted(self.center, offset);
+
+        Some(offset)
+    }
+
+    /// Grow a walled world until `cell` is not next to the boundary layer, so that it can topple.
+    /// Every lattice has a neighbor on each side along every axis, so otherwise it would topple onto the boundary.
+    /// Calls `moved` with the new size and how far everything moved, each time the world grows.
+    /// On failure, returns the boundary cell it would have toppled onto.
+    fn make_room(
+        &mut self,
+        cell: &mut [usize; 3],
+        mut moved: impl FnMut([usize; 3], [usize; 3]),
+    ) -> Result<(), [usize; 3]> {
+        if self.boundary != Boundary::Wall {
+            return Ok(());
+        }
+        loop {
+            let axes = [0, 1, 2].map(|i| cell[i] == 1 || cell[i] == self.size[i] - 2);
+            if axes == [false; 3] {
+                return Ok(());
+            }
+            let edge = [0, 1, 2].map(|i| match cell[i] {
+                1 => 0,
+                x if axes[i] => x + 1,
+                x => x,
+            });
+            let offset = self.grow(axes).ok_or(edge)?;
+            moved(self.size, offset);
+            *cell = shifted(*cell, offset);
+        }
+    }
+
+    /// Call this before changing any cells within `bounds`, so that the change can be undone.
+    fn record(&mut self, bounds: Bounds) {
+        let keys = match self.storage {
+            Storage::Chunked(_) => self.chunk_keys(bounds),
+            _ => Vec::new(),
+        };
+        match (&mut self.backup, &self.storage) {
+            (Some(backup), Storage::Dense(data)) => {
+                for x in bounds.lo[0]..=bounds.hi[0] {
+                    for y in bounds.lo[1]..=bounds.hi[1] {
+                        let row = x * self.size[1] + y;
+                        backup.keep_row(row, &data[row * self.size[2]..(row + 1) * self.size[2]]);
+                    }
+                }
+            }
+            (Some(backup), Storage::Chunked(chunks)) => {
+                for key in keys {
+                    backup.keep_chunk(key, chunks.get(&key).map(|c| &**c));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn restore(&mut self, mut backup: Backup) {
+        self.size = backup.size;
+        self.center = backup.center;
+        if let Some(storage) = backup.storage.take() {
+            self.storage = storage;
+            return;
+        }
+        match &mut self.storage {
+            Storage::Dense(data) => backup.paste_rows(data),
+            Storage::Chunked(chunks) => backup.paste_chunks(chunks),
+            // Packed worlds only change once the window is stable, so they only need keeping when they grow.
+            Storage::Symmetric(_) | Storage::Packed(_) => {}
+        }
+    }
+
+    /// Replace the height of every cell within `bounds`.
+    /// In a chunked world, chunks are only stored if they already were, or if the new heights aren't zero.
+    fn fill(&mut self, bounds: Bounds, f: impl Fn(i8) -> i8) {
+        let (size, center) = (self.size, self.center);
+        let keys = match self.storage {
+            Storage::Chunked(_) => self.chunk_keys(bounds),
+            _ => Vec::new(),
+        };
+        match &mut self.storage {
+            Storage::Dense(data) => {
+                for x in bounds.lo[0]..=bounds.hi[0] {
+                    for y in bounds.lo[1]..=bounds.hi[1] {
+                        let start = (x * size[1] + y) * size[2];
+                        for h in &mut data[start + bounds.lo[2]..=start + bounds.hi[2]] {
+                            *h = f(*h);
+                        }
+                    }
+                }
+            }
+            Storage::Chunked(chunks) => {
+                let n = CHUNK_SIZE as isize;
+                for key in keys {
+                    if !chunks.contains_key(&key) && f(0) == 0 {
+                        continue;
+                    }
+                    let chunk = chunks
+                        .entry(key)
+                        .or_insert_with(|| Box::new([0; CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE]));
+                    for (offset, h) in chunk.iter_mut().enumerate() {
+                        let offset = offset as isize;
+                        let pos = [offset / (n * n), offset / n % n, offset % n];
+                        let in_bounds = (0..3).all(|i| {
+                            let g = key[i] * n + pos[i] + center[i] as isize;
+                            bounds.lo[i] as isize <= g && g <= bounds.hi[i] as isize
+                        });
+                        if in_bounds {
+                            *h = f(*h);
+                        }
+                    }
+                }
+            }
+            Storage::Symmetric(_) => panic!("{}", SYMMETRIC_CHANGE),
+            Storage::Packed(_) => panic!("{}", PACKED_CHANGE),
+        }
+    }
+
+    /// The keys of every chunk with a cell within `bounds`, in order.
+    fn chunk_keys(&self, bounds: Bounds) -> Vec<[isize; 3]> {
+        let (lo, _) = chunk_location(self.position(bounds.lo));
+        let (hi, _) = chunk_location(self.position(bounds.hi));
+        let mut out = Vec::new();
+        for x in lo[0]..=hi[0] {
+            for y in lo[1]..=hi[1] {
+                for z in lo[2]..=hi[2] {
+                    out.push([x, y, z]);
+                }
+            }
+        }
+        out
+    }
+
+    fn dense(&self) -> &[i8] {
+        match &self.storage {
+            Storage::Dense(data) => data,
+            _ => unreachable!("Only dense worlds are toppled this way."),
+        }
+    }
+
+    /// The heights of all cells, in the same order as the `Index` impl: the last coordinate varies fastest.
+    /// A chunked world is unpacked into its current bounds, which only grow as far as sand has reached.
+    pub fn to_color_array(&self) -> Cow<'_, [i8]> {
+        match &self.storage {
+            Storage::Dense(data) => Cow::Borrowed(data),
+            Storage::Chunked(chunks) => {
+                let size = self.size;
+                let mut data = vec![0; size[0] * size[1] * size[2]];
+                for (key, chunk) in chunks {
+                    for (offset, &height) in chunk.iter().enumerate() {
+                        // Only cells strictly inside the bounds ever hold sand.
+                        if height != 0 {
+                            let n = CHUNK_SIZE as isize;
+                            let offset = offset as isize;
+                            let idx = self.grid_index([
+                                key[0] * n + offset / (n * n),
+                                key[1] * n + offset / n % n,
+                                key[2] * n + offset % n,
+                            ]);
+                            data[self.linear_index(idx)] = height;
+                        }
+                    }
                 }
-            } else {
-                panic!("Overflow before {}", self.total_grains);
+                Cow::Owned(data)
+            }
+            Storage::Symmetric(wedge) => {
+                Cow::Owned(symmetric::expand(wedge, self.size, self.center))
+            }
+            Storage::Packed(heights) => {
+                let mut data = vec![0; self.size.iter().product()];
+                heights.unpack(0, &mut data);
+                Cow::Owned(data)
             }
         }
     }
 
-    pub fn to_color_array(&self) -> &[u8] {
-        &self.data
+    /// The heights of the cells whose first coordinate is `x`, as in `to_color_array`, copied into `out`,
+    /// so that a world can be uploaded a slab at a time without unpacking all of it at once.
+    pub fn unpack_slab(&self, x: usize, out: &mut [i8]) {
+        let [nx, ny, nz] = self.size;
+        assert!(x < nx, "Slab {} is outside the world.", x);
+        assert_eq!(out.len(), ny * nz);
+        match &self.storage {
+            Storage::Dense(data) => out.copy_from_slice(&data[x * ny * nz..(x + 1) * ny * nz]),
+            Storage::Packed(heights) => heights.unpack(x * ny * nz, out),
+            _ => {
+                for (i, height) in out.iter_mut().enumerate() {
+                    *height = self[[x, i / nz, i % nz]];
+                }
+            }
+        }
+    }
+
+    pub fn size(&self) -> [usize; 3] {
+        self.size
+    }
+
+    /// The cell where sand is added. This moves when the world grows.
+    pub fn center(&self) -> [usize; 3] {
+        self.center
+    }
+
+    pub fn lattice(&self) -> Lattice {
+        self.lattice
     }
 
-    pub fn total_grains(&self) -> usize {
+    pub fn boundary(&self) -> Boundary {
+        self.boundary
+    }
+
+    pub fn total_grains(&self) -> isize {
         self.total_grains
     }
+
+    /// Roughly how many bytes the world's cells take up.
+    pub fn memory_size(&self) -> usize {
+        let heights = match &self.storage {
+            Storage::Dense(data) => data.len(),
+            Storage::Chunked(chunks) => chunks.len() * std::mem::size_of::<Chunk>(),
+            Storage::Symmetric(wedge) => wedge.len(),
+            Storage::Packed(heights) => heights.memory_size(),
+        };
+        let odometer = self
+            .odometer
+            .as_ref()
+            .map_or(0, |odometer| 4 * odometer.len());
+        heights + odometer
+    }
+
+    /// Whether the world was made `with_manna`.
+    pub fn is_manna(&self) -> bool {
+        self.manna.is_some()
+    }
+
+    /// Whether the world was made `chunked`.
+    pub fn is_chunked(&self) -> bool {
+        matches!(self.storage, Storage::Chunked(_))
+    }
+
+    /// Whether the world was made `symmetric`.
+    pub fn is_symmetric(&self) -> bool {
+        matches!(self.storage, Storage::Symmetric(_))
+    }
+
+    /// Whether the world was made `packed`.
+    pub fn is_packed(&self) -> bool {
+        matches!(self.storage, Storage::Packed(_))
+    }
+
+    /// How many times each cell has toppled, in the same order as `to_color_array`,
+    /// if the world was made `with_odometer`.
+    pub fn odometer(&self) -> Option<Cow<'_, [u32]>> {
+        let odometer = self.odometer.as_ref()?;
+        Some(match self.storage {
+            Storage::Symmetric(_) => {
+                Cow::Owned(symmetric::expand(odometer, self.size, self.center))
+            }
+            _ => Cow::Borrowed(odometer),
+        })
+    }
+
+    fn linear_index(&self, idx: [usize; 3]) -> usize {
+        (idx[0] * self.size[1] + idx[1]) * self.size[2] + idx[2]
+    }
+
+    fn position(&self, idx: [usize; 3]) -> [isize; 3] {
+        [
+            idx[0] as isize - self.center[0] as isize,
+            idx[1] as isize - self.center[1] as isize,
+            idx[2] as isize - self.center[2] as isize,
+        ]
+    }
+
+    fn grid_index(&self, pos: [isize; 3]) -> [usize; 3] {
+        let idx = [
+            pos[0] + self.center[0] as isize,
+            pos[1] + self.center[1] as isize,
+            pos[2] + self.center[2] as isize,
+        ];
+        assert!(
+            idx.iter()
+                .zip(&self.size)
+                .all(|(&x, &n)| 0 <= x && x < n as isize),
+            "Position {:?} is outside the world.",
+            pos
+        );
+        [idx[0] as usize, idx[1] as usize, idx[2] as usize]
+    }
+}
+
+/// Enough of a world's earlier state to restore it, recorded as the world changes.
+struct Backup {
+    size: [usize; 3],
+    center: [usize; 3],
+    /// Until the world grows, each row along the last axis that has changed, by `x * size[1] + y`,
+    /// and their earlier heights, one row after another.
+    rows: Mutex<(Vec<usize>, Vec<i8>)>,
+    /// A bit set of the rows that have been kept.
+    kept: Vec<AtomicU64>,
+    /// Once a dense world grows, all of its earlier storage is kept instead.
+    storage: Option<Storage>,
+    /// The earlier contents of each chunk of a chunked world that has changed, or `None` if it wasn't stored.
+    /// Chunks don't move when the world grows, so these are kept even then.
+    chunks: HashMap<[isize; 3], Option<Box<Chunk>>>,
 }
+
+impl Backup {
+    fn new(world: &World) -> Self {
+        let num_rows = world.size[0] * world.size[1];
+        Self {
+            size: world.size,
+            center: world.center,
+            rows: Mutex::default(),
+            kept: (0..num_rows.div_ceil(64))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            storage: None,
+            chunks: HashMap::new(),
+        }
+    }
+
+    /// Call this before changing any cells in a row.
+    /// Rows may be toppled on several threads, so this only needs shared access.
+    fn keep_row(&self, row: usize, heights: &[i8]) {
+        if self.storage.is_some() {
+            return;
+        }
+        let (kept, bit) = (&self.kept[row / 64], 1 << (row % 64));
+        if kept.load(Ordering::Relaxed) & bit == 0
+            && kept.fetch_or(bit, Ordering::Relaxed) & bit == 0
+        {
+            let mut rows = self.rows.lock().unwrap();
+            rows.0.push(row);
+            rows.1.extend_from_slice(heights);
+        }
+    }
+
+    /// Call this before a dense world grows.
+    fn keep_storage(&mut self, data: &[i8]) {
+        if self.storage.is_none() {
+            let mut data = data.to_vec();
+            self.paste_rows(&mut data);
+            self.storage = Some(Storage::Dense(data));
+            self.rows = Mutex::default();
+        }
+    }
+
+    /// Call this before changing any cells in the chunk at `key`, which holds `chunk`, if it is stored.
+    fn keep_chunk(&mut self, key: [isize; 3], chunk: Option<&Chunk>) {
+        self.chunks
+            .entry(key)
+            .or_insert_with(|| chunk.map(|chunk| Box::new(*chunk)));
+    }
+
+    fn paste_chunks(self, chunks: &mut HashMap<[isize; 3], Box<Chunk>>) {
+        for (key, chunk) in self.chunks {
+            match chunk {
+                Some(chunk) => chunks.insert(key, chunk),
+                None => chunks.remove(&key),
+            };
+        }
+    }
+
+    fn paste_rows(&self, data: &mut [i8]) {
+        let rows = self.rows.lock().unwrap();
+        for (row, heights) in rows.0.iter().zip(rows.1.chunks_exact(self.size[2])) {
+            data[row * self.size[2]..(row + 1) * self.size[2]].copy_from_slice(heights);
+        }
+    }
+}
+
+/// Cells stored like the heights of a world, moved by `offset` into a larger world.
+/// The new cells are all zero.
+fn regrown<T: Copy + Default>(
+    data: &[T],
+    old_size: [usize; 3],
+    size: [usize; 3],
+    offset: [usize; 3],
+) -> Vec<T> {
+    let mut out = vec![T::default(); size[0] * size[1] * size[2]];
+    for x in 0..old_size[0] {
+        for y in 0..old_size[1] {
+            let src = (x * old_size[1] + y) * old_size[2];
+            let dst = ((x + offset[0]) * size[1] + (y + offset[1])) * size[2] + offset[2];
+            out[dst..dst + old_size[2]].copy_from_slice(&data[src..src + old_size[2]]);
+        }
+    }
+    out
+}
+
+/// Drops on the same cell added together, leaving out any that cancel out, in order by position.
+fn merged(drops: &[([isize; 3], isize)]) -> Vec<([isize; 3], isize)> {
+    let mut out = drops.to_vec();
+    out.sort_unstable_by_key(|&(pos, _)| pos);
+    out.dedup_by(|(pos, num_grains), (first, total)| {
+        if pos == first {
+            *total += *num_grains;
+        }
+        pos == first
+    });
+    out.retain(|&(_, n)| n != 0);
+    out
+}
+
+fn shifted(loc: [usize; 3], offset: [usize; 3]) -> [usize; 3] {
+    [loc[0] + offset[0], loc[1] + offset[1], loc[2] + offset[2]]
+}
+
+/// An avalanche reached the boundary layer of the world, and the world could not grow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OverflowError {
+    /// The number of grains the world would have held after the addition.
+    pub total_grains: isize,
+    /// The boundary cell the avalanche reached,
+    /// in the coordinates of the world as it had grown by then.
+    pub cell: [usize; 3],
+}
+
+impl fmt::Display for OverflowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Overflow at cell {:?} before {} grains",
+            self.cell, self.total_grains
+        )
+    }
+}
+
+impl std::error::Error for OverflowError {}
+
+/// Why sand couldn't be added to a world, or sandpiles added together or multiplied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArithmeticError {
+    /// The avalanche reached the boundary layer of the world, and the world could not grow.
+    Overflow(OverflowError),
+    /// The sand would have gone somewhere other than the center of a symmetric world.
+    Symmetric,
+}
+
+impl From<OverflowError> for ArithmeticError {
+    fn from(err: OverflowError) -> Self {
+        ArithmeticError::Overflow(err)
+    }
+}
+
+impl fmt::Display for ArithmeticError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArithmeticError::Overflow(err) => err.fmt(f),
+            ArithmeticError::Symmetric => f.write_str(SYMMETRIC_CHANGE),
+        }
+    }
+}
+
+impl std::error::Error for ArithmeticError {}
@@ -0,0 +1,648 @@
+//! Adding sand a little at a time, so that a program can carry on doing other things,
+//! like drawing frames, while a big avalanche goes on.
+//!
+//! The work is done a sweep at a time, or with the Manna rule, a few grains at a time,
+//! and what is left to do is kept in the world in between.
+//! The world is partway through toppling until it finishes, and is put back the way it was if that fails.
+//! Big piles are stabilized on their own first, from an estimate of the avalanche if they are made of a few drops,
+//! or else by doubling, and only change the world once they are.
+//! Symmetric worlds sweep over the cells they store instead, and only change once they are stable.
+//! Packed worlds do the work in a dense window onto the world, and only change once it is finished.
+
+use super::chunks::ChunkToppling;
+use super::estimate::{self, Estimate};
+use super::packed::Window;
+use super::symmetric::SymmetricToppling;
+use super::topple::{Bounds, Stencil, Tally, Toppling};
+use super::{
+    chunk_cell_mut, merged, shifted, symmetric, AvalancheStats, Backup, Boundary, OverflowError,
+    Storage, World, CHUNK_SIZE,
+};
+
+use rand::SeedableRng;
+use rand_pcg::Pcg64Mcg;
+use std::collections::VecDeque;
+
+/// How many grains are dropped between checks of the time, with the Manna rule.
+const MANNA_GRAINS: usize = 1 << 10;
+
+/// How many times the cells of a small avalanche topple one at a time, before the rest is swept.
+const SMALL_TOPPLES: usize = 1 << 16;
+
+/// Sand partway through being added to a world.
+pub(super) struct Pending {
+    old_size: [usize; 3],
+    old_center: [usize; 3],
+    /// The grains added, less the holes dug.
+    num_grains: isize,
+    /// The grains added, plus the holes dug, on each cell.
+    total: usize,
+    work: Work,
+}
+
+enum Work {
+    Start(Vec<([isize; 3], isize)>),
+    /// Dropping grains one at a time, by the Manna rule, and how many are left on each cell.
+    Manna {
+        rng: Pcg64Mcg,
+        drops: Vec<([isize; 3], isize)>,
+        tally: Tally,
+    },
+    /// Stabilizing the new grains on their own, in an empty world shaped like this one.
+    /// The pile of `2n + b` grains is the stabilization of twice the pile of `n` grains, plus `b` more,
+    /// so the bits of the number of grains are added one at a time, from the highest, down to `bit`.
+    /// So no height is ever more than about twice the threshold before toppling.
+    Pile {
+        pile: Box<World>,
+        drops: Vec<([isize; 3], isize)>,
+        bit: usize,
+        toppling: Sweeping,
+    },
+    /// Stabilizing the new grains on their own, from an estimate of how many times each cell topples.
+    Estimate(Box<Estimate>),
+    /// Carrying on the avalanche in this world.
+    Topple(Sweeping),
+    /// Adding the sand to a window onto a packed world instead.
+    Window(Box<Window>),
+    /// Adding the sand at the center of a symmetric world instead.
+    Symmetric(Box<SymmetricToppling>),
+    Done(Tally),
+}
+
+/// An avalanche partway through, swept a row at a time in a dense world, or a chunk at a time in a chunked one.
+pub(super) enum Sweeping {
+    Dense(Toppling),
+    Chunked(ChunkToppling),
+}
+
+impl Sweeping {
+    fn is_done(&self) -> bool {
+        match self {
+            Sweeping::Dense(toppling) => toppling.is_done(),
+            Sweeping::Chunked(toppling) => toppling.is_done(),
+        }
+    }
+
+    /// Contains every cell that has changed.
+    fn changed(&self) -> Bounds {
+        match self {
+            Sweeping::Dense(toppling) => toppling.changed(),
+            Sweeping::Chunked(toppling) => toppling.changed(),
+        }
+    }
+
+    fn into_tally(self) -> Tally {
+        match self {
+            Sweeping::Dense(toppling) => toppling.into_tally(),
+            Sweeping::Chunked(toppling) => toppling.into_tally(),
+        }
+    }
+
+    /// How many cells of `world`, the world being toppled, are unstable.
+    fn num_unstable(&self, world: &World) -> usize {
+        match (self, &world.storage) {
+            (Sweeping::Dense(toppling), Storage::Dense(data)) => toppling.num_unstable(data),
+            (Sweeping::Chunked(toppling), Storage::Chunked(chunks)) => {
+                toppling.num_unstable(chunks)
+            }
+            _ => unreachable!("An avalanche is swept the way its world is stored."),
+        }
+    }
+}
+
+/// How far along adding sand is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    /// How many of the grains, and holes, have been dropped into the avalanche so far.
+    pub grains_processed: usize,
+    /// How many grains, and holes, there are in all.
+    pub total: usize,
+    /// How many cells are waiting to topple, or untopple.
+    pub unstable: usize,
+}
+
+impl World {
+    /// Start adding grains to several cells, relative to the center, or taking them away if negative,
+    /// like `try_add_signed_multi`, but without doing any of the work yet; see `keep_adding`.
+    /// Any sand that is already being added must be finished or cancelled first,
+    /// and a symmetric world must only take sand at the center.
+    pub fn start_adding(&mut self, drops: &[([isize; 3], isize)]) {
+        assert!(self.pending.is_none(), "Sand is already being added.");
+        let num_grains = drops.iter().map(|&(_, n)| n).sum();
+        let work = if self.is_symmetric() {
+            assert!(
+                drops.iter().all(|&(pos, n)| pos == [0; 3] || n == 0),
+                "{}",
+                super::SYMMETRIC_CHANGE
+            );
+            Work::Symmetric(Box::new(self.start_symmetric(num_grains)))
+        } else if self.is_packed() {
+            Work::Window(Box::new(Window::new(drops)))
+        } else {
+            Work::Start(drops.to_vec())
+        };
+        self.backup = Some(Backup::new(self));
+        self.pending = Some(Box::new(Pending {
+            old_size: self.size,
+            old_center: self.center,
+            num_grains,
+            total: merged(drops).iter().map(|&(_, n)| n.unsigned_abs()).sum(),
+            work,
+        }));
+    }
+
+    /// Carry on adding sand, until it is finished, or `out_of_time` returns true.
+    /// It is checked after every sweep, or with the Manna rule, every thousand grains or so,
+    /// so this always does at least that much work.
+    ///
+    /// Returns `None` if there is still more to do, or else the stats of the avalanche, as `try_add_signed_multi` would.
+    /// If the avalanche reaches the boundary layer, and the world cannot grow any further,
+    /// the world is left as it was before `start_adding`.
+    pub fn keep_adding(
+        &mut self,
+        mut out_of_time: impl FnMut() -> bool,
+    ) -> Option<Result<AvalancheStats, OverflowError>> {
+        let mut pending = self.pending.take().expect("No sand is being added.");
+
+        let result = if let Work::Symmetric(toppling) = &mut pending.work {
+            loop {
+                if toppling.is_done() {
+                    self.backup = None;
+                    break Ok((self.finish_symmetric(toppling), None));
+                }
+                if let Err(cell) = self.sweep_symmetric(toppling) {
+                    break Err(cell);
+                }
+                if out_of_time() {
+                    self.pending = Some(pending);
+                    return None;
+                }
+            }
+        } else if let Work::Window(window) = &mut pending.work {
+            match self.keep_windowing(window, &mut out_of_time) {
+                Some(result) => result.map(|tally| (AvalancheStats::default(), Some(tally))),
+                None => {
+                    self.pending = Some(pending);
+                    return None;
+                }
+            }
+        } else {
+            let mut work = std::mem::replace(&mut pending.work, Work::Start(Vec::new()));
+            loop {
+                work = match self.work(work) {
+                    Ok(work) => work,
+                    Err(cell) => break Err(cell),
+                };
+                if let Work::Done(tally) = work {
+                    break Ok((AvalancheStats::default(), Some(tally)));
+                }
+                if out_of_time() {
+                    pending.work = work;
+                    self.pending = Some(pending);
+                    return None;
+                }
+            }
+        };
+
+        let result = match result {
+            Ok((stats, None)) => Ok(stats),
+            Ok((_, Some(tally))) => {
+                self.backup = None;
+                Ok(self.finish(pending.old_size, pending.old_center, tally))
+            }
+            Err(cell) => {
+                if let Some(backup) = self.backup.take() {
+                    self.restore(backup);
+                }
+                Err(cell)
+            }
+        };
+        Some(match result {
+            Ok(stats) => {
+                self.total_grains = self.total_grains + pending.num_grains - stats.lost;
+                Ok(stats)
+            }
+            Err(cell) => Err(OverflowError {
+                total_grains: self.total_grains + pending.num_grains,
+                cell,
+            }),
+        })
+    }
+
+    /// Stop adding sand, and put the world back the way it was before `start_adding`.
+    pub fn cancel_adding(&mut self) {
+        self.pending = None;
+        if let Some(backup) = self.backup.take() {
+            self.restore(backup);
+        }
+    }
+
+    /// How far along adding sand is, if any is being added.
+    pub fn progress(&self) -> Option<Progress> {
+        let pending = self.pending.as_ref()?;
+        let left = |drops: &[([isize; 3], isize)]| -> usize {
+            drops.iter().map(|&(_, n)| n.unsigned_abs()).sum()
+        };
+        let (grains_processed, unstable) = match &pending.work {
+            Work::Start(_) => (0, 0),
+            Work::Manna { drops, .. } => (pending.total - left(drops), 0),
+            Work::Pile {
+                pile,
+                drops,
+                bit,
+                toppling,
+            } => {
+                let added = drops.iter().map(|&(_, n)| n.unsigned_abs() >> bit).sum();
+                (added, toppling.num_unstable(pile))
+            }
+            Work::Estimate(estimate) => (0, estimate.num_unstable()),
+            Work::Topple(toppling) => (pending.total, toppling.num_unstable(self)),
+            Work::Window(window) => window.progress(),
+            Work::Symmetric(toppling) => {
+                (toppling.grains_added(), toppling.num_unstable(self.lattice))
+            }
+            Work::Done(_) => (pending.total, 0),
+        };
+        Some(Progress {
+            grains_processed,
+            total: pending.total,
+            unstable,
+        })
+    }
+
+    /// Whether sand is partway through being added.
+    pub fn is_adding(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Do some of the work of adding sand: a sweep of toppling, or getting ready for the next one.
+    /// Returns what is left to do. On failure, returns the boundary cell the avalanche reached.
+    fn work(&mut self, work: Work) -> Result<Work, [usize; 3]> {
+        Ok(match work {
+            Work::Start(drops) => {
+                self.fit(&drops)?;
+                let mut drops = merged(&drops);
+                let seeds = drops.iter().map(|&(pos, _)| self.grid_index(pos)).collect();
+                let tally = Tally::new(self.size, seeds, self.odometer.is_some());
+                if drops.is_empty() {
+                    return Ok(Work::Done(tally));
+                }
+                if let Some(seed) = self.manna {
+                    // Drops come in no particular order, but the result depends on it.
+                    drops.sort_unstable();
+                    return Ok(Work::Manna {
+                        rng: Pcg64Mcg::seed_from_u64(seed),
+                        drops,
+                        tally,
+                    });
+                }
+
+                let bounds = drops
+                    .iter()
+                    .map(|&(pos, _)| Bounds::cell(self.grid_index(pos)))
+                    .reduce(Bounds::union)
+                    .unwrap();
+
+                // A few grains can just be added, without making heights too large.
+                let threshold = self.lattice.threshold() as isize;
+                if drops.iter().all(|&(_, n)| n.abs() <= threshold) {
+                    self.record(bounds);
+                    for &(pos, num_grains) in &drops {
+                        self[pos] += num_grains as i8;
+                    }
+
+                    // Getting ready to sweep takes longer than most small avalanches take to topple.
+                    // With grains all added, or all taken away, the order doesn't matter.
+                    let sign = drops[0].1.signum();
+                    let is_dense = matches!(self.storage, Storage::Dense(_));
+                    if is_dense && drops.iter().all(|&(_, n)| n.signum() == sign) {
+                        let cells: Vec<_> =
+                            drops.iter().map(|&(pos, _)| self.grid_index(pos)).collect();
+                        let mut tally = tally;
+                        return Ok(match self.topple_small(&cells, sign as i8, &mut tally)? {
+                            None => Work::Done(tally),
+                            Some(region) => Work::Topple(self.sweeping(region, tally)),
+                        });
+                    }
+                    return Ok(Work::Topple(self.sweeping(bounds, tally)));
+                }
+
+                // By the abelian property, the new grains can be stabilized on their own first.
+                // A pile at the center of a cube keeps its symmetries, which makes that about 48 times less work.
+                if self.keeps_symmetry(&drops) {
+                    let (pile, tally, bounds) = self.symmetric_pile(drops[0].1)?;
+                    return Ok(self.add_pile(&pile, bounds, tally));
+                }
+                if estimate::is_worth_estimating(&drops) {
+                    return Ok(Work::Estimate(Box::new(Estimate::new(self, drops))));
+                }
+                self.start_pile(drops)
+            }
+
+            Work::Manna {
+                mut rng,
+                mut drops,
+                mut tally,
+            } => {
+                self.drop_manna(&mut rng, &mut drops, MANNA_GRAINS, &mut tally)?;
+                if drops.iter().any(|&(_, n)| n != 0) {
+                    Work::Manna { rng, drops, tally }
+                } else {
+                    self.manna = self.manna.map(|seed| seed.wrapping_add(1));
+                    Work::Done(tally)
+                }
+            }
+
+            Work::Pile {
+                mut pile,
+                drops,
+                bit,
+                mut toppling,
+            } => {
+                if !toppling.is_done() {
+                    pile.keep_sweeping(&mut toppling)?;
+                    return Ok(Work::Pile {
+                        pile,
+                        drops,
+                        bit,
+                        toppling,
+                    });
+                }
+                let bounds = toppling.changed();
+                let mut tally = toppling.into_tally();
+
+                if bit > 0 {
+                    let bit = bit - 1;
+                    pile.fill(bounds, |h| 2 * h);
+                    for &(pos, num_grains) in &drops {
+                        pile[pos] += (num_grains.signum() * (num_grains.abs() >> bit & 1)) as i8;
+                    }
+                    // Doubling the pile doubles the avalanche so far.
+                    tally.double();
+                    let toppling = pile.sweeping(bounds, tally);
+                    return Ok(Work::Pile {
+                        pile,
+                        drops,
+                        bit,
+                        toppling,
+                    });
+                }
+
+                self.add_pile(&pile, bounds, tally)
+            }
+
+            Work::Estimate(mut estimate) => {
+                if !estimate.is_done() {
+                    // A pile too big for the world is left to doubling, to find where it reached the boundary layer.
+                    return Ok(match estimate.step() {
+                        Ok(()) => Work::Estimate(estimate),
+                        Err(()) => self.start_pile(estimate.drops().to_vec()),
+                    });
+                }
+                match self.add_estimated(&estimate) {
+                    Some((tally, bounds)) => Work::Topple(self.sweeping(bounds, tally)),
+                    None => self.start_pile(estimate.drops().to_vec()),
+                }
+            }
+
+            Work::Topple(mut toppling) => {
+                if toppling.is_done() {
+                    Work::Done(toppling.into_tally())
+                } else {
+                    self.keep_sweeping(&mut toppling)?;
+                    Work::Topple(toppling)
+                }
+            }
+
+            Work::Window(_) => unreachable!("Packed worlds add sand in their window."),
+            Work::Symmetric(_) => unreachable!("Symmetric worlds add sand a sweep at a time."),
+            Work::Done(tally) => Work::Done(tally),
+        })
+    }
+
+    /// Topple the cells of a small avalanche in a dense world, starting from `cells`, a cell at a time,
+    /// in the order they became unstable. It must only topple, or only untopple if `sign` is negative.
+    /// Counts the avalanche in `tally`, and grows the world if it reaches the boundary layer.
+    ///
+    /// Returns `None` once everything is stable, or else, if it topples `SMALL_TOPPLES` times first,
+    /// a box containing every cell that is still unstable, which can be swept.
+    /// No height is then more than about twice the threshold.
+    /// On failure, returns the boundary cell the avalanche reached.
+    fn topple_small(
+        &mut self,
+        cells: &[[usize; 3]],
+        sign: i8,
+        tally: &mut Tally,
+    ) -> Result<Option<Bounds>, [usize; 3]> {
+        let neighbors = self.lattice.neighbors();
+        let stencil = Stencil::new(&neighbors);
+        let threshold = stencil.threshold;
+        let height = |world: &World, cell: [usize; 3]| sign * world[cell];
+
+        // A cell joins the queue when it becomes unstable, and topples once each time it gets to the front,
+        // so it can only gain a grain from each neighbor while it waits.
+        let mut unstable: VecDeque<_> = cells
+            .iter()
+            .copied()
+            .filter(|&cell| height(self, cell) >= threshold)
+            .collect();
+        let mut num_topples = 0;
+        while let Some(&cell) = unstable.front() {
+            if num_topples == SMALL_TOPPLES {
+                let region = unstable
+                    .iter()
+                    .map(|&cell| Bounds::cell(cell))
+                    .reduce(Bounds::union);
+                return Ok(region);
+            }
+            unstable.pop_front();
+            if height(self, cell) < threshold {
+                continue;
+            }
+
+            // Every lattice has neighbors on both sides along each axis.
+            let mut cell = cell;
+            let axes = [0, 1, 2].map(|i| cell[i] <= 1 || cell[i] >= self.size[i] - 2);
+            if axes != [false; 3] && self.boundary == Boundary::Wall {
+                let offset = match self.grow(axes) {
+                    Some(offset) => offset,
+                    None => {
+                        let size = self.size;
+                        let on_boundary =
+                            |to: [usize; 3]| (0..3).any(|i| to[i] == 0 || to[i] == size[i] - 1);
+                        let to = neighbors
+                            .iter()
+                            .map(|d| [0, 1, 2].map(|i| (cell[i] as isize + d[i]) as usize))
+                            .find(|&to| on_boundary(to));
+                        return Err(to.unwrap());
+                    }
+                };
+                tally.resize(self.size, offset);
+                for waiting in &mut unstable {
+                    *waiting = shifted(*waiting, offset);
+                }
+                cell = shifted(cell, offset);
+            }
+            tally.count_topple(cell);
+            num_topples += 1;
+
+            let (size, backup) = (self.size, &self.backup);
+            let data = match &mut self.storage {
+                Storage::Dense(data) => data,
+                _ => unreachable!("Only dense worlds topple small avalanches this way."),
+            };
+            let [x, y, z] = cell;
+            for (i, &([dx, dy], counts)) in stencil.rows.iter().enumerate() {
+                let (x, y) = ((x as isize + dx) as usize, (y as isize + dy) as usize);
+                let row = x * size[1] + y;
+                let heights = &mut data[row * size[2]..(row + 1) * size[2]];
+                if let Some(backup) = backup {
+                    backup.keep_row(row, heights);
+                }
+                // The first row is the cell's own.
+                if i == 0 {
+                    heights[z] -= sign * threshold;
+                }
+                for (z, &n) in (z - 1..).zip(&counts) {
+                    if n == 0 {
+                        continue;
+                    }
+                    if [x, y, z]
+                        .iter()
+                        .zip(&size)
+                        .any(|(&x, &n)| x == 0 || x == n - 1)
+                    {
+                        tally.lost += (sign * n) as isize;
+                        continue;
+                    }
+                    heights[z] += sign * n;
+                    if sign * heights[z] >= threshold && sign * (heights[z] - sign * n) < threshold
+                    {
+                        unstable.push_back([x, y, z]);
+                    }
+                }
+            }
+            if height(self, cell) >= threshold {
+                unstable.push_back(cell);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Start stabilizing the merged `drops` on their own by doubling, in an empty world shaped like this one.
+    fn start_pile(&self, drops: Vec<([isize; 3], isize)>) -> Work {
+        let storage = match self.storage {
+            Storage::Chunked(_) => Storage::Chunked(Default::default()),
+            _ => Storage::Dense(vec![0; self.size.iter().product()]),
+        };
+        let pile = Box::new(World {
+            storage,
+            total_grains: 0,
+            odometer: None,
+            manna: None,
+            backup: None,
+            pending: None,
+            ..*self
+        });
+        let seeds = drops.iter().map(|&(pos, _)| self.grid_index(pos)).collect();
+        let tally = Tally::new(self.size, seeds, self.odometer.is_some());
+        let bounds = drops
+            .iter()
+            .map(|&(pos, _)| Bounds::cell(self.grid_index(pos)))
+            .reduce(Bounds::union)
+            .unwrap();
+        let max_grains = drops.iter().map(|&(_, n)| n.unsigned_abs()).max().unwrap();
+        let num_bits = std::mem::size_of::<usize>() * 8 - max_grains.leading_zeros() as usize;
+        let toppling = pile.sweeping(bounds, tally);
+        Work::Pile {
+            pile,
+            drops,
+            bit: num_bits,
+            toppling,
+        }
+    }
+
+    /// Add a pile that was stabilized on its own, in an empty world shaped like this one, to this world,
+    /// and carry on its avalanche here. `bounds` contains every cell of the pile that isn't empty.
+    fn add_pile(&mut self, pile: &World, bounds: Bounds, tally: Tally) -> Work {
+        // The pile started out the same shape as this world, so this world grows the same way.
+        while pile.size != self.size {
+            let (old, new) = (self.size, pile.size);
+            self.grow([old[0] != new[0], old[1] != new[1], old[2] != new[2]])
+                .unwrap();
+        }
+
+        self.record(bounds);
+        let (size, center) = (self.size, self.center);
+        match (&mut self.storage, &pile.storage) {
+            (Storage::Dense(data), Storage::Dense(pile_data)) => {
+                for x in bounds.lo[0]..=bounds.hi[0] {
+                    for y in bounds.lo[1]..=bounds.hi[1] {
+                        let start = (x * size[1] + y) * size[2];
+                        for (h, &p) in data[start + bounds.lo[2]..=start + bounds.hi[2]]
+                            .iter_mut()
+                            .zip(&pile_data[start + bounds.lo[2]..=start + bounds.hi[2]])
+                        {
+                            *h += p;
+                        }
+                    }
+                }
+            }
+            (Storage::Chunked(chunks), Storage::Chunked(pile_chunks)) => {
+                for (key, pile_chunk) in pile_chunks {
+                    let chunk = chunks
+                        .entry(*key)
+                        .or_insert_with(|| Box::new([0; CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE]));
+                    for (h, &p) in chunk.iter_mut().zip(pile_chunk.iter()) {
+                        *h += p;
+                    }
+                }
+            }
+            (storage, Storage::Symmetric(wedge)) => {
+                for x in bounds.lo[0]..=bounds.hi[0] {
+                    for y in bounds.lo[1]..=bounds.hi[1] {
+                        for z in bounds.lo[2]..=bounds.hi[2] {
+                            let idx = [x, y, z];
+                            let pos = [0, 1, 2].map(|i| idx[i] as isize - center[i] as isize);
+                            let p = symmetric::cell(wedge, pos);
+                            match storage {
+                                _ if p == 0 => {}
+                                Storage::Dense(data) => data[(x * size[1] + y) * size[2] + z] += p,
+                                Storage::Chunked(chunks) => *chunk_cell_mut(chunks, pos) += p,
+                                Storage::Symmetric(_) | Storage::Packed(_) => {
+                                    unreachable!("Only dense and chunked worlds add a pile.")
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            _ => unreachable!("The pile is stored the same way as the world, or symmetrically."),
+        }
+
+        // The pile's avalanche carries on in this world.
+        Work::Topple(self.sweeping(bounds, tally))
+    }
+
+    /// An avalanche in this dense world, where `region` contains every unstable cell, added to `tally`.
+    pub(super) fn toppling(&self, region: Bounds, tally: Tally) -> Toppling {
+        let stencil = Stencil::new(&self.lattice.neighbors());
+        Toppling::new(self.size, stencil, self.boundary, region, tally)
+    }
+
+    /// Like `toppling`, but for a world stored either way.
+    fn sweeping(&self, region: Bounds, tally: Tally) -> Sweeping {
+        match self.storage {
+            Storage::Chunked(_) => Sweeping::Chunked(self.chunk_toppling(region, tally)),
+            _ => Sweeping::Dense(self.toppling(region, tally)),
+        }
+    }
+
+    /// Sweep once through the avalanche, as `sweep` or `sweep_chunks` does.
+    fn keep_sweeping(&mut self, sweeping: &mut Sweeping) -> Result<(), [usize; 3]> {
+        match sweeping {
+            Sweeping::Dense(toppling) => self.sweep(toppling),
+            Sweeping::Chunked(toppling) => self.sweep_chunks(toppling),
+        }
+    }
+}
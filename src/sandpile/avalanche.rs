@@ -0,0 +1,237 @@
+//! Avalanches that topple a generation at a time, so that they can be watched as they spread.
+//!
+//! In each generation, every cell that was unstable when it began topples once,
+//! and cells that become unstable along the way wait for the next generation.
+//! Grains are dropped a few at a time, just enough to make their cells topple again, so heights stay small.
+//! By the abelian property, the world ends up the same as with `World::add_signed_multi`,
+//! as long as grains are only added to a world without holes, or only taken from one without sand.
+//! Otherwise, holes fill up with grains before anything topples, rather than after.
+
+use super::topple::Tally;
+use super::{merged, shifted, AvalancheStats, Backup, OverflowError, World};
+
+/// An avalanche in progress, which carries on each time it is given the world it started in.
+/// Until it is finished or cancelled, the world should only be changed through it.
+pub struct Avalanche {
+    /// The cells where grains are dropped, and how many are still to be dropped on each.
+    drops: Vec<([usize; 3], isize)>,
+    /// The cells still to topple in this generation, with the next one last.
+    current: Vec<[usize; 3]>,
+    /// The cells that have become unstable during this generation.
+    next: Vec<[usize; 3]>,
+    neighbors: Vec<[isize; 3]>,
+    generations: u64,
+    tally: Tally,
+    /// Only taken while the avalanche is going on.
+    backup: Option<Backup>,
+    old_size: [usize; 3],
+    old_center: [usize; 3],
+    num_grains: isize,
+}
+
+impl World {
+    /// Start adding grains to cells, relative to the center, or taking them away if negative,
+    /// without toppling anything yet. The world must not be symmetric or packed, or use the Manna rule.
+    ///
+    /// Fails, leaving the world as it was, if it cannot grow to fit the drops.
+    pub fn start_avalanche(
+        &mut self,
+        drops: &[([isize; 3], isize)],
+    ) -> Result<Avalanche, OverflowError> {
+        assert!(
+            !self.is_symmetric() && !self.is_packed() && !self.is_manna(),
+            "Only abelian worlds that are not symmetric or packed can be toppled a generation at a time."
+        );
+        let (old_size, old_center) = (self.size, self.center);
+        let num_grains: isize = drops.iter().map(|&(_, n)| n).sum();
+
+        self.backup = Some(Backup::new(self));
+        let result = self.fit(drops);
+        let backup = self.backup.take();
+        if let Err(cell) = result {
+            self.restore(backup.unwrap());
+            return Err(OverflowError {
+                total_grains: self.total_grains + num_grains,
+                cell,
+            });
+        }
+
+        let mut drops: Vec<_> = merged(drops)
+            .into_iter()
+            .map(|(pos, n)| (self.grid_index(pos), n))
+            .collect();
+        drops.sort_unstable();
+        let seeds = drops.iter().map(|&(cell, _)| cell).collect();
+        Ok(Avalanche {
+            drops,
+            current: Vec::new(),
+            next: Vec::new(),
+            neighbors: self.lattice.neighbors(),
+            generations: 0,
+            tally: Tally::new(self.size, seeds, self.odometer.is_some()),
+            backup,
+            old_size,
+            old_center,
+            num_grains,
+        })
+    }
+}
+
+impl Avalanche {
+    /// Finish the generation that is going on, or if none is, topple the next one.
+    /// Returns whether the avalanche is over.
+    ///
+    /// If the avalanche reaches the boundary layer, and the world cannot grow any further,
+    /// the world is left as it was before the avalanche started, and the avalanche must not be used again.
+    pub fn next_generation(&mut self, world: &mut World) -> Result<bool, OverflowError> {
+        self.run(world, |avalanche, world| {
+            if avalanche.current.is_empty() {
+                avalanche.start_generation(world);
+            }
+            while let Some(cell) = avalanche.current.pop() {
+                avalanche.topple(world, cell)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Topple up to `num_topples` cells, going on to later generations if need be.
+    /// Returns whether the avalanche is over, and fails like `next_generation`.
+    pub fn advance(&mut self, world: &mut World, num_topples: u64) -> Result<bool, OverflowError> {
+        self.run(world, |avalanche, world| {
+            let mut num_toppled = 0;
+            while num_toppled < num_topples {
+                if avalanche.current.is_empty() {
+                    avalanche.start_generation(world);
+                    if avalanche.current.is_empty() {
+                        break;
+                    }
+                }
+                let cell = avalanche.current.pop().unwrap();
+                num_toppled += avalanche.topple(world, cell)? as u64;
+            }
+            Ok(())
+        })
+    }
+
+    /// Put the world back the way it was before the avalanche started.
+    pub fn cancel(mut self, world: &mut World) {
+        world.restore(self.backup.take().unwrap());
+    }
+
+    /// What the avalanche did, once it is over.
+    pub fn finish(self, world: &mut World) -> AvalancheStats {
+        assert!(self.is_done(), "The avalanche is still going on.");
+        world.total_grains += self.num_grains - self.tally.lost;
+        world.finish(self.old_size, self.old_center, self.tally)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.current.is_empty() && self.next.is_empty() && self.grains_left() == 0
+    }
+
+    /// How many generations have begun.
+    pub fn generations(&self) -> u64 {
+        self.generations
+    }
+
+    pub fn topples(&self) -> u64 {
+        self.tally.topples
+    }
+
+    /// How many cells are waiting to topple, in this generation or the next.
+    pub fn unstable(&self) -> usize {
+        self.current.len() + self.next.len()
+    }
+
+    /// How many grains are still to be dropped, or taken away.
+    pub fn grains_left(&self) -> usize {
+        self.drops.iter().map(|&(_, n)| n.unsigned_abs()).sum()
+    }
+
+    /// Carry on the avalanche with `f`, keeping the world's backup up to date.
+    fn run(
+        &mut self,
+        world: &mut World,
+        f: impl FnOnce(&mut Self, &mut World) -> Result<(), [usize; 3]>,
+    ) -> Result<bool, OverflowError> {
+        world.backup = self.backup.take();
+        let result = f(self, world);
+        self.backup = world.backup.take();
+        match result {
+            Ok(()) => Ok(self.is_done()),
+            Err(cell) => {
+                world.restore(self.backup.take().unwrap());
+                Err(OverflowError {
+                    total_grains: world.total_grains + self.num_grains,
+                    cell,
+                })
+            }
+        }
+    }
+
+    /// Drop just enough grains for each cell they are dropped on to topple,
+    /// and make the cells that became unstable during the last generation the current one.
+    fn start_generation(&mut self, world: &mut World) {
+        let threshold = self.neighbors.len() as isize;
+        for (cell, left) in &mut self.drops {
+            let sign = left.signum();
+            let height = world[*cell] as isize;
+            let num_grains = (threshold - sign * height).max(0).min(left.abs());
+            if num_grains == 0 {
+                continue;
+            }
+            let height = world.change(*cell, (sign * num_grains) as i8) as isize;
+            *left -= sign * num_grains;
+            if sign * height == threshold {
+                self.next.push(*cell);
+            }
+        }
+
+        self.next.sort_unstable_by(|a, b| b.cmp(a));
+        self.next.dedup();
+        self.current = std::mem::take(&mut self.next);
+        if !self.current.is_empty() {
+            self.generations += 1;
+        }
+    }
+
+    /// Topple `cell`, or untopple it, if it is still unstable. Returns whether it was.
+    /// On failure, returns the boundary cell it would have toppled onto.
+    fn topple(&mut self, world: &mut World, mut cell: [usize; 3]) -> Result<bool, [usize; 3]> {
+        let threshold = self.neighbors.len() as i8;
+        let height = world[cell];
+        let sign = (height >= threshold) as i8 - (height <= -threshold) as i8;
+        if sign == 0 {
+            return Ok(false);
+        }
+
+        world.make_room(&mut cell, |size, offset| self.shift(size, offset))?;
+
+        let height = world.change(cell, -sign * threshold);
+        for &d in &self.neighbors {
+            let to = [0, 1, 2].map(|i| (cell[i] as isize + d[i]) as usize);
+            if (0..3).any(|i| to[i] == 0 || to[i] == world.size[i] - 1) {
+                self.tally.lost += sign as isize;
+            } else if world.change(to, sign) == sign * threshold {
+                self.next.push(to);
+            }
+        }
+        if sign * height >= threshold {
+            self.next.push(cell);
+        }
+        self.tally.count_topple(cell);
+        Ok(true)
+    }
+
+    /// Move every cell, after the world grows to `size`.
+    fn shift(&mut self, size: [usize; 3], offset: [usize; 3]) {
+        self.tally.resize(size, offset);
+        for (cell, _) in &mut self.drops {
+            *cell = shifted(*cell, offset);
+        }
+        for cell in self.current.iter_mut().chain(&mut self.next) {
+            *cell = shifted(*cell, offset);
+        }
+    }
+}
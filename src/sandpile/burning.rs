@@ -0,0 +1,78 @@
+//! Dhar's burning algorithm, which tells whether a world is recurrent:
+//! whether it can be reached from any other world by adding sand, with the boundary layer as a sink.
+//!
+//! Fire starts in the boundary layer, and spreads to every cell with at least as many grains
+//! as it has neighbors that have not yet burnt. The world is recurrent exactly when every cell burns.
+
+use super::World;
+
+impl World {
+    /// Whether every cell burns; see `unburnt`.
+    /// On `Lattice::Bcc8` and `Lattice::Fcc12`, this includes the cells that sand dropped at the center never reaches.
+    pub fn is_recurrent(&self) -> bool {
+        self.unburnt().is_empty()
+    }
+
+    /// The cells that do not burn, relative to the center, as if the boundary layer were a sink.
+    pub fn unburnt(&self) -> Vec<[isize; 3]> {
+        let size = self.size;
+        let heights = self.to_color_array();
+        let offsets: Vec<isize> = self
+            .lattice
+            .neighbors()
+            .iter()
+            .map(|&[dx, dy, dz]| (dx * size[1] as isize + dy) * size[2] as isize + dz)
+            .collect();
+
+        // The boundary layer has burnt from the start.
+        let mut burnt = vec![true; heights.len()];
+        for x in 1..size[0] - 1 {
+            for y in 1..size[1] - 1 {
+                let start = (x * size[1] + y) * size[2];
+                for b in &mut burnt[start + 1..start + size[2] - 1] {
+                    *b = false;
+                }
+            }
+        }
+
+        // How many neighbors of each cell have not yet burnt.
+        let mut unburnt_neighbors = vec![0; heights.len()];
+        let mut burning = Vec::new();
+        for (i, &b) in burnt.iter().enumerate() {
+            if !b {
+                unburnt_neighbors[i] = offsets
+                    .iter()
+                    .filter(|&&d| !burnt[(i as isize + d) as usize])
+                    .count() as i8;
+                if heights[i] >= unburnt_neighbors[i] {
+                    burning.push(i);
+                }
+            }
+        }
+        for &i in &burning {
+            burnt[i] = true;
+        }
+
+        while let Some(i) = burning.pop() {
+            for &d in &offsets {
+                let j = (i as isize + d) as usize;
+                if !burnt[j] {
+                    unburnt_neighbors[j] -= 1;
+                    if heights[j] >= unburnt_neighbors[j] {
+                        burnt[j] = true;
+                        burning.push(j);
+                    }
+                }
+            }
+        }
+
+        let mut out = Vec::new();
+        for (i, &b) in burnt.iter().enumerate() {
+            if !b {
+                let idx = [i / (size[1] * size[2]), i / size[2] % size[1], i % size[2]];
+                out.push(self.position(idx));
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,284 @@
+//! Toppling a chunked world a chunk at a time, without ever storing it densely.
+//!
+//! Each chunk that might be unstable is copied into a buffer with a halo, one cell thick, all around it.
+//! Its rows are toppled as in the `topple` module, and the grains that land in the halo are then added
+//! to the chunks next to it, which may make them unstable in turn.
+//! A sweep topples each of those chunks once, so each cell topples or untopples at most once a sweep,
+//! and heights stay as small as they do in a dense world.
+
+use super::topple::{edge_cell, spread, Bounds, Stencil, Tally};
+use super::{chunk_cell_mut, chunk_location, Boundary, Chunk, Storage, World, CHUNK_SIZE};
+
+use std::collections::HashMap;
+
+/// How many cells a chunk is across, along with its halo.
+const PADDED: usize = CHUNK_SIZE + 2;
+
+/// A bit for each row of a chunk along the last axis, by `x * CHUNK_SIZE + y`.
+type Rows = [u64; CHUNK_SIZE * CHUNK_SIZE / 64];
+
+/// An avalanche in progress in a chunked world.
+pub struct ChunkToppling {
+    stencil: Stencil,
+    /// The rows that might be unstable, in each chunk that has any.
+    rows: HashMap<[isize; 3], Rows>,
+    /// Contains every cell that has changed.
+    changed: Bounds,
+    tally: Tally,
+}
+
+impl ChunkToppling {
+    /// Contains every cell that has changed.
+    pub fn changed(&self) -> Bounds {
+        self.changed
+    }
+
+    pub fn into_tally(self) -> Tally {
+        self.tally
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// How many cells of `chunks`, the world being toppled, are unstable.
+    pub fn num_unstable(&self, chunks: &HashMap<[isize; 3], Box<Chunk>>) -> usize {
+        let threshold = self.stencil.threshold;
+        let mut out = 0;
+        for (key, rows) in &self.rows {
+            if let Some(chunk) = chunks.get(key) {
+                for (row, heights) in chunk.chunks_exact(CHUNK_SIZE).enumerate() {
+                    if is_marked(rows, row) {
+                        out += heights
+                            .iter()
+                            .filter(|&&h| h >= threshold || h <= -threshold)
+                            .count();
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+fn mark(rows: &mut Rows, row: usize) {
+    rows[row / 64] |= 1 << (row % 64);
+}
+
+fn is_marked(rows: &Rows, row: usize) -> bool {
+    rows[row / 64] >> (row % 64) & 1 == 1
+}
+
+impl World {
+    /// An avalanche in this chunked world, where `region` contains every unstable cell, added to `tally`.
+    pub(super) fn chunk_toppling(&self, region: Bounds, tally: Tally) -> ChunkToppling {
+        let chunks = match &self.storage {
+            Storage::Chunked(chunks) => chunks,
+            _ => unreachable!("Only chunked worlds are toppled this way."),
+        };
+        let n = CHUNK_SIZE as isize;
+        let mut rows = HashMap::new();
+        for key in self.chunk_keys(region) {
+            // Chunks that were never stored are empty, so they are stable.
+            if !chunks.contains_key(&key) {
+                continue;
+            }
+            let mut marked = Rows::default();
+            for x in 0..n {
+                for y in 0..n {
+                    let pos = [key[0] * n + x, key[1] * n + y];
+                    let in_region = (0..2).all(|i| {
+                        let g = pos[i] + self.center[i] as isize;
+                        region.lo[i] as isize <= g && g <= region.hi[i] as isize
+                    });
+                    if in_region {
+                        mark(&mut marked, (x * n + y) as usize);
+                    }
+                }
+            }
+            if marked != Rows::default() {
+                rows.insert(key, marked);
+            }
+        }
+        ChunkToppling {
+            stencil: Stencil::new(&self.lattice.neighbors()),
+            rows,
+            changed: region,
+            tally,
+        }
+    }
+
+    /// Sweep once through the chunks of a chunked world that might be unstable, toppling them.
+    /// If the avalanche reaches the boundary layer, the world grows, which is free, since chunks don't move.
+    /// On failure, returns the boundary cell it reached.
+    pub(super) fn sweep_chunks(&mut self, toppling: &mut ChunkToppling) -> Result<(), [usize; 3]> {
+        // Chunks are toppled in order, so that the same avalanche always topples the same way.
+        let mut keys: Vec<_> = toppling.rows.keys().copied().collect();
+        keys.sort_unstable();
+        for key in keys {
+            if let Some(rows) = toppling.rows.remove(&key) {
+                self.topple_chunk(key, rows, toppling)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Topple the `rows` of the chunk at `key` that might be unstable, in order,
+    /// then add the grains that reached its halo to the chunks next to it.
+    /// Rows that are marked ahead of the sweep are toppled during it; the rest wait until next time.
+    fn topple_chunk(
+        &mut self,
+        key: [isize; 3],
+        mut rows: Rows,
+        toppling: &mut ChunkToppling,
+    ) -> Result<(), [usize; 3]> {
+        let n = CHUNK_SIZE;
+        let threshold = toppling.stencil.threshold;
+        // The position of the first cell of the halo, relative to the center.
+        let origin = key.map(|k| k * n as isize - 1);
+        let pos = |x: usize, y: usize, z: usize| {
+            [
+                origin[0] + x as isize,
+                origin[1] + y as isize,
+                origin[2] + z as isize,
+            ]
+        };
+
+        let mut cells = [0; PADDED * PADDED * PADDED];
+        if let Storage::Chunked(chunks) = &self.storage {
+            if let Some(chunk) = chunks.get(&key) {
+                for (row, heights) in chunk.chunks_exact(n).enumerate() {
+                    let start = ((row / n + 1) * PADDED + row % n + 1) * PADDED + 1;
+                    cells[start..start + n].copy_from_slice(heights);
+                }
+            }
+        }
+
+        // Padded with two zeros at each end, for the halo, and the neighbors along the row.
+        // Untopples count as -1.
+        let mut topples = [0; PADDED + 2];
+        let mut next = Rows::default();
+        let mut toppled = false;
+        for row in 0..n * n {
+            if !is_marked(&rows, row) {
+                continue;
+            }
+            let (x, y) = (row / n + 1, row % n + 1);
+            let start = (x * PADDED + y) * PADDED;
+
+            // Only cells strictly inside the world topple. The rest are in the boundary layer, or beyond it.
+            let inside = |pos: [isize; 3], i: usize| {
+                let g = pos[i] + self.center[i] as isize;
+                1 <= g && g <= self.size[i] as isize - 2
+            };
+            if !inside(pos(x, y, 1), 0) || !inside(pos(x, y, 1), 1) {
+                continue;
+            }
+            for z in 1..=n {
+                let h = cells[start + z];
+                topples[z + 1] = if inside(pos(x, y, z), 2) {
+                    (h >= threshold) as i8 - (h <= -threshold) as i8
+                } else {
+                    0
+                };
+            }
+            let here = &topples[2..n + 2];
+            let (first, last) = match here.iter().position(|&t| t != 0) {
+                Some(first) => (first + 1, here.iter().rposition(|&t| t != 0).unwrap() + 1),
+                None => continue,
+            };
+
+            // Make room before anything topples onto the boundary layer.
+            let mut cell = self.grid_index(pos(x, y, first));
+            if self.boundary == Boundary::Wall {
+                while let Some(edge) = edge_cell(self.size, cell, cell[2] + last - first) {
+                    let axes = [0, 1, 2].map(|i| edge[i] == 0 || edge[i] == self.size[i] - 1);
+                    let offset = self.grow(axes).ok_or(edge)?;
+                    toppling.tally.resize(self.size, offset);
+                    toppling.changed = toppling.changed.shifted(offset);
+                    cell = self.grid_index(pos(x, y, first));
+                }
+            }
+
+            toppling
+                .tally
+                .count_row(cell, &topples[first + 1..last + 2]);
+            let changed = Bounds {
+                lo: [cell[0] - 1, cell[1] - 1, cell[2] - 1],
+                hi: [cell[0] + 1, cell[1] + 1, cell[2] + last - first + 1],
+            };
+            toppling.changed = toppling.changed.union(changed);
+            toppled = true;
+
+            for &([dx, dy], counts) in &toppling.stencil.rows {
+                let nx = (x as isize + dx) as usize;
+                let ny = (y as isize + dy) as usize;
+                let start = (nx * PADDED + ny) * PADDED;
+                let lost = if [dx, dy] == [0, 0] { threshold } else { 0 };
+                spread(&mut cells[start..start + PADDED], &topples, lost, counts);
+                if (1..=n).contains(&nx) && (1..=n).contains(&ny) {
+                    let neighbor = (nx - 1) * n + ny - 1;
+                    mark(if neighbor > row { &mut rows } else { &mut next }, neighbor);
+                }
+            }
+        }
+        if !toppled {
+            return Ok(());
+        }
+
+        let (size, center, boundary) = (self.size, self.center, self.boundary);
+        let (chunks, backup) = match (&mut self.storage, &mut self.backup) {
+            (Storage::Chunked(chunks), backup) => (chunks, backup),
+            _ => unreachable!("Only chunked worlds are toppled this way."),
+        };
+        let is_halo = |c: usize| c == 0 || c == PADDED - 1;
+        for x in 0..PADDED {
+            for y in 0..PADDED {
+                for z in 0..PADDED {
+                    let i = (x * PADDED + y) * PADDED + z;
+                    let pos = pos(x, y, z);
+                    // Everything outside the world with a sink has fallen into it.
+                    if boundary == Boundary::Sink
+                        && (0..3).any(|i| {
+                            let g = pos[i] + center[i] as isize;
+                            g < 1 || g > size[i] as isize - 2
+                        })
+                    {
+                        toppling.tally.lost += std::mem::take(&mut cells[i]) as isize;
+                    }
+                    if cells[i] == 0 || !(is_halo(x) || is_halo(y) || is_halo(z)) {
+                        continue;
+                    }
+
+                    let (neighbor, offset) = chunk_location(pos);
+                    if let Some(backup) = backup {
+                        backup.keep_chunk(neighbor, chunks.get(&neighbor).map(|c| &**c));
+                    }
+                    let height = chunk_cell_mut(chunks, pos);
+                    *height += cells[i];
+                    if *height >= threshold || *height <= -threshold {
+                        mark(toppling.rows.entry(neighbor).or_default(), offset / n);
+                    }
+                }
+            }
+        }
+
+        if let Some(backup) = backup {
+            backup.keep_chunk(key, chunks.get(&key).map(|c| &**c));
+        }
+        let chunk = chunks
+            .entry(key)
+            .or_insert_with(|| Box::new([0; CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE]));
+        for (row, heights) in chunk.chunks_exact_mut(n).enumerate() {
+            let start = ((row / n + 1) * PADDED + row % n + 1) * PADDED + 1;
+            heights.copy_from_slice(&cells[start..start + n]);
+        }
+        if next != Rows::default() {
+            let rows = toppling.rows.entry(key).or_default();
+            for (r, n) in rows.iter_mut().zip(next) {
+                *r |= n;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,462 @@
+//! The divisible sandpile, where each cell holds any amount of mass rather than whole grains.
+//! A cell with more than 1 keeps 1, and splits the rest evenly among its neighbors.
+//!
+//! Mass spreads out until every cell is nearly full or nearly stable, which takes infinitely many splits,
+//! so it stops once no cell has more than a small tolerance over 1.
+//! The result is close to a ball, unlike the abelian sandpile, which makes it a useful comparison.
+
+use super::topple::Bounds;
+use super::{regrown, shifted, Boundary, Lattice, OverflowError};
+
+use std::ops::Index;
+
+pub struct DivisibleWorld {
+    size: [usize; 3],
+    /// The world grows, up to this size, rather than letting mass reach the boundary layer.
+    max_size: [usize; 3],
+    /// Where mass is added.
+    center: [usize; 3],
+    /// The mass of every cell, in the same order as `World::to_color_array`.
+    mass: Vec<f32>,
+    lattice: Lattice,
+    boundary: Boundary,
+    /// How much more than 1 a cell can hold without splitting.
+    tolerance: f32,
+    /// Not counting any that fell into a sink.
+    total_mass: f64,
+}
+
+/// Signed coordinates, relative to the center, which must lie within the world's bounds.
+impl Index<[isize; 3]> for DivisibleWorld {
+    type Output = f32;
+    fn index(&self, pos: [isize; 3]) -> &f32 {
+        &self.mass[self.linear_index(self.grid_index(pos))]
+    }
+}
+
+impl DivisibleWorld {
+    /// Create an empty world with the given dimensions, each at least 3.
+    pub fn new(size: [usize; 3]) -> Self {
+        Self::growable(size, size)
+    }
+
+    /// Create an empty world with the given dimensions,
+    /// which doubles in size whenever mass reaches its boundary, until it reaches `max_size`.
+    pub fn growable(size: [usize; 3], max_size: [usize; 3]) -> Self {
+        assert!(
+            size.iter().all(|&n| n >= 3),
+            "World dimensions must be at least 3."
+        );
+
+        Self {
+            size,
+            max_size,
+            center: [size[0] / 2, size[1] / 2, size[2] / 2],
+            mass: vec![0.; size[0] * size[1] * size[2]],
+            lattice: Lattice::Cubic6,
+            boundary: Boundary::Wall,
+            tolerance: 1e-3,
+            total_mass: 0.,
+        }
+    }
+
+    /// Use a different lattice than the default `Lattice::Cubic6`.
+    /// The world must not have any mass yet.
+    pub fn with_lattice(mut self, lattice: Lattice) -> Self {
+        assert_eq!(
+            self.total_mass, 0.,
+            "Cannot change the lattice of a sandpile."
+        );
+        self.lattice = lattice;
+        self
+    }
+
+    /// Use a different boundary than the default `Boundary::Wall`.
+    /// The world must not have any mass yet.
+    pub fn with_boundary(mut self, boundary: Boundary) -> Self {
+        assert_eq!(
+            self.total_mass, 0.,
+            "Cannot change the boundary of a sandpile."
+        );
+        self.boundary = boundary;
+        self
+    }
+
+    /// Stop splitting once no cell holds more than `1 + tolerance`, rather than the default `1.001`.
+    pub fn with_tolerance(mut self, tolerance: f32) -> Self {
+        assert!(tolerance > 0., "The tolerance must be positive.");
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Add mass to the center, and split until stable.
+    /// Panics if mass reaches the boundary layer; see `try_add_mass_at`.
+    pub fn add_mass(&mut self, mass: f32) {
+        self.add_mass_at([0; 3], mass);
+    }
+
+    /// Add mass to the cell at `pos`, relative to the center, and split until stable.
+    /// Panics if mass reaches the boundary layer; see `try_add_mass_at`.
+    pub fn add_mass_at(&mut self, pos: [isize; 3], mass: f32) {
+        self.try_add_mass_at(pos, mass)
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+
+    /// Add mass to the cell at `pos`, relative to the center, which must be inside the boundary layer,
+    /// and split until stable. If mass reaches the boundary layer, and the world cannot grow any further,
+    /// the world is left as it was before the call.
+    /// The error counts the total mass the world would have held, rounded down, as grains.
+    pub fn try_add_mass_at(&mut self, pos: [isize; 3], mass: f32) -> Result<(), OverflowError> {
+        assert!(mass >= 0., "Cannot add negative mass.");
+        let idx = self.grid_index(pos);
+        assert!(
+            (0..3).all(|i| 0 < idx[i] && idx[i] < self.size[i] - 1),
+            "Position {:?} is in the boundary layer.",
+            pos
+        );
+
+        let backup = (self.size, self.center, self.mass.clone());
+        let i = self.linear_index(idx);
+        self.mass[i] += mass;
+        match self.stabilize(Bounds::cell(idx)) {
+            Ok(lost) => {
+                self.total_mass += mass as f64 - lost;
+                Ok(())
+            }
+            Err(cell) => {
+                self.size = backup.0;
+                self.center = backup.1;
+                self.mass = backup.2;
+                Err(OverflowError {
+                    total_grains: (self.total_mass + mass as f64) as isize,
+                    cell,
+                })
+            }
+        }
+    }
+
+    /// Split cells until none holds more than `1 + tolerance`. `region` must contain every such cell.
+    /// Returns how much mass fell into a sink, or on failure, the boundary cell mass reached.
+    fn stabilize(&mut self, region: Bounds) -> Result<f64, [usize; 3]> {
+        let (mut region, mut lost) = self.settle(region)?;
+        let share = 1. / self.lattice.threshold() as f32;
+        // Sweeps alternate between going forwards and backwards, so that mass travels quickly in every direction.
+        let mut backwards = false;
+        'sweeps: loop {
+            let size = self.size;
+            let offsets = self.offsets();
+
+            let mut split: Option<Bounds> = None;
+            for idx in cells(region, backwards) {
+                let i = self.linear_index(idx);
+                let excess = self.mass[i] - 1.;
+                if excess <= self.tolerance {
+                    continue;
+                }
+
+                if self.boundary == Boundary::Wall {
+                    if let Some(offset) = self.grow_from(idx, None)? {
+                        if let Some(split) = split {
+                            region = region.union(self.around(split, size));
+                        }
+                        region = region.shifted(offset);
+                        continue 'sweeps;
+                    }
+                }
+
+                self.mass[i] = 1.;
+                for &d in &offsets {
+                    self.mass[(i as isize + d) as usize] += excess * share;
+                }
+                let cell = Bounds::cell(idx);
+                split = Some(split.map_or(cell, |s| s.union(cell)));
+            }
+
+            let split = match split {
+                Some(split) => split,
+                None => return Ok(lost),
+            };
+            // Only the neighbors of cells that split can need to split next time.
+            region = self.around(split, size);
+            let at_edge = (0..3).any(|i| split.lo[i] == 1 || split.hi[i] == size[i] - 2);
+            if self.boundary == Boundary::Sink && at_edge {
+                lost += self.drain(split);
+            }
+            backwards = !backwards;
+        }
+    }
+
+    /// Find roughly how much mass each cell will send out before the world is stable, and send it,
+    /// leaving only a little splitting for `stabilize` to finish. Takes the same `region` as `stabilize`,
+    /// and returns a region containing every cell that may still need to split, and how much mass fell into a sink.
+    ///
+    /// The mass each cell sends out, its odometer, is the smallest `u >= 0` with `u = avg(u) + mass - 1`
+    /// wherever `u > 0`, where `avg` is over neighbors. Solving that by successive over-relaxation
+    /// takes a number of sweeps about linear in the radius of the result, rather than quadratic like splitting.
+    fn settle(&mut self, mut region: Bounds) -> Result<(Bounds, f64), [usize; 3]> {
+        let share = 1. / self.lattice.threshold() as f64;
+        let mut odometer = vec![0f64; self.mass.len()];
+        // Relaxation converges fastest with a factor that depends on how wide the result will be.
+        // The result is close to a ball holding 1 per cell, so estimate its width up front.
+        let total: f64 = self.mass.iter().map(|&m| m as f64).sum();
+        let diameter = 2. * (total * 3. / 4. / std::f64::consts::PI).cbrt();
+        let mut backwards = false;
+        let support = 'sweeps: loop {
+            let size = self.size;
+            // Each cell depends on the one just before it, so leave that until last.
+            let mut offsets = self.offsets();
+            offsets.sort_by_key(|&d| if backwards { d } else { -d });
+            offsets.sort_by_key(|&d| d.abs() == 1);
+            let (&last, others) = offsets.split_last().unwrap();
+            let width = (0..3)
+                .map(|i| region.hi[i] + 1 - region.lo[i])
+                .max()
+                .unwrap();
+            let omega = 2. / (1. + std::f64::consts::PI / diameter.max(width as f64));
+
+            let mut change = 0f64;
+            let mut support: Option<Bounds> = None;
+            for [x, y] in rows(region, backwards) {
+                let row = self.linear_index([x, y, 0]);
+                let near_wall = x == 1 || x == size[0] - 2 || y == 1 || y == size[1] - 2;
+                // The cells in this row that send mass out.
+                let mut sending = [usize::MAX, 0];
+                for k in 0..=region.hi[2] - region.lo[2] {
+                    let z = if backwards {
+                        region.hi[2] - k
+                    } else {
+                        region.lo[2] + k
+                    };
+                    let i = row + z;
+                    let old = odometer[i];
+                    let others: f64 = others
+                        .iter()
+                        .map(|&d| odometer[(i as isize + d) as usize])
+                        .sum();
+                    let partial =
+                        old * (1. - omega) + omega * (self.mass[i] as f64 - 1. + share * others);
+                    let new =
+                        (partial + omega * share * odometer[(i as isize + last) as usize]).max(0.);
+                    change = change.max((new - old).abs());
+                    odometer[i] = new;
+                    if new == 0. {
+                        continue;
+                    }
+
+                    if self.boundary == Boundary::Wall && (near_wall || z == 1 || z == size[2] - 2)
+                    {
+                        if let Some(offset) = self.grow_from([x, y, z], Some(&mut odometer))? {
+                            if let Some(support) = support {
+                                region = region.union(self.around(support, size));
+                            }
+                            if sending[0] <= sending[1] {
+                                let row = Bounds {
+                                    lo: [x, y, sending[0]],
+                                    hi: [x, y, sending[1]],
+                                };
+                                region = region.union(self.around(row, size));
+                            }
+                            region = region.shifted(offset);
+                            continue 'sweeps;
+                        }
+                    }
+                    sending = [sending[0].min(z), sending[1].max(z)];
+                }
+                if sending[0] <= sending[1] {
+                    let row = Bounds {
+                        lo: [x, y, sending[0]],
+                        hi: [x, y, sending[1]],
+                    };
+                    support = Some(support.map_or(row, |s| s.union(row)));
+                }
+            }
+
+            match support {
+                Some(support) if change > self.tolerance as f64 => {
+                    region = self.around(support, size);
+                    backwards = !backwards;
+                }
+                Some(support) => break support,
+                None => return Ok((region, 0.)),
+            }
+        };
+
+        // Cells can send far more mass than they hold, so add up what they send and receive before rounding.
+        let offsets = self.offsets();
+        let mut moved = vec![0f64; self.mass.len()];
+        for idx in cells(support, false) {
+            let i = self.linear_index(idx);
+            moved[i] -= odometer[i];
+            for &d in &offsets {
+                moved[(i as isize + d) as usize] += odometer[i] * share;
+            }
+        }
+        let reached = Bounds {
+            lo: support.lo.map(|x| x - 1),
+            hi: support.hi.map(|x| x + 1),
+        };
+        for idx in cells(reached, false) {
+            let i = self.linear_index(idx);
+            self.mass[i] = (self.mass[i] as f64 + moved[i]) as f32;
+        }
+        let size = self.size;
+        let at_edge = (0..3).any(|i| support.lo[i] == 1 || support.hi[i] == size[i] - 2);
+        let lost = if self.boundary == Boundary::Sink && at_edge {
+            self.drain(support)
+        } else {
+            0.
+        };
+        Ok((self.around(support, size).union(region), lost))
+    }
+
+    /// If a cell inside a wall is next to the boundary layer, grow the world away from it,
+    /// along with `odometer` if given. Returns how far the contents moved,
+    /// or on failure, the boundary cell the cell would send mass to.
+    fn grow_from(
+        &mut self,
+        idx: [usize; 3],
+        odometer: Option<&mut Vec<f64>>,
+    ) -> Result<Option<[usize; 3]>, [usize; 3]> {
+        let size = self.size;
+        let axes = [0, 1, 2].map(|j| idx[j] == 1 || idx[j] == size[j] - 2);
+        if axes == [false; 3] {
+            return Ok(None);
+        }
+        let offset = self.grow(axes).ok_or_else(|| {
+            [0, 1, 2].map(|j| match idx[j] {
+                1 => 0,
+                x if x == size[j] - 2 => x + 1,
+                x => x,
+            })
+        })?;
+        if let Some(odometer) = odometer {
+            *odometer = regrown(odometer, size, self.size, offset);
+        }
+        Ok(Some(offset))
+    }
+
+    /// The offsets of each cell's neighbors in `mass`.
+    fn offsets(&self) -> Vec<isize> {
+        let size = self.size;
+        self.lattice
+            .neighbors()
+            .iter()
+            .map(|&[dx, dy, dz]| (dx * size[1] as isize + dy) * size[2] as isize + dz)
+            .collect()
+    }
+
+    /// The cells next to `bounds`, or in it, inside the boundary layer of a world of the given size.
+    fn around(&self, bounds: Bounds, size: [usize; 3]) -> Bounds {
+        Bounds {
+            lo: bounds.lo.map(|x| (x - 1).max(1)),
+            hi: [0, 1, 2].map(|i| (bounds.hi[i] + 1).min(size[i] - 2)),
+        }
+    }
+
+    /// Empty the cells of the boundary layer next to `bounds` into the sink, returning how much mass they held.
+    fn drain(&mut self, bounds: Bounds) -> f64 {
+        let size = self.size;
+        let mut lost = 0.;
+        for x in bounds.lo[0] - 1..=bounds.hi[0] + 1 {
+            for y in bounds.lo[1] - 1..=bounds.hi[1] + 1 {
+                for z in bounds.lo[2] - 1..=bounds.hi[2] + 1 {
+                    let idx = [x, y, z];
+                    if (0..3).any(|i| idx[i] == 0 || idx[i] == size[i] - 1) {
+                        let i = self.linear_index(idx);
+                        lost += std::mem::take(&mut self.mass[i]) as f64;
+                    }
+                }
+            }
+        }
+        lost
+    }
+
+    /// Double the world's size along the given axes, keeping the contents centered.
+    /// Returns how far the contents moved, or `None` if the world is already as large as it may be.
+    fn grow(&mut self, axes: [bool; 3]) -> Option<[usize; 3]> {
+        let old_size = self.size;
+        let mut new_size = old_size;
+        let mut offset = [0; 3];
+        for i in 0..3 {
+            if axes[i] {
+                new_size[i] = (2 * old_size[i]).min(self.max_size[i]);
+                if new_size[i] <= old_size[i] {
+                    return None;
+                }
+                offset[i] = (new_size[i] - old_size[i]) / 2;
+            }
+        }
+
+        self.mass = regrown(&self.mass, old_size, new_size, offset);
+        self.size = new_size;
+        self.center = shifted(self.center, offset);
+        Some(offset)
+    }
+
+    /// The mass of all cells, in the same order as `World::to_color_array`.
+    pub fn masses(&self) -> &[f32] {
+        &self.mass
+    }
+
+    pub fn size(&self) -> [usize; 3] {
+        self.size
+    }
+
+    /// The cell where mass is added. This moves when the world grows.
+    pub fn center(&self) -> [usize; 3] {
+        self.center
+    }
+
+    pub fn lattice(&self) -> Lattice {
+        self.lattice
+    }
+
+    pub fn boundary(&self) -> Boundary {
+        self.boundary
+    }
+
+    pub fn total_mass(&self) -> f64 {
+        self.total_mass
+    }
+
+    fn linear_index(&self, idx: [usize; 3]) -> usize {
+        (idx[0] * self.size[1] + idx[1]) * self.size[2] + idx[2]
+    }
+
+    fn grid_index(&self, pos: [isize; 3]) -> [usize; 3] {
+        let idx = [0, 1, 2].map(|i| pos[i] + self.center[i] as isize);
+        assert!(
+            (0..3).all(|i| 0 <= idx[i] && idx[i] < self.size[i] as isize),
+            "Position {:?} is outside the world.",
+            pos
+        );
+        idx.map(|x| x as usize)
+    }
+}
+
+/// The cells in `region`, in order along each axis, or in reverse.
+fn cells(region: Bounds, backwards: bool) -> impl Iterator<Item = [usize; 3]> {
+    let lens = [0, 1, 2].map(|i| region.hi[i] + 1 - region.lo[i]);
+    let at = move |i: usize, k: usize| {
+        if backwards {
+            region.hi[i] - k
+        } else {
+            region.lo[i] + k
+        }
+    };
+    (0..lens[0]).flat_map(move |a| {
+        (0..lens[1]).flat_map(move |b| (0..lens[2]).map(move |c| [at(0, a), at(1, b), at(2, c)]))
+    })
+}
+
+/// The rows of cells in `region`, along the last axis, in order along the other axes, or in reverse.
+fn rows(region: Bounds, backwards: bool) -> impl Iterator<Item = [usize; 2]> {
+    cells(
+        Bounds {
+            lo: [region.lo[0], region.lo[1], 0],
+            hi: [region.hi[0], region.hi[1], 0],
+        },
+        backwards,
+    )
+    .map(|[x, y, _]| [x, y])
+}
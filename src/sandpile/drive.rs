@@ -0,0 +1,75 @@
+//! Dropping grains one at a time on random cells, as in studies of self-organized criticality.
+//!
+//! The random numbers come from a seeded PCG generator, whose output is the same on every platform,
+//! so a run can be repeated exactly.
+
+use super::{ArithmeticError, World};
+
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64Mcg;
+
+pub struct RandomDrive {
+    rng: Pcg64Mcg,
+    sites: Sites,
+}
+
+enum Sites {
+    /// Every cell inside the boundary layer of the world, as it is when the grain is dropped.
+    Uniform,
+    /// Cells relative to the center, chosen with probability proportional to their weights.
+    Weighted {
+        positions: Vec<[isize; 3]>,
+        weights: WeightedIndex<f64>,
+    },
+}
+
+impl RandomDrive {
+    /// Drop grains on cells chosen uniformly from inside the world's boundary layer.
+    pub fn uniform(seed: u64) -> Self {
+        Self {
+            rng: Pcg64Mcg::seed_from_u64(seed),
+            sites: Sites::Uniform,
+        }
+    }
+
+    /// Drop grains on the given cells, relative to the center, with probability proportional to their weights.
+    /// Panics unless the weights are all finite and non-negative, and some are positive.
+    pub fn weighted(seed: u64, sites: &[([isize; 3], f64)]) -> Self {
+        let weights = WeightedIndex::new(sites.iter().map(|&(_, w)| w))
+            .unwrap_or_else(|err| panic!("Invalid weights for a random drive: {}", err));
+        Self {
+            rng: Pcg64Mcg::seed_from_u64(seed),
+            sites: Sites::Weighted {
+                positions: sites.iter().map(|&(pos, _)| pos).collect(),
+                weights,
+            },
+        }
+    }
+
+    /// The cell the next grain will be dropped on, relative to the center.
+    pub fn next_site(&mut self, world: &World) -> [isize; 3] {
+        match &self.sites {
+            Sites::Uniform => {
+                let (size, center) = (world.size(), world.center());
+                let mut pos = [0; 3];
+                for i in 0..3 {
+                    let x = self.rng.gen_range(1..size[i] - 1);
+                    pos[i] = x as isize - center[i] as isize;
+                }
+                pos
+            }
+            Sites::Weighted { positions, weights } => positions[weights.sample(&mut self.rng)],
+        }
+    }
+
+    /// Drop grains one at a time, toppling until stable after each.
+    /// Stops at the first grain that cannot be added; see `World::try_add_sand_at`.
+    pub fn drive(&mut self, world: &mut World, num_grains: usize) -> Result<(), ArithmeticError> {
+        for _ in 0..num_grains {
+            let pos = self.next_site(world);
+            world.try_add_sand_at(pos, 1)?;
+        }
+        Ok(())
+    }
+}
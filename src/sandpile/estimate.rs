@@ -0,0 +1,735 @@
+//! Stabilizing a big pile on its own, starting from an estimate of how many times each cell topples,
+//! rather than from nothing, which takes far fewer sweeps.
+//!
+//! Grains dropped on a single cell make a pile with all the symmetries of a cube about that cell,
+//! so its odometer is found as in the `symmetric` module, and moved to where the grains were dropped.
+//! Where the piles of several drops overlap, the sum of their odometers is corrected by the same problem at half the scale:
+//! an eighth of the grains, dropped half as far apart, topple about a quarter as many times.
+//! Less the odometers of its own drops, the odometer of that problem is smooth enough to scale up.
+//!
+//! The estimate doesn't need to be right. The avalanche topples each cell the fewest times of any way
+//! of making every cell stable, so toppling from the estimate until every cell is stable can only overshoot.
+//! Then cells untopple, as long as that keeps every cell stable, and once no single cell can,
+//! the largest set of cells that can all untopple together is found by burning away the ones that can't,
+//! as in Dhar's burning algorithm. Once no cells can untopple, the odometer is exactly the avalanche's.
+
+use super::symmetric;
+use super::topple::{Bounds, Tally};
+use super::{chunk_cell_mut, Boundary, Lattice, Storage, World};
+
+use std::collections::HashMap;
+
+/// The least number of grains in the biggest drop that is worth estimating.
+/// Smaller piles are stabilized about as fast by doubling.
+const ESTIMATE_GRAINS: usize = 1 << 12;
+
+/// The most drops that are worth estimating, since each needs a pile of its own.
+const ESTIMATE_DROPS: usize = 1 << 6;
+
+/// The most grains that can be estimated, without any height overflowing.
+const MAX_GRAINS: usize = 1 << 28;
+
+/// The problem is halved until it has only one drop, or fewer grains than this.
+const COARSE_GRAINS: isize = 1 << 14;
+
+/// Whether `drops`, merged, are worth stabilizing from an estimate: they must all add grains, or all take them away.
+pub(super) fn is_worth_estimating(drops: &[([isize; 3], isize)]) -> bool {
+    let sign = drops[0].1.signum();
+    let grains = drops.iter().map(|&(_, n)| n.unsigned_abs());
+    drops.len() <= ESTIMATE_DROPS
+        && drops.iter().all(|&(_, n)| n.signum() == sign)
+        && grains.clone().max().unwrap() >= ESTIMATE_GRAINS
+        && grains.sum::<usize>() <= MAX_GRAINS
+}
+
+/// A pile partway through being stabilized from an estimate, at each scale in turn.
+pub(super) struct Estimate {
+    lattice: Lattice,
+    /// The drops, as they were given, with grains added if positive, or taken away if negative.
+    drops: Vec<([isize; 3], isize)>,
+    /// The drops at each scale, the finest first, all adding grains.
+    levels: Vec<Vec<([isize; 3], isize)>>,
+    /// The odometer of each single drop's pile, by its number of grains; see `symmetric::pile_odometer`.
+    piles: HashMap<isize, (usize, Vec<u32>)>,
+    /// For a world with a sink, the positions of its boundary layer along each axis.
+    sink: Option<[[isize; 2]; 3]>,
+    max_size: [usize; 3],
+    /// The pile at the coarsest scale still to be finished.
+    relaxation: Relaxation,
+}
+
+impl Estimate {
+    /// `drops` must be merged, and worth estimating.
+    pub(super) fn new(world: &World, drops: Vec<([isize; 3], isize)>) -> Self {
+        let mut levels = vec![drops.iter().map(|&(pos, n)| (pos, n.abs())).collect()];
+        loop {
+            let finer: &Vec<([isize; 3], isize)> = levels.last().unwrap();
+            if finer.len() < 2 || finer.iter().map(|&(_, n)| n).sum::<isize>() < COARSE_GRAINS {
+                break;
+            }
+            let mut coarser = Vec::new();
+            for &(pos, n) in finer {
+                coarser.push((pos.map(|x| x.div_euclid(2)), n / 8));
+            }
+            levels.push(super::merged(&coarser));
+        }
+
+        let sink = match world.boundary {
+            Boundary::Sink => Some([0, 1, 2].map(|i| {
+                let center = world.center[i] as isize;
+                [-center, world.size[i] as isize - 1 - center]
+            })),
+            Boundary::Wall => None,
+        };
+        let mut out = Self {
+            lattice: world.lattice,
+            drops,
+            levels,
+            piles: HashMap::new(),
+            sink,
+            max_size: world.max_size,
+            relaxation: Relaxation::empty(world.lattice),
+        };
+        out.relaxation = out.start_level(None);
+        out
+    }
+
+    /// The drops, as they were given.
+    pub(super) fn drops(&self) -> &[([isize; 3], isize)] {
+        &self.drops
+    }
+
+    pub(super) fn is_done(&self) -> bool {
+        self.levels.len() == 1 && self.relaxation.is_done()
+    }
+
+    /// Do some of the work: a sweep, or finding a set of cells to untopple, or starting on the next finer scale.
+    /// Fails if the pile would be more than twice as wide as the world can grow.
+    pub(super) fn step(&mut self) -> Result<(), ()> {
+        if !self.relaxation.is_done() {
+            return self.relaxation.step();
+        }
+        self.levels.pop();
+        let coarse = std::mem::replace(&mut self.relaxation, Relaxation::empty(self.lattice));
+        self.relaxation = self.start_level(Some(&coarse));
+        Ok(())
+    }
+
+    /// How many cells are waiting to topple at the current scale.
+    pub(super) fn num_unstable(&self) -> usize {
+        self.relaxation.num_unstable()
+    }
+
+    /// Estimate the pile of the finest drops left, from the finished pile of the coarser drops, if there are any.
+    fn start_level(&mut self, coarse: Option<&Relaxation>) -> Relaxation {
+        let drops = self.levels.last().unwrap();
+        // Only the finest scale is in the world. The rest only estimate it.
+        let sink = if self.levels.len() == 1 {
+            self.sink
+        } else {
+            None
+        };
+        let lattice = self.lattice;
+        for &(_, n) in drops
+            .iter()
+            .chain(coarse.iter().flat_map(|coarse| &coarse.drops))
+        {
+            self.piles
+                .entry(n)
+                .or_insert_with(|| symmetric::pile_odometer(lattice, n));
+        }
+
+        // Every cell that might topple, with a layer of cells around it that only take grains.
+        let mut lo = [isize::MAX; 3];
+        let mut hi = [isize::MIN; 3];
+        for &(pos, n) in drops {
+            let reach = self.piles[&n].0 as isize + 2;
+            for i in 0..3 {
+                lo[i] = lo[i].min(pos[i] - reach);
+                hi[i] = hi[i].max(pos[i] + reach);
+            }
+        }
+        if let Some((coarse_lo, coarse_hi)) = coarse.and_then(|coarse| coarse.toppled()) {
+            for i in 0..3 {
+                lo[i] = lo[i].min(2 * coarse_lo[i] - 2);
+                hi[i] = hi[i].max(2 * coarse_hi[i] + 3);
+            }
+        }
+        if let Some(sink) = sink {
+            for i in 0..3 {
+                lo[i] = lo[i].max(sink[i][0]);
+                hi[i] = hi[i].min(sink[i][1]);
+            }
+        }
+        let size = [0, 1, 2].map(|i| (hi[i] - lo[i] + 1) as usize);
+        // Cells next to the outermost layer are left out, so that nothing topples onto it before the box can grow.
+        // Toppling too few times is as good a start as any.
+        let index = |pos: [isize; 3]| -> Option<usize> {
+            let idx = [0, 1, 2].map(|i| pos[i] - lo[i]);
+            let inside = (0..3).all(|i| 1 < idx[i] && idx[i] < size[i] as isize - 2);
+            inside
+                .then(|| (idx[0] as usize * size[1] + idx[1] as usize) * size[2] + idx[2] as usize)
+        };
+        let mut estimate = vec![0; size.iter().product()];
+
+        // The piles of each drop on its own.
+        for &(pos, n) in drops {
+            add_pile(&self.piles[&n], pos, 1, |pos, t| {
+                if let Some(i) = index(pos) {
+                    estimate[i] += t;
+                }
+            });
+        }
+
+        // What overlapping at half the scale did to the coarse drops' piles, scaled up.
+        // Cells between the coarse cells get the average of the ones on either side.
+        if let Some(coarse) = coarse {
+            let mut overlap: Vec<i64> = coarse.odometer.iter().map(|&t| t as i64).collect();
+            for &(pos, n) in &coarse.drops {
+                add_pile(&self.piles[&n], pos, -1, |pos, t| {
+                    if let Some(i) = coarse.index(pos) {
+                        overlap[i] += t;
+                    }
+                });
+            }
+            let overlap = |pos: [isize; 3]| coarse.index(pos).map_or(0, |i| overlap[i]);
+            for x in lo[0]..=hi[0] {
+                for y in lo[1]..=hi[1] {
+                    for z in lo[2]..=hi[2] {
+                        let i = match index([x, y, z]) {
+                            Some(i) => i,
+                            None => continue,
+                        };
+                        let mut sum = 0;
+                        for corner in 0..8 {
+                            let pos = [x, y, z];
+                            let corner = [0, 1, 2]
+                                .map(|j| (pos[j] + (corner >> j & 1) * (pos[j] & 1)).div_euclid(2));
+                            sum += overlap(corner);
+                        }
+                        // Four times the average of the eight corners.
+                        estimate[i] += (sum + 1).div_euclid(2);
+                    }
+                }
+            }
+        }
+
+        let walls = walls(sink, lo, hi);
+        let mut relaxation = Relaxation::empty(lattice);
+        relaxation.lo = lo;
+        relaxation.resize(size, [0; 3], walls);
+        relaxation.sink = sink;
+        relaxation.max_size = self.max_size.map(|n| 2 * n);
+        relaxation.start(
+            drops.clone(),
+            estimate.into_iter().map(|t| t.max(0) as u32).collect(),
+        );
+        relaxation
+    }
+}
+
+/// Whether each side of a box from `lo` to `hi` has a wall, rather than being in the `sink`.
+fn walls(sink: Option<[[isize; 2]; 3]>, lo: [isize; 3], hi: [isize; 3]) -> [[bool; 2]; 3] {
+    [0, 1, 2].map(|i| {
+        let at_sink = |side: usize| sink.is_some_and(|sink| sink[i][side] == [lo, hi][side][i]);
+        [!at_sink(0), !at_sink(1)]
+    })
+}
+
+/// Call `f` with the position of every cell that toppled in `pile`, dropped at `pos`, and `sign` times how many times it did.
+fn add_pile(
+    pile: &(usize, Vec<u32>),
+    pos: [isize; 3],
+    sign: i64,
+    mut f: impl FnMut([isize; 3], i64),
+) {
+    let (reach, odometer) = pile;
+    let reach = *reach as isize;
+    for x in -reach..=reach {
+        for y in -reach..=reach {
+            for z in -reach..=reach {
+                let t = symmetric::cell(odometer, [x, y, z]);
+                if t != 0 {
+                    f([pos[0] + x, pos[1] + y, pos[2] + z], sign * t as i64);
+                }
+            }
+        }
+    }
+}
+
+/// A box of cells, with heights that can hold any number of grains or holes, and how many times each has toppled.
+/// Cells in the outermost layer of the box never topple. Along a side of the box in a world's sink,
+/// the grains they take are lost. Otherwise, the box grows before any grains reach them.
+struct Relaxation {
+    threshold: i32,
+    neighbors: Vec<[isize; 3]>,
+    /// Where the grains were dropped, and how many.
+    drops: Vec<([isize; 3], isize)>,
+    /// The position of the first cell, relative to the center of the world.
+    lo: [isize; 3],
+    size: [usize; 3],
+    /// Whether each side of the box, along each axis, grows when the avalanche reaches it, rather than being in the sink.
+    walls: [[bool; 2]; 3],
+    /// For a world with a sink, the positions of its boundary layer along each axis. The box never goes past them.
+    sink: Option<[[isize; 2]; 3]>,
+    /// The box fails to grow past this size.
+    max_size: [usize; 3],
+    /// How far each neighbor is from a cell, in the order of the cells.
+    offsets: Vec<isize>,
+    /// How far each row with a neighbor of a cell is from the cell's own row.
+    row_offsets: Vec<isize>,
+    heights: Vec<i32>,
+    odometer: Vec<u32>,
+    /// Whether each row along the last axis, by `x * size[1] + y`, might have a cell that can topple, or untopple.
+    marked: Vec<bool>,
+    /// The cells to look for a set that can untopple among first; see `burn`.
+    last_burn: Option<Vec<usize>>,
+    backwards: bool,
+    stage: Stage,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    /// Toppling every unstable cell.
+    Toppling,
+    /// Untoppling any cell that can, while every cell stays stable.
+    Untoppling,
+    Done,
+}
+
+impl Relaxation {
+    fn empty(lattice: Lattice) -> Self {
+        Self {
+            threshold: lattice.threshold() as i32,
+            neighbors: lattice.neighbors(),
+            drops: Vec::new(),
+            lo: [0; 3],
+            size: [0; 3],
+            walls: [[true; 2]; 3],
+            sink: None,
+            max_size: [0; 3],
+            offsets: Vec::new(),
+            row_offsets: Vec::new(),
+            heights: Vec::new(),
+            odometer: Vec::new(),
+            marked: Vec::new(),
+            last_burn: None,
+            backwards: false,
+            stage: Stage::Done,
+        }
+    }
+
+    /// Drop grains into the box, and topple each cell as many times as `estimate` says, to start from there.
+    fn start(&mut self, drops: Vec<([isize; 3], isize)>, estimate: Vec<u32>) {
+        for &(pos, n) in &drops {
+            let i = self.index(pos).expect("Drops are inside the box.");
+            self.heights[i] += n as i32;
+        }
+        for (i, &t) in estimate.iter().enumerate() {
+            if t != 0 {
+                self.heights[i] -= self.threshold * t as i32;
+                for &offset in &self.offsets {
+                    self.heights[(i as isize + offset) as usize] += t as i32;
+                }
+            }
+        }
+        self.drops = drops;
+        self.odometer = estimate;
+        self.marked = vec![true; self.size[0] * self.size[1]];
+        self.stage = Stage::Toppling;
+    }
+
+    fn is_done(&self) -> bool {
+        self.stage == Stage::Done
+    }
+
+    /// Where the cell at `pos`, relative to the center of the world, is stored, if it is inside the outermost layer.
+    fn index(&self, pos: [isize; 3]) -> Option<usize> {
+        let idx = [0, 1, 2].map(|i| pos[i] - self.lo[i]);
+        let inside = (0..3).all(|i| 0 < idx[i] && idx[i] < self.size[i] as isize - 1);
+        inside.then(|| {
+            ((idx[0] as usize * self.size[1]) + idx[1] as usize) * self.size[2] + idx[2] as usize
+        })
+    }
+
+    /// The position of the cell stored at `cell`, relative to the center of the world.
+    fn position(&self, cell: usize) -> [isize; 3] {
+        let [_, ny, nz] = self.size;
+        let idx = [cell / (ny * nz), cell / nz % ny, cell % nz];
+        [0, 1, 2].map(|i| self.lo[i] + idx[i] as isize)
+    }
+
+    /// The corners of a box containing every cell that has toppled, relative to the center of the world, if any have.
+    fn toppled(&self) -> Option<([isize; 3], [isize; 3])> {
+        let mut out: Option<([isize; 3], [isize; 3])> = None;
+        for (cell, &t) in self.odometer.iter().enumerate() {
+            if t > 0 {
+                let pos = self.position(cell);
+                out = Some(out.map_or((pos, pos), |(lo, hi)| {
+                    (
+                        [0, 1, 2].map(|i| lo[i].min(pos[i])),
+                        [0, 1, 2].map(|i| hi[i].max(pos[i])),
+                    )
+                }));
+            }
+        }
+        out
+    }
+
+    fn num_unstable(&self) -> usize {
+        match self.stage {
+            Stage::Toppling => self
+                .heights
+                .iter()
+                .filter(|&&h| h >= self.threshold)
+                .count(),
+            _ => 0,
+        }
+    }
+
+    /// Sweep once, or if no cell can untopple on its own, find a set that can.
+    fn step(&mut self) -> Result<(), ()> {
+        match self.stage {
+            Stage::Toppling => match self.sweep(1) {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.marked.iter_mut().for_each(|m| *m = true);
+                    self.stage = Stage::Untoppling;
+                }
+                Err(sides) => self.grow(sides)?,
+            },
+            Stage::Untoppling => {
+                // Only cells that toppled untopple, so this never needs the box to grow.
+                if !self.sweep(-1).unwrap() && !self.burn() {
+                    self.stage = Stage::Done;
+                }
+            }
+            Stage::Done => {}
+        }
+        Ok(())
+    }
+
+    /// Topple each marked cell that is unstable as many times as it can, if `sign` is positive,
+    /// or else untopple each that has a hole, and has toppled, as many times as it can while staying stable.
+    /// Returns whether any did. A cell can't topple onto a side of the box that grows,
+    /// so if one would, this stops, and returns the sides that must grow first.
+    fn sweep(&mut self, sign: i32) -> Result<bool, [[bool; 2]; 3]> {
+        let [nx, ny, nz] = self.size;
+        let threshold = self.threshold;
+        let mut changed = false;
+        let backwards = self.backwards;
+        self.backwards = !backwards;
+        for i in 0..nx * ny {
+            let row = if backwards { nx * ny - 1 - i } else { i };
+            let (x, y) = (row / ny, row % ny);
+            if !self.marked[row] || x == 0 || x == nx - 1 || y == 0 || y == ny - 1 {
+                continue;
+            }
+            self.marked[row] = false;
+            let start = row * nz;
+            for z in 1..nz - 1 {
+                let cell = start + z;
+                let h = self.heights[cell];
+                let k = if sign > 0 {
+                    if h < threshold {
+                        continue;
+                    }
+                    h / threshold
+                } else {
+                    if h >= 0 || self.odometer[cell] == 0 {
+                        continue;
+                    }
+                    -((-h + threshold - 1) / threshold).min(self.odometer[cell] as i32)
+                };
+
+                let idx = [x, y, z];
+                let sides = [0, 1, 2].map(|i| {
+                    let walls = self.walls[i];
+                    [
+                        walls[0] && idx[i] == 1,
+                        walls[1] && idx[i] == self.size[i] - 2,
+                    ]
+                });
+                if sides != [[false; 2]; 3] {
+                    self.marked[row] = true;
+                    return Err(sides);
+                }
+
+                self.heights[cell] -= k * threshold;
+                self.odometer[cell] = (self.odometer[cell] as i32 + k) as u32;
+                for &offset in &self.offsets {
+                    self.heights[(cell as isize + offset) as usize] += k;
+                }
+                for &offset in &self.row_offsets {
+                    self.marked[(row as isize + offset) as usize] = true;
+                }
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Untopple the largest set of cells that have toppled, and can all untopple together, leaving every cell stable,
+    /// then keep untoppling what is left of it, after burning away the cells that no longer can.
+    /// A cell can, if it has fewer grains than it has neighbors in the set, and has untoppled fewer times than it toppled.
+    /// The set is looked for around the last one first, since it is usually inside it, or next to it.
+    /// Returns whether anything untoppled.
+    fn burn(&mut self) -> bool {
+        if let Some(cells) = self.last_burn.take() {
+            if self.burn_from(cells) {
+                return true;
+            }
+        }
+        let cells = (0..self.odometer.len())
+            .filter(|&cell| self.odometer[cell] > 0)
+            .collect();
+        self.burn_from(cells)
+    }
+
+    /// Like `burn`, but only looking among the cells in `unburnt`, which must be in order.
+    fn burn_from(&mut self, mut unburnt: Vec<usize>) -> bool {
+        let nz = self.size[2];
+        let threshold = self.threshold;
+        // How many neighbors each cell has in the set, or -1 if it has burnt, or was never in the set.
+        let mut unburnt_neighbors = vec![-1i8; self.heights.len()];
+        for &cell in &unburnt {
+            unburnt_neighbors[cell] = 0;
+        }
+        let mut burning = Vec::new();
+        for &cell in &unburnt {
+            let n = self
+                .offsets
+                .iter()
+                .filter(|&&offset| unburnt_neighbors[(cell as isize + offset) as usize] >= 0)
+                .count() as i8;
+            unburnt_neighbors[cell] = n;
+            if self.heights[cell] >= n as i32 || self.odometer[cell] == 0 {
+                burning.push(cell);
+            }
+        }
+
+        let mut rounds = 0;
+        loop {
+            while let Some(cell) = burning.pop() {
+                if unburnt_neighbors[cell] < 0 {
+                    continue;
+                }
+                unburnt_neighbors[cell] = -1;
+                for &offset in &self.offsets {
+                    let neighbor = (cell as isize + offset) as usize;
+                    if unburnt_neighbors[neighbor] >= 0 {
+                        unburnt_neighbors[neighbor] -= 1;
+                        if self.heights[neighbor] >= unburnt_neighbors[neighbor] as i32 {
+                            burning.push(neighbor);
+                        }
+                    }
+                }
+            }
+            unburnt.retain(|&cell| unburnt_neighbors[cell] >= 0);
+            if unburnt.is_empty() {
+                return rounds > 0;
+            }
+            if rounds == 0 {
+                // The next set is looked for among these cells, and the ones next to them.
+                let mut next = unburnt_neighbors
+                    .iter()
+                    .map(|&n| n >= 0)
+                    .collect::<Vec<_>>();
+                for &cell in &unburnt {
+                    for &offset in &self.offsets {
+                        next[(cell as isize + offset) as usize] = true;
+                    }
+                }
+                let next = (0..next.len())
+                    .filter(|&cell| next[cell] && self.odometer[cell] > 0)
+                    .collect();
+                self.last_burn = Some(next);
+            }
+            rounds += 1;
+
+            let mut last_row = usize::MAX;
+            for &cell in &unburnt {
+                self.heights[cell] += threshold;
+                self.odometer[cell] -= 1;
+                for &offset in &self.offsets {
+                    self.heights[(cell as isize + offset) as usize] -= 1;
+                }
+                let row = cell / nz;
+                if row != last_row {
+                    for &offset in &self.row_offsets {
+                        self.marked[(row as isize + offset) as usize] = true;
+                    }
+                    last_row = row;
+                }
+            }
+            // Cells that have untoppled as many times as they toppled, or gained too many grains, burn.
+            for &cell in &unburnt {
+                if self.odometer[cell] == 0 || self.heights[cell] >= unburnt_neighbors[cell] as i32
+                {
+                    burning.push(cell);
+                }
+            }
+        }
+    }
+
+    /// Grow the box on the given sides, so that grains can reach further. Sides in the sink never grow.
+    /// Fails if the box would be larger than its maximum size.
+    fn grow(&mut self, sides: [[bool; 2]; 3]) -> Result<(), ()> {
+        let mut lo = self.lo;
+        let mut hi = [0, 1, 2].map(|i| self.lo[i] + self.size[i] as isize - 1);
+        for i in 0..3 {
+            let more = self.size[i] as isize / 8 + 2;
+            if sides[i][0] {
+                lo[i] -= more;
+            }
+            if sides[i][1] {
+                hi[i] += more;
+            }
+            if let Some(sink) = self.sink {
+                lo[i] = lo[i].max(sink[i][0]);
+                hi[i] = hi[i].min(sink[i][1]);
+            }
+        }
+        let size = [0, 1, 2].map(|i| (hi[i] - lo[i] + 1) as usize);
+        if (0..3).any(|i| size[i] > self.max_size[i]) {
+            return Err(());
+        }
+        let walls = walls(self.sink, lo, hi);
+        let offset = [0, 1, 2].map(|i| (self.lo[i] - lo[i]) as usize);
+        self.lo = lo;
+        self.resize(size, offset, walls);
+        Ok(())
+    }
+
+    /// Move every cell by `offset` into a box of `size`, along with everything that depends on the size.
+    fn resize(&mut self, size: [usize; 3], offset: [usize; 3], walls: [[bool; 2]; 3]) {
+        let old_size = self.size;
+        let volume = size.iter().product();
+        if self.heights.is_empty() {
+            self.heights = vec![0; volume];
+            self.odometer = vec![0; volume];
+        } else {
+            self.heights = super::regrown(&self.heights, old_size, size, offset);
+            self.odometer = super::regrown(&self.odometer, old_size, size, offset);
+        }
+        self.marked = vec![true; size[0] * size[1]];
+        self.size = size;
+        self.walls = walls;
+        self.offsets = self
+            .neighbors
+            .iter()
+            .map(|&[dx, dy, dz]| (dx * size[1] as isize + dy) * size[2] as isize + dz)
+            .collect();
+        self.row_offsets = self
+            .neighbors
+            .iter()
+            .map(|&[dx, dy, _]| dx * size[1] as isize + dy)
+            .collect();
+        self.row_offsets.sort_unstable();
+        self.row_offsets.dedup();
+    }
+}
+
+impl World {
+    /// Add a pile that `estimate` has finished stabilizing on its own to this world, growing it as the pile grew.
+    /// Returns the pile's avalanche, carried on in this world, and a box containing every cell the pile changed.
+    /// Returns `None` if the world can't grow enough, without changing it.
+    pub(super) fn add_estimated(&mut self, estimate: &Estimate) -> Option<(Tally, Bounds)> {
+        let pile = &estimate.relaxation;
+        let sign = estimate.drops[0].1.signum();
+        let toppled = pile.toppled();
+        let mut lost = 0;
+        for (cell, &h) in pile.heights.iter().enumerate() {
+            if h != 0 && pile.index(pile.position(cell)).is_none() {
+                lost += h as isize;
+            }
+        }
+
+        // The pile, in an empty world shaped like this one, would have grown until nothing toppled next to the boundary layer.
+        if let Some((lo, hi)) = toppled {
+            let fits = |size: [usize; 3], center: [usize; 3], i: usize| {
+                let c = center[i] as isize;
+                lo[i] + c >= 2 && hi[i] + c <= size[i] as isize - 3
+            };
+            if self.boundary == Boundary::Wall {
+                let (mut size, mut center) = (self.size, self.center);
+                for i in 0..3 {
+                    while !fits(size, center, i) {
+                        let grown = (2 * size[i]).min(self.max_size[i]);
+                        if grown <= size[i] {
+                            return None;
+                        }
+                        center[i] += (grown - size[i]) / 2;
+                        size[i] = grown;
+                    }
+                }
+                loop {
+                    let axes = [0, 1, 2].map(|i| !fits(self.size, self.center, i));
+                    if axes == [false; 3] {
+                        break;
+                    }
+                    self.grow(axes).unwrap();
+                }
+            }
+        }
+
+        // Cells change up to one cell further out than anything toppled, and where grains were dropped.
+        let mut changed = toppled.map(|(lo, hi)| (lo.map(|x| x - 1), hi.map(|x| x + 1)));
+        for &(pos, _) in &estimate.drops {
+            changed = Some(changed.map_or((pos, pos), |(lo, hi)| {
+                (
+                    [0, 1, 2].map(|i| lo[i].min(pos[i])),
+                    [0, 1, 2].map(|i| hi[i].max(pos[i])),
+                )
+            }));
+        }
+        let (lo, hi) = changed.unwrap();
+        let first = self.position([1; 3]);
+        let last = self.position(self.size.map(|n| n - 2));
+        let bounds = Bounds {
+            lo: self.grid_index([0, 1, 2].map(|i| lo[i].max(first[i]))),
+            hi: self.grid_index([0, 1, 2].map(|i| hi[i].min(last[i]))),
+        };
+
+        self.record(bounds);
+        let seeds = estimate
+            .drops
+            .iter()
+            .map(|&(pos, _)| self.grid_index(pos))
+            .collect();
+        let mut tally = Tally::new(self.size, seeds, self.odometer.is_some());
+        tally.lost = sign * lost;
+        let (size, center) = (self.size, self.center);
+        for x in bounds.lo[0]..=bounds.hi[0] {
+            for y in bounds.lo[1]..=bounds.hi[1] {
+                let row = [x, y, bounds.lo[2]];
+                // Everything that changed is inside the box.
+                let start = pile.index(self.position(row)).unwrap();
+                let len = bounds.hi[2] - bounds.lo[2] + 1;
+                tally.count_row(row, &pile.odometer[start..start + len]);
+                let heights = &pile.heights[start..start + len];
+                match &mut self.storage {
+                    Storage::Dense(data) => {
+                        let start = (x * size[1] + y) * size[2] + bounds.lo[2];
+                        for (h, &p) in data[start..start + len].iter_mut().zip(heights) {
+                            *h += (sign * p as isize) as i8;
+                        }
+                    }
+                    Storage::Chunked(chunks) => {
+                        for (z, &p) in (bounds.lo[2]..).zip(heights) {
+                            if p != 0 {
+                                let pos =
+                                    [0, 1, 2].map(|i| [x, y, z][i] as isize - center[i] as isize);
+                                *chunk_cell_mut(chunks, pos) += (sign * p as isize) as i8;
+                            }
+                        }
+                    }
+                    Storage::Symmetric(_) | Storage::Packed(_) => {
+                        unreachable!("Only dense and chunked worlds add a pile.")
+                    }
+                }
+            }
+        }
+        Some((tally, bounds))
+    }
+}
@@ -0,0 +1,170 @@
+//! The fixed-energy sandpile, on a torus, where no grain is ever added or lost.
+//! It starts from grains scattered at random, and every step, all the unstable cells topple at once.
+//!
+//! Below a critical density, activity eventually dies out, leaving a stable, absorbing state;
+//! above it, the pile stays active forever. How the active density behaves near that density
+//! is the absorbing-state phase transition, so each step reports it.
+
+use super::Lattice;
+
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64Mcg;
+
+pub struct FixedEnergyWorld {
+    size: [usize; 3],
+    /// The height of every cell, in the same order as `World::to_color_array`.
+    /// Nothing bounds these, since grains can be scattered, or sent by the Manna rule, onto one cell over and over.
+    heights: Vec<i32>,
+    lattice: Lattice,
+    neighbors: Vec<[isize; 3]>,
+    /// Whether toppling cells send each grain to a random neighbor, as in `World::with_manna`.
+    manna: bool,
+    rng: Pcg64Mcg,
+    /// Every unstable cell, in increasing order.
+    active: Vec<usize>,
+    total_grains: usize,
+    steps: u64,
+}
+
+impl FixedEnergyWorld {
+    /// Create a world with the given dimensions, each at least 3, and an average of `density` grains per cell,
+    /// each dropped on a cell chosen at random from `seed`. The density must not be more than the lattice's threshold.
+    pub fn random(size: [usize; 3], lattice: Lattice, density: f64, seed: u64) -> Self {
+        assert!(
+            size.iter().all(|&n| n >= 3),
+            "World dimensions must be at least 3."
+        );
+        let threshold = lattice.threshold();
+        assert!(
+            (0. ..=threshold as f64).contains(&density),
+            "The density must be between 0 and {}.",
+            threshold
+        );
+
+        let num_cells = size[0] * size[1] * size[2];
+        let total_grains = (density * num_cells as f64).round() as usize;
+        let mut rng = Pcg64Mcg::seed_from_u64(seed);
+        let mut heights = vec![0; num_cells];
+        for _ in 0..total_grains {
+            heights[rng.gen_range(0..num_cells)] += 1;
+        }
+        let active = (0..num_cells)
+            .filter(|&i| heights[i] >= threshold as i32)
+            .collect();
+
+        Self {
+            size,
+            heights,
+            lattice,
+            neighbors: lattice.neighbors(),
+            manna: false,
+            rng,
+            active,
+            total_grains,
+            steps: 0,
+        }
+    }
+
+    /// Topple by the Manna rule, sending each grain to a neighbor chosen at random,
+    /// using the same generator that scattered the grains.
+    pub fn with_manna(mut self) -> Self {
+        assert_eq!(
+            self.steps, 0,
+            "Cannot change the rule of a running sandpile."
+        );
+        self.manna = true;
+        self
+    }
+
+    pub fn size(&self) -> [usize; 3] {
+        self.size
+    }
+
+    pub fn lattice(&self) -> Lattice {
+        self.lattice
+    }
+
+    pub fn is_manna(&self) -> bool {
+        self.manna
+    }
+
+    pub fn heights(&self) -> &[i32] {
+        &self.heights
+    }
+
+    /// Which never changes.
+    pub fn total_grains(&self) -> usize {
+        self.total_grains
+    }
+
+    /// The average number of grains per cell.
+    pub fn density(&self) -> f64 {
+        self.total_grains as f64 / self.heights.len() as f64
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// The fraction of cells that are unstable, and will topple in the next step.
+    pub fn active_density(&self) -> f64 {
+        self.active.len() as f64 / self.heights.len() as f64
+    }
+
+    /// Topple every unstable cell once, all at the same time, wrapping around the edges.
+    /// Returns the fraction of cells that toppled.
+    pub fn step(&mut self) -> f64 {
+        let density = self.active_density();
+        let threshold = self.neighbors.len();
+
+        for &i in &self.active {
+            self.heights[i] -= threshold as i32;
+        }
+        let mut touched = self.active.clone();
+        for &i in &self.active {
+            let cell = self.cell(i);
+            for k in 0..threshold {
+                let d = if self.manna {
+                    self.neighbors[self.rng.gen_range(0..threshold)]
+                } else {
+                    self.neighbors[k]
+                };
+                let j = self.wrapped(cell, d);
+                self.heights[j] += 1;
+                touched.push(j);
+            }
+        }
+
+        // Only cells that toppled, or got a grain, can have become unstable.
+        touched.sort_unstable();
+        touched.dedup();
+        touched.retain(|&i| self.heights[i] >= threshold as i32);
+        self.active = touched;
+        self.steps += 1;
+        density
+    }
+
+    /// Step until the pile is stable, yielding the fraction of cells that toppled in each step.
+    /// Above the critical density, this never ends.
+    pub fn activity(&mut self) -> impl Iterator<Item = f64> + '_ {
+        std::iter::from_fn(move || {
+            if self.active.is_empty() {
+                None
+            } else {
+                Some(self.step())
+            }
+        })
+    }
+
+    fn cell(&self, i: usize) -> [usize; 3] {
+        let [_, ny, nz] = self.size;
+        [i / (ny * nz), i / nz % ny, i % nz]
+    }
+
+    /// The linear index of the neighbor of `cell` in the direction `d`, wrapping around the edges.
+    fn wrapped(&self, cell: [usize; 3], d: [isize; 3]) -> usize {
+        let [x, y, z] =
+            [0, 1, 2].map(|i| (cell[i] as isize + d[i]).rem_euclid(self.size[i] as isize) as usize);
+        (x * self.size[1] + y) * self.size[2] + z
+    }
+}
@@ -0,0 +1,123 @@
+//! The Manna sandpile, where a toppling cell sends each of its grains to a neighbor chosen at random,
+//! rather than one to every neighbor. Cells still topple once they have a grain for every neighbor,
+//! and untopple once they have a hole that deep, taking each grain from a random neighbor.
+//!
+//! Unlike the abelian sandpile, the result depends on the order that cells topple in.
+//! Here, grains are dropped one at a time, and a toppling cell sends its grains one at a time.
+//! Any cell that becomes unstable topples straight away, before any other grain moves,
+//! so heights never go past the threshold. Along with a seeded generator, this makes every run reproducible.
+
+use super::topple::Tally;
+use super::{chunk_cell_mut, chunk_location, shifted, Boundary, Storage, World, SYMMETRIC_CHANGE};
+
+use rand::Rng;
+use rand_pcg::Pcg64Mcg;
+
+/// A cell partway through toppling.
+struct Topple {
+    cell: [usize; 3],
+    /// 1 if the cell is toppling, or -1 if it is untoppling.
+    sign: i8,
+    /// How many grains it has yet to send, or to take.
+    left: i8,
+}
+
+impl World {
+    /// Add up to `max_grains` of the grains left in `drops` to cells, relative to the center,
+    /// or take them away, one at a time, and topple by the Manna rule after each.
+    /// Every drop must be inside the boundary layer, and the random numbers come from `rng`.
+    ///
+    /// Counts the avalanche in `tally`, and takes the grains it added out of `drops`.
+    /// On failure, returns the boundary cell the avalanche reached.
+    pub(super) fn drop_manna(
+        &mut self,
+        rng: &mut Pcg64Mcg,
+        drops: &mut [([isize; 3], isize)],
+        max_grains: usize,
+        tally: &mut Tally,
+    ) -> Result<(), [usize; 3]> {
+        let neighbors = self.lattice.neighbors();
+        let threshold = neighbors.len() as i8;
+
+        let mut num_dropped = 0;
+        let mut stack: Vec<Topple> = Vec::new();
+        for (pos, num_grains) in drops.iter_mut() {
+            let sign = num_grains.signum() as i8;
+            while *num_grains != 0 && num_dropped < max_grains {
+                *num_grains -= sign as isize;
+                num_dropped += 1;
+                let cell = self.grid_index(*pos);
+                if self.change(cell, sign) == sign * threshold {
+                    stack.push(self.topple(cell, sign, threshold, tally));
+                }
+
+                while let Some(top) = stack.last_mut() {
+                    if top.left == 0 {
+                        stack.pop();
+                        continue;
+                    }
+                    top.left -= 1;
+                    let (from, sign) = (top.cell, top.sign);
+
+                    let d = neighbors[rng.gen_range(0..neighbors.len())];
+                    let mut to = [0, 1, 2].map(|i| (from[i] as isize + d[i]) as usize);
+                    let axes = [0, 1, 2].map(|i| to[i] == 0 || to[i] == self.size[i] - 1);
+                    if axes != [false; 3] {
+                        if self.boundary == Boundary::Sink {
+                            tally.lost += sign as isize;
+                            continue;
+                        }
+                        let offset = self.grow(axes).ok_or(to)?;
+                        tally.resize(self.size, offset);
+                        for topple in &mut stack {
+                            topple.cell = shifted(topple.cell, offset);
+                        }
+                        to = shifted(to, offset);
+                    }
+
+                    if self.change(to, sign) == sign * threshold {
+                        stack.push(self.topple(to, sign, threshold, tally));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Start toppling a cell, or untoppling it if `sign` is negative, and count it in `tally`.
+    fn topple(&mut self, cell: [usize; 3], sign: i8, threshold: i8, tally: &mut Tally) -> Topple {
+        self.change(cell, -sign * threshold);
+        tally.count_topple(cell);
+        Topple {
+            cell,
+            sign,
+            left: threshold,
+        }
+    }
+
+    /// Add `grains` to a cell, keeping its row, or its chunk, in the backup first. Returns the new height.
+    pub(super) fn change(&mut self, cell: [usize; 3], grains: i8) -> i8 {
+        let row = cell[0] * self.size[1] + cell[1];
+        let len = self.size[2];
+        let pos = self.position(cell);
+        let height = match &mut self.storage {
+            Storage::Dense(data) => {
+                if let Some(backup) = &self.backup {
+                    backup.keep_row(row, &data[row * len..(row + 1) * len]);
+                }
+                &mut data[row * len + cell[2]]
+            }
+            Storage::Chunked(chunks) => {
+                if let Some(backup) = &mut self.backup {
+                    let (key, _) = chunk_location(pos);
+                    backup.keep_chunk(key, chunks.get(&key).map(|c| &**c));
+                }
+                chunk_cell_mut(chunks, pos)
+            }
+            Storage::Symmetric(_) => panic!("{}", SYMMETRIC_CHANGE),
+            Storage::Packed(_) => unreachable!("Packed worlds add sand in a window."),
+        };
+        *height += grains;
+        *height
+    }
+}
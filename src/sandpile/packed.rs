@@ -0,0 +1,559 @@
+//! Worlds whose heights are packed into a few bits each, to keep more of them in memory:
+//! either a copy, in a `PackedWorld`, or a world made `packed`, which sand is added to like any other.
+//!
+//! Heights are signed, and on most lattices can be higher than 5, so the number of bits is chosen
+//! to fit the heights the world actually has. A pile with no holes on `Lattice::Cubic6` needs 3,
+//! which packs 21 cells into the 8 bytes that 8 cells of a dense world take up.
+//!
+//! A packed world is never toppled in place. Sand is added to a dense window onto the cells around it instead,
+//! which has a sink for a boundary. If the avalanche reaches the edge of the window, and that isn't the edge of the world,
+//! it starts again in a window twice as wide, so the window ends up about as big as the avalanche.
+//! Once it is stable, the window is packed back into the world.
+
+use super::topple::Tally;
+use super::{merged, Boundary, Lattice, Storage, World};
+
+use std::ops::Index;
+
+/// Heights packed into a few bits each, in the same order as `World::to_color_array`.
+#[derive(Clone)]
+pub(super) struct PackedHeights {
+    /// How many bits each cell takes up. Cells never straddle two words.
+    bits: u32,
+    /// The lowest height that can be stored, which is stored as 0.
+    lowest: i8,
+    /// The first cell is in the lowest bits of the first word.
+    words: Vec<u64>,
+}
+
+/// Every height, so that `Index` can return a reference to a height that is only stored packed.
+static HEIGHTS: [i8; 256] = all_heights();
+
+const fn all_heights() -> [i8; 256] {
+    let mut out = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        out[i] = i as u8 as i8;
+        i += 1;
+    }
+    out
+}
+
+/// A reference to `height` that lives as long as the program.
+pub(super) fn height_ref(height: i8) -> &'static i8 {
+    &HEIGHTS[height as u8 as usize]
+}
+
+impl PackedHeights {
+    /// `len` empty cells.
+    pub(super) fn new(len: usize) -> Self {
+        Self::pack(len, 0, 0, std::iter::repeat(0))
+    }
+
+    /// `heights`, in as few bits as they need.
+    fn from_heights(heights: &[i8]) -> Self {
+        let lowest = heights.iter().copied().min().unwrap_or(0);
+        let highest = heights.iter().copied().max().unwrap_or(0);
+        Self::pack(heights.len(), lowest, highest, heights.iter().copied())
+    }
+
+    /// The first `len` of `heights`, in enough bits for any height from `lowest` to `highest`, and 0.
+    fn pack(len: usize, lowest: i8, highest: i8, heights: impl Iterator<Item = i8>) -> Self {
+        let (lowest, highest) = (lowest.min(0), highest.max(0));
+        let range = (highest as i16 - lowest as i16) as u32;
+        let bits = (u32::BITS - range.leading_zeros()).max(1);
+        let per_word = (64 / bits) as usize;
+
+        let mut words = Vec::with_capacity(len.div_ceil(per_word));
+        let (mut word, mut n) = (0, 0);
+        for height in heights.take(len) {
+            word |= ((height as i16 - lowest as i16) as u64) << (n * bits);
+            n += 1;
+            if n as usize == per_word {
+                words.push(word);
+                word = 0;
+                n = 0;
+            }
+        }
+        if n > 0 {
+            words.push(word);
+        }
+        Self {
+            bits,
+            lowest,
+            words,
+        }
+    }
+
+    pub(super) fn get(&self, i: usize) -> i8 {
+        let (word, shift) = self.location(i);
+        ((self.words[word] >> shift & self.mask()) as i16 + self.lowest as i16) as i8
+    }
+
+    /// Whether `height` can be stored without packing the heights again.
+    pub(super) fn fits(&self, height: i8) -> bool {
+        let stored = height as i16 - self.lowest as i16;
+        0 <= stored && stored as u64 <= self.mask()
+    }
+
+    /// Change the height of a cell, which must fit; see `repack`.
+    pub(super) fn set(&mut self, i: usize, height: i8) {
+        assert!(
+            self.fits(height),
+            "Height {} doesn't fit in {} bits; the world must be repacked first.",
+            height,
+            self.bits
+        );
+        let (word, shift) = self.location(i);
+        self.words[word] &= !(self.mask() << shift);
+        self.words[word] |= ((height as i16 - self.lowest as i16) as u64) << shift;
+    }
+
+    /// Pack the first `len` heights again, into as few bits as hold them,
+    /// and any height from `lowest` to `highest`. This goes through every cell.
+    pub(super) fn repack(&mut self, len: usize, lowest: i8, highest: i8) {
+        let (mut lo, mut hi) = (lowest, highest);
+        for i in 0..len {
+            let height = self.get(i);
+            lo = lo.min(height);
+            hi = hi.max(height);
+        }
+        *self = Self::pack(len, lo, hi, (0..len).map(|i| self.get(i)));
+    }
+
+    /// The same heights, moved by `offset` into a larger world, in the same number of bits.
+    /// The new cells are all zero.
+    pub(super) fn regrown(
+        &self,
+        old_size: [usize; 3],
+        size: [usize; 3],
+        offset: [usize; 3],
+    ) -> Self {
+        let len = size.iter().product();
+        let highest = (self.lowest as i16 + self.mask() as i16).min(i8::MAX as i16) as i8;
+        let heights = (0..len).map(|i| {
+            let idx = [i / (size[1] * size[2]), i / size[2] % size[1], i % size[2]];
+            let inside = (0..3).all(|j| (offset[j]..offset[j] + old_size[j]).contains(&idx[j]));
+            if !inside {
+                return 0;
+            }
+            let [x, y, z] = [0, 1, 2].map(|j| idx[j] - offset[j]);
+            self.get((x * old_size[1] + y) * old_size[2] + z)
+        });
+        Self::pack(len, self.lowest, highest, heights)
+    }
+
+    /// Unpack the heights of the cells from `start` on into `out`.
+    pub(super) fn unpack(&self, start: usize, out: &mut [i8]) {
+        for (i, height) in (start..).zip(out) {
+            *height = self.get(i);
+        }
+    }
+
+    pub(super) fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    pub(super) fn memory_size(&self) -> usize {
+        8 * self.words.len()
+    }
+
+    fn mask(&self) -> u64 {
+        (1 << self.bits) - 1
+    }
+
+    /// Which word the cell with this linear index is in, and how far up in that word.
+    fn location(&self, i: usize) -> (usize, u32) {
+        let per_word = (64 / self.bits) as usize;
+        (i / per_word, (i % per_word) as u32 * self.bits)
+    }
+}
+
+pub struct PackedWorld {
+    size: [usize; 3],
+    max_size: [usize; 3],
+    center: [usize; 3],
+    lattice: Lattice,
+    boundary: Boundary,
+    total_grains: isize,
+    odometer: Option<Vec<u32>>,
+    manna: Option<u64>,
+    heights: PackedHeights,
+}
+
+/// Unsigned coordinates, as in `World`. The height is unpacked into a table of every height.
+impl Index<[usize; 3]> for PackedWorld {
+    type Output = i8;
+    fn index(&self, idx: [usize; 3]) -> &i8 {
+        assert!(
+            idx.iter().zip(&self.size).all(|(&x, &n)| x < n),
+            "Cell {:?} is outside the world.",
+            idx
+        );
+        height_ref(self.heights.get(self.linear_index(idx)))
+    }
+}
+
+impl World {
+    /// A copy of the world, packed into as few bits per cell as its heights need.
+    /// The packed world is dense, whatever the storage of this one.
+    pub fn to_packed(&self) -> PackedWorld {
+        let heights = match &self.storage {
+            Storage::Packed(heights) => heights.clone(),
+            _ => PackedHeights::from_heights(&self.to_color_array()),
+        };
+        PackedWorld {
+            size: self.size,
+            max_size: self.max_size,
+            center: self.center,
+            lattice: self.lattice,
+            boundary: self.boundary,
+            total_grains: self.total_grains,
+            odometer: self.odometer().map(|odometer| odometer.into_owned()),
+            manna: self.manna,
+            heights,
+        }
+    }
+}
+
+impl PackedWorld {
+    /// Unpack into a dense world, which adds sand the same way this one's world did.
+    pub fn to_world(&self) -> World {
+        World {
+            size: self.size,
+            max_size: self.max_size,
+            center: self.center,
+            storage: Storage::Dense(self.to_color_array()),
+            lattice: self.lattice,
+            boundary: self.boundary,
+            total_grains: self.total_grains,
+            odometer: self.odometer.clone(),
+            manna: self.manna,
+            backup: None,
+            pending: None,
+        }
+    }
+
+    pub fn size(&self) -> [usize; 3] {
+        self.size
+    }
+
+    pub fn center(&self) -> [usize; 3] {
+        self.center
+    }
+
+    pub fn lattice(&self) -> Lattice {
+        self.lattice
+    }
+
+    pub fn total_grains(&self) -> isize {
+        self.total_grains
+    }
+
+    /// How many bits each cell takes up.
+    pub fn bits(&self) -> u32 {
+        self.heights.bits()
+    }
+
+    /// Roughly how many bytes the world's cells take up, as in `World::memory_size`.
+    pub fn memory_size(&self) -> usize {
+        let odometer = self
+            .odometer
+            .as_ref()
+            .map_or(0, |odometer| 4 * odometer.len());
+        self.heights.memory_size() + odometer
+    }
+
+    /// The height of the cell at `pos`, relative to the center, which must lie within the world's bounds.
+    pub fn get(&self, pos: [isize; 3]) -> i8 {
+        self.heights.get(self.linear_index(self.grid_index(pos)))
+    }
+
+    /// Whether `height` fits in the bits each cell takes up now, so that `set` can store it.
+    pub fn fits(&self, height: i8) -> bool {
+        self.heights.fits(height)
+    }
+
+    /// Change the height of the cell at `pos`, relative to the center.
+    /// The height must fit in the bits each cell takes up now; see `repack`.
+    /// This doesn't topple anything, or change `total_grains`.
+    pub fn set(&mut self, pos: [isize; 3], height: i8) {
+        let i = self.linear_index(self.grid_index(pos));
+        self.heights.set(i, height);
+    }
+
+    /// Pack the heights again, into as few bits as hold every height the world has,
+    /// and any height from `lowest` to `highest`, so that `set` can store those.
+    /// Unlike `set`, this takes time in proportion to the size of the world.
+    pub fn repack(&mut self, lowest: i8, highest: i8) {
+        let len = self.size.iter().product();
+        self.heights.repack(len, lowest, highest);
+    }
+
+    /// Unpack the heights of the cells whose first coordinate is `x` into `out`,
+    /// in the same order as `World::to_color_array`, so that a big world can be uploaded a slab at a time.
+    pub fn unpack_slab(&self, x: usize, out: &mut [i8]) {
+        let slab = self.size[1] * self.size[2];
+        assert!(x < self.size[0], "Slab {} is outside the world.", x);
+        assert_eq!(out.len(), slab);
+        self.heights.unpack(x * slab, out);
+    }
+
+    /// The heights of all cells, as in `World::to_color_array`.
+    pub fn to_color_array(&self) -> Vec<i8> {
+        let slab = self.size[1] * self.size[2];
+        let mut data = vec![0; self.size[0] * slab];
+        for (x, out) in data.chunks_mut(slab).enumerate() {
+            self.unpack_slab(x, out);
+        }
+        data
+    }
+
+    fn linear_index(&self, idx: [usize; 3]) -> usize {
+        (idx[0] * self.size[1] + idx[1]) * self.size[2] + idx[2]
+    }
+
+    fn grid_index(&self, pos: [isize; 3]) -> [usize; 3] {
+        let idx = [0, 1, 2].map(|i| pos[i] + self.center[i] as isize);
+        assert!(
+            idx.iter()
+                .zip(&self.size)
+                .all(|(&x, &n)| 0 <= x && x < n as isize),
+            "Position {:?} is outside the world.",
+            pos
+        );
+        idx.map(|x| x as usize)
+    }
+}
+
+/// Sand partway through being added to a packed world, in a dense window onto the cells around it.
+pub(super) struct Window {
+    drops: Vec<([isize; 3], isize)>,
+    /// The corners of the window, including its boundary layer, relative to the center of the packed world.
+    lo: [isize; 3],
+    hi: [isize; 3],
+    /// The window, once it has been unpacked, with the sand being added to it.
+    world: Option<Box<World>>,
+}
+
+impl Window {
+    /// A window just big enough for the avalanche to start in.
+    pub(super) fn new(drops: &[([isize; 3], isize)]) -> Self {
+        let drops = merged(drops);
+        let corner = |f: fn(isize, isize) -> isize| {
+            let cells = drops.iter().map(|&(pos, _)| pos);
+            cells.reduce(|a, b| [0, 1, 2].map(|i| f(a[i], b[i])))
+        };
+        let (lo, hi) = (
+            corner(isize::min).unwrap_or_default(),
+            corner(isize::max).unwrap_or_default(),
+        );
+        Self {
+            drops,
+            lo: lo.map(|x| x - 2),
+            hi: hi.map(|x| x + 2),
+            world: None,
+        }
+    }
+
+    /// Where the center of the window is, relative to the center of the packed world.
+    /// The window's center is the middle cell, as in a new world.
+    fn shift(&self) -> [isize; 3] {
+        [0, 1, 2].map(|i| self.lo[i] + (self.hi[i] - self.lo[i] + 1) / 2)
+    }
+
+    /// How many grains, and holes, have been dropped into the avalanche so far, and how many cells are unstable.
+    pub(super) fn progress(&self) -> (usize, usize) {
+        self.world
+            .as_ref()
+            .and_then(|world| world.progress())
+            .map_or((0, 0), |progress| {
+                (progress.grains_processed, progress.unstable)
+            })
+    }
+}
+
+impl World {
+    /// Carry on adding sand to a packed world, as in `keep_adding`.
+    ///
+    /// Returns `None` if there is still more to do, or else the finished avalanche.
+    /// On failure, returns the boundary cell the avalanche reached.
+    pub(super) fn keep_windowing(
+        &mut self,
+        window: &mut Window,
+        out_of_time: &mut dyn FnMut() -> bool,
+    ) -> Option<Result<Tally, [usize; 3]>> {
+        if window.world.is_none() {
+            if let Err(cell) = self.fit(&window.drops) {
+                return Some(Err(cell));
+            }
+        }
+        loop {
+            if window.world.is_none() {
+                window.world = Some(Box::new(self.open_window(window)));
+            }
+            let stats = window
+                .world
+                .as_mut()
+                .unwrap()
+                .keep_adding(&mut *out_of_time)?
+                .expect("A window has a sink, so it never overflows.");
+
+            // The cells next to the edge of the window that toppled, relative to the center of this world.
+            let shift = window.shift();
+            let (lo, hi) = match stats.bounds {
+                Some((lo, hi)) => (
+                    [0, 1, 2].map(|i| lo[i] + shift[i]),
+                    [0, 1, 2].map(|i| hi[i] + shift[i]),
+                ),
+                None => (window.hi, window.lo),
+            };
+            let first = self.position([0; 3]);
+            let last = self.position(self.size.map(|n| n - 1));
+            let mut reached = [[false; 2]; 3];
+            let mut overflow = [false; 3];
+            for i in 0..3 {
+                reached[i] = [lo[i] <= window.lo[i] + 1, hi[i] >= window.hi[i] - 1];
+                let walled = self.boundary == Boundary::Wall;
+                overflow[i] = walled
+                    && (reached[i][0] && window.lo[i] == first[i]
+                        || reached[i][1] && window.hi[i] == last[i]);
+            }
+            if overflow != [false; 3] {
+                let mut edge = self.grid_index(lo);
+                for i in (0..3).filter(|&i| overflow[i]) {
+                    edge[i] = if reached[i][0] && window.lo[i] == first[i] {
+                        0
+                    } else {
+                        self.size[i] - 1
+                    };
+                }
+                if self.grow(overflow).is_none() {
+                    return Some(Err(edge));
+                }
+            }
+
+            // Grains fell out of the window, but not out of the world, so it starts again in a wider one.
+            let (first, last) = (
+                self.position([0; 3]),
+                self.position(self.size.map(|n| n - 1)),
+            );
+            let mut widened = false;
+            for i in 0..3 {
+                let width = window.hi[i] - window.lo[i];
+                if reached[i][0] && window.lo[i] > first[i] {
+                    window.lo[i] = (window.lo[i] - width).max(first[i]);
+                    widened = true;
+                }
+                if reached[i][1] && window.hi[i] < last[i] {
+                    window.hi[i] = (window.hi[i] + width).min(last[i]);
+                    widened = true;
+                }
+            }
+            if !widened {
+                let world = window.world.take().unwrap();
+                return Some(Ok(self.close_window(window, *world, stats.lost)));
+            }
+            window.world = None;
+        }
+    }
+
+    /// Unpack the cells in a window onto this packed world into a dense world with a sink,
+    /// which adds sand the same way, and start adding the window's drops to it.
+    /// The window is first cut down to fit in this world.
+    fn open_window(&self, window: &mut Window) -> World {
+        let (first, last) = (
+            self.position([0; 3]),
+            self.position(self.size.map(|n| n - 1)),
+        );
+        for i in 0..3 {
+            window.lo[i] = window.lo[i].max(first[i]);
+            window.hi[i] = window.hi[i].min(last[i]);
+        }
+        let lo = self.grid_index(window.lo);
+        let size = [0, 1, 2].map(|i| (window.hi[i] - window.lo[i]) as usize + 1);
+        let heights = match &self.storage {
+            Storage::Packed(heights) => heights,
+            _ => unreachable!("Only packed worlds add sand in a window."),
+        };
+
+        // Grains that reach the boundary layer of the window are counted as lost, so it is left empty.
+        let len = size.iter().product();
+        let mut data = vec![0; len];
+        for x in 1..size[0] - 1 {
+            for y in 1..size[1] - 1 {
+                let row = (x * size[1] + y) * size[2];
+                let start = self.linear_index([lo[0] + x, lo[1] + y, lo[2] + 1]);
+                heights.unpack(start, &mut data[row + 1..row + size[2] - 1]);
+            }
+        }
+
+        let mut world = World {
+            size,
+            max_size: size,
+            center: size.map(|n| n / 2),
+            storage: Storage::Dense(data),
+            boundary: Boundary::Sink,
+            total_grains: 0,
+            odometer: Some(vec![0; len]),
+            backup: None,
+            pending: None,
+            ..*self
+        };
+        let shift = window.shift();
+        let drops: Vec<_> = window
+            .drops
+            .iter()
+            .map(|&(pos, n)| ([0, 1, 2].map(|i| pos[i] - shift[i]), n))
+            .collect();
+        world.start_adding(&drops);
+        world
+    }
+
+    /// Pack the cells inside a window that has finished adding sand back into this world,
+    /// packing the whole world again first if any of their heights don't fit.
+    /// Returns the window's avalanche, in which `lost` grains fell into the sink.
+    fn close_window(&mut self, window: &Window, world: World, lost: isize) -> Tally {
+        let (lo, size) = (self.grid_index(window.lo), world.size);
+        let (data, odometer) = (world.dense(), world.odometer.as_ref().unwrap());
+        let seeds = window
+            .drops
+            .iter()
+            .map(|&(pos, _)| self.grid_index(pos))
+            .collect();
+        let mut tally = Tally::new(self.size, seeds, self.odometer.is_some());
+        tally.lost = lost;
+
+        let interior = |x: usize, y: usize| {
+            let row = (x * size[1] + y) * size[2];
+            row + 1..row + size[2] - 1
+        };
+        let rows = || (1..size[0] - 1).flat_map(|x| (1..size[1] - 1).map(move |y| (x, y)));
+        let mut range = (0, 0);
+        for (x, y) in rows() {
+            for &height in &data[interior(x, y)] {
+                range = (range.0.min(height), range.1.max(height));
+            }
+        }
+
+        let len = self.size.iter().product();
+        let [_, ny, nz] = self.size;
+        let heights = match &mut self.storage {
+            Storage::Packed(heights) => heights,
+            _ => unreachable!("Only packed worlds add sand in a window."),
+        };
+        if !heights.fits(range.0) || !heights.fits(range.1) {
+            heights.repack(len, range.0, range.1);
+        }
+        for (x, y) in rows() {
+            let cell = [lo[0] + x, lo[1] + y, lo[2] + 1];
+            let start = (cell[0] * ny + cell[1]) * nz + cell[2];
+            for (i, &height) in (start..).zip(&data[interior(x, y)]) {
+                heights.set(i, height);
+            }
+            tally.count_row(cell, &odometer[interior(x, y)]);
+        }
+        self.manna = world.manna;
+        tally
+    }
+}
@@ -0,0 +1,87 @@
+//! What avalanches do, for measuring how their sizes are distributed.
+
+/// What happened when sand was added.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AvalancheStats {
+    /// How many times cells toppled, in total.
+    pub topples: u64,
+    /// How many different cells toppled.
+    pub sites: usize,
+    /// How many times the cell where the grains were dropped toppled.
+    /// Each time sets off a new wave of topplings. With several drops, this adds up all of their cells.
+    pub waves: u64,
+    /// Two corners of a box containing every cell that toppled, relative to the center, if any did.
+    pub bounds: Option<([isize; 3], [isize; 3])>,
+    /// How many grains fell into a sink, less any holes that did.
+    pub lost: isize,
+}
+
+impl AvalancheStats {
+    /// Half the longest side of the box containing every cell that toppled, rounded down.
+    pub fn radius(&self) -> u64 {
+        match self.bounds {
+            Some((lo, hi)) => (0..3)
+                .map(|i| (hi[i] - lo[i] + 1) as u64 / 2)
+                .max()
+                .unwrap(),
+            None => 0,
+        }
+    }
+}
+
+/// How many times each value was seen, in bins that double in width, which suit power laws.
+/// The first bin holds only zero, and bin `k` holds values from `2^(k-1)` up to `2^k`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Histogram {
+    counts: Vec<u64>,
+}
+
+impl Histogram {
+    pub fn add(&mut self, value: u64) {
+        let bin = (64 - value.leading_zeros()) as usize;
+        if self.counts.len() <= bin {
+            self.counts.resize(bin + 1, 0);
+        }
+        self.counts[bin] += 1;
+    }
+
+    /// Each bin's lowest value, the lowest value of the next bin, and how many values it holds.
+    pub fn bins(&self) -> impl Iterator<Item = (u64, u64, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(bin, &count)| match bin {
+                0 => (0, 1, count),
+                _ => (1 << (bin - 1), 1 << bin, count),
+            })
+    }
+
+    /// How many values have been added.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+/// Histograms of the stats of many avalanches.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AvalancheHistograms {
+    pub topples: Histogram,
+    pub sites: Histogram,
+    pub waves: Histogram,
+    pub radius: Histogram,
+    pub lost: Histogram,
+}
+
+impl AvalancheHistograms {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, stats: &AvalancheStats) {
+        self.topples.add(stats.topples);
+        self.sites.add(stats.sites as u64);
+        self.waves.add(stats.waves);
+        self.radius.add(stats.radius());
+        self.lost.add(stats.lost.unsigned_abs() as u64);
+    }
+}
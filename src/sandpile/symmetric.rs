@@ -0,0 +1,377 @@
+//! Worlds where sand is only ever added at the center, and so keep all 48 symmetries of a cube:
+//! the pile looks the same after swapping or negating coordinates relative to the center.
+//!
+//! Only the cells with `x >= y >= z >= 0` relative to the center are stored and toppled,
+//! which is about one cell in 48. Every other cell is a reflection of one of these.
+//!
+//! Toppling sweeps over the stored cells, like the rows of a dense world.
+//! Near the planes of symmetry, some of a cell's neighbors are reflections of the same stored cell,
+//! so that cell gets grains from each of them.
+
+use super::topple::{Bounds, Tally};
+use super::{AvalancheStats, Boundary, Lattice, Storage, World};
+
+use std::collections::HashMap;
+
+/// The cell with `x >= y >= z >= 0` that `pos`, relative to the center, is a reflection of.
+pub fn canonical(pos: [isize; 3]) -> [usize; 3] {
+    let mut cell = [
+        pos[0].unsigned_abs(),
+        pos[1].unsigned_abs(),
+        pos[2].unsigned_abs(),
+    ];
+    cell.sort_unstable_by(|a, b| b.cmp(a));
+    cell
+}
+
+/// Where a cell with `x >= y >= z >= 0` is stored.
+/// Cells are in order of `x`, so the ones within any distance of the center come first.
+pub fn wedge_index([x, y, z]: [usize; 3]) -> usize {
+    x * (x + 1) * (x + 2) / 6 + y * (y + 1) / 2 + z
+}
+
+/// How many cells with `x` at most `limit` are stored.
+fn wedge_len(limit: usize) -> usize {
+    wedge_index([limit + 1, 0, 0])
+}
+
+/// How many cells are reflections of this one, including itself.
+fn orbit_size([x, y, z]: [usize; 3]) -> u64 {
+    let arrangements = if x == z {
+        1
+    } else if x == y || y == z {
+        3
+    } else {
+        6
+    };
+    let nonzero = [x, y, z].iter().filter(|&&c| c != 0).count();
+    arrangements << nonzero
+}
+
+/// Where grains go when a stored cell near the planes of symmetry topples, and how many go there.
+/// A neighbor gets grains from each of its own neighbors that is a reflection of the cell.
+/// Any of them may be in the boundary layer.
+fn transfers_from(neighbors: &[[isize; 3]], cell: [usize; 3]) -> Vec<(usize, u32)> {
+    let step =
+        |from: [usize; 3], d: &[isize; 3]| canonical([0, 1, 2].map(|i| from[i] as isize + d[i]));
+    let mut targets = Vec::new();
+    for d in neighbors {
+        let target = step(cell, d);
+        if !targets.contains(&target) {
+            targets.push(target);
+        }
+    }
+    targets
+        .into_iter()
+        .map(|target| {
+            let weight = neighbors.iter().filter(|d| step(target, d) == cell).count();
+            (wedge_index(target), weight as u32)
+        })
+        .collect()
+}
+
+/// The cell at `pos`, relative to the center. Cells that aren't stored are empty.
+pub fn cell<T: Copy + Default>(wedge: &[T], pos: [isize; 3]) -> T {
+    wedge
+        .get(wedge_index(canonical(pos)))
+        .copied()
+        .unwrap_or_default()
+}
+
+/// Every cell of a world, stored like the heights of a dense world.
+pub fn expand<T: Copy + Default>(wedge: &[T], size: [usize; 3], center: [usize; 3]) -> Vec<T> {
+    let mut out = Vec::with_capacity(size[0] * size[1] * size[2]);
+    for x in 0..size[0] {
+        for y in 0..size[1] {
+            for z in 0..size[2] {
+                let pos = [x, y, z];
+                out.push(cell(
+                    wedge,
+                    [0, 1, 2].map(|i| pos[i] as isize - center[i] as isize),
+                ));
+            }
+        }
+    }
+    out
+}
+
+/// Grains partway through being added at the center of a symmetric world, toppled a sweep at a time.
+/// The world itself only changes once they are stable, apart from growing.
+pub(super) struct SymmetricToppling {
+    /// The stored cells, as far out as `limit`, with room for more than a byte's worth of grains.
+    heights: Vec<i32>,
+    odometer: Vec<u32>,
+    /// Where grains go from each stored cell near the planes of symmetry, worked out when it first topples.
+    transfers: HashMap<usize, Vec<(usize, u32)>>,
+    /// The furthest a stored cell can be from the center, before it is in the boundary layer.
+    limit: usize,
+    num_grains: isize,
+    /// Grains still to be added at the center, once the ones already added have stopped toppling.
+    /// They are added a quarter of a billion or so at a time, so that no height overflows.
+    remaining: isize,
+    /// How far out the next sweep needs to go: one cell further than anything has toppled since grains were added.
+    reach: usize,
+    /// Whether the last sweep toppled nothing, or no grains have been added yet.
+    stable: bool,
+}
+
+impl SymmetricToppling {
+    pub fn is_done(&self) -> bool {
+        self.stable && self.remaining == 0
+    }
+
+    /// How many grains have been added at the center, or taken away, so far.
+    pub fn grains_added(&self) -> usize {
+        (self.num_grains - self.remaining).unsigned_abs()
+    }
+
+    /// How many cells are waiting to topple, or untopple, counting every reflection.
+    pub fn num_unstable(&self, lattice: Lattice) -> usize {
+        let threshold = lattice.threshold() as i32;
+        let mut num_unstable = 0;
+        let mut i = 0;
+        for x in 0..=self.limit {
+            for y in 0..=x {
+                for z in 0..=y {
+                    if self.heights[i].abs() >= threshold {
+                        num_unstable += orbit_size([x, y, z]) as usize;
+                    }
+                    i += 1;
+                }
+            }
+        }
+        num_unstable
+    }
+}
+
+impl World {
+    /// Get ready to add grains at the center of a symmetric world, or take them away; see `sweep_symmetric`.
+    pub(super) fn start_symmetric(&self, num_grains: isize) -> SymmetricToppling {
+        let wedge = match &self.storage {
+            Storage::Symmetric(wedge) => wedge,
+            _ => unreachable!("Only symmetric worlds are toppled this way."),
+        };
+        let limit = self.symmetric_limit();
+        let mut heights: Vec<i32> = wedge.iter().map(|&h| h as i32).collect();
+        heights.resize(wedge_len(limit), 0);
+        SymmetricToppling {
+            odometer: vec![0; heights.len()],
+            heights,
+            transfers: HashMap::new(),
+            limit,
+            num_grains,
+            remaining: num_grains,
+            reach: 0,
+            stable: true,
+        }
+    }
+
+    /// Sweep outwards over the stored cells once, adding the next grains first if the last sweep toppled nothing.
+    /// The world grows if the avalanche reaches the boundary layer.
+    /// On failure, returns the boundary cell the avalanche reached, and the world must be put back the way it was.
+    pub(super) fn sweep_symmetric(
+        &mut self,
+        toppling: &mut SymmetricToppling,
+    ) -> Result<(), [usize; 3]> {
+        let threshold = self.lattice.threshold() as i32;
+        let neighbors = self.lattice.neighbors();
+        let SymmetricToppling {
+            heights,
+            odometer,
+            transfers,
+            limit,
+            remaining,
+            reach,
+            stable,
+            ..
+        } = toppling;
+
+        if *stable {
+            let n = (*remaining).clamp(-1 << 28, 1 << 28);
+            *remaining -= n;
+            heights[0] += n as i32;
+            *reach = 0;
+        }
+
+        let mut toppled = false;
+        let mut x = 0;
+        while x <= (*reach).min(*limit) {
+            for y in 0..=x {
+                let row = wedge_index([x, y, 0]);
+                for z in 0..=y {
+                    let i = row + z;
+                    if heights[i].abs() < threshold {
+                        continue;
+                    }
+                    let cell = [x, y, z];
+                    let k = heights[i] / threshold;
+                    heights[i] -= k * threshold;
+                    odometer[i] += k.unsigned_abs();
+                    toppled = true;
+                    *reach = (*reach).max(x + 1);
+
+                    while x == *limit && self.boundary == Boundary::Wall {
+                        let edge = [0, 1, 2].map(|i| self.center[i] + [*limit + 1, y, z][i]);
+                        if self.grow([true; 3]).is_none() {
+                            return Err(edge);
+                        }
+                        *limit = self.symmetric_limit();
+                        heights.resize(wedge_len(*limit), 0);
+                        odometer.resize(heights.len(), 0);
+                    }
+
+                    // Far from the planes of symmetry, every neighbor is stored, and is a neighbor only once.
+                    if z >= 2 && y >= z + 3 && x >= y + 3 {
+                        for d in &neighbors {
+                            let target = [0, 1, 2].map(|i| (cell[i] as isize + d[i]) as usize);
+                            // Cells past the limit are in the boundary layer, and not stored.
+                            if let Some(h) = heights.get_mut(wedge_index(target)) {
+                                *h += k;
+                            }
+                        }
+                        continue;
+                    }
+                    let transfers = transfers
+                        .entry(i)
+                        .or_insert_with(|| transfers_from(&neighbors, cell));
+                    for &(j, weight) in transfers.iter() {
+                        if let Some(h) = heights.get_mut(j) {
+                            *h += k * weight as i32;
+                        }
+                    }
+                }
+            }
+            x += 1;
+        }
+        *stable = !toppled;
+        Ok(())
+    }
+
+    /// Store the heights of a symmetric world once `toppling` is done, and count its avalanche.
+    pub(super) fn finish_symmetric(&mut self, toppling: &SymmetricToppling) -> AvalancheStats {
+        let SymmetricToppling {
+            heights,
+            odometer,
+            limit,
+            num_grains,
+            ..
+        } = toppling;
+        let mut stats = AvalancheStats::default();
+        let mut total_grains = 0;
+        let mut radius = None;
+        let mut i = 0;
+        for x in 0..=*limit {
+            for y in 0..=x {
+                for z in 0..=y {
+                    let orbit = orbit_size([x, y, z]);
+                    total_grains += heights[i] as i64 * orbit as i64;
+                    if odometer[i] > 0 {
+                        stats.topples += odometer[i] as u64 * orbit;
+                        stats.sites += orbit as usize;
+                        radius = Some(x as isize);
+                    }
+                    i += 1;
+                }
+            }
+        }
+        stats.waves = odometer[0] as u64;
+        stats.bounds = radius.map(|r| ([-r; 3], [r; 3]));
+        stats.lost = self.total_grains + num_grains - total_grains as isize;
+
+        self.storage = Storage::Symmetric(heights.iter().map(|&h| h as i8).collect());
+        if let Some(counts) = &mut self.odometer {
+            counts.resize(odometer.len(), 0);
+            for (n, t) in counts.iter_mut().zip(odometer) {
+                *n += t;
+            }
+        }
+        stats
+    }
+
+    /// Add grains at the center of a symmetric world, or take them away, and topple until stable.
+    /// On failure, returns the boundary cell the avalanche reached, and leaves the world as it was.
+    pub(super) fn drop_symmetric(
+        &mut self,
+        num_grains: isize,
+    ) -> Result<AvalancheStats, [usize; 3]> {
+        let (old_size, old_center) = (self.size, self.center);
+        let mut toppling = self.start_symmetric(num_grains);
+        while !toppling.is_done() {
+            if let Err(cell) = self.sweep_symmetric(&mut toppling) {
+                self.size = old_size;
+                self.center = old_center;
+                return Err(cell);
+            }
+        }
+        Ok(self.finish_symmetric(&toppling))
+    }
+
+    /// Whether sand dropped as in `drops`, in an empty world shaped like this one, makes a pile with all the symmetries
+    /// of a cube, so that `symmetric_pile` can stabilize it.
+    pub(super) fn keeps_symmetry(&self, drops: &[([isize; 3], isize)]) -> bool {
+        let same = |v: [usize; 3]| v.iter().all(|&n| n == v[0]);
+        matches!(drops, [([0, 0, 0], _)])
+            && same(self.size)
+            && same(self.max_size)
+            && same(self.center)
+            && (self.boundary == Boundary::Wall || 2 * self.center[0] + 1 == self.size[0])
+    }
+
+    /// Stabilize `num_grains` at the center of an empty world shaped like this one, on their own,
+    /// storing only about one cell in 48; see `keeps_symmetry`.
+    /// Returns the pile, its avalanche, and a box containing every cell that changed.
+    /// On failure, returns the boundary cell the avalanche reached.
+    pub(super) fn symmetric_pile(
+        &self,
+        num_grains: isize,
+    ) -> Result<(World, Tally, Bounds), [usize; 3]> {
+        let mut pile = World {
+            storage: Storage::Symmetric(Vec::new()),
+            total_grains: 0,
+            odometer: Some(Vec::new()),
+            manna: None,
+            backup: None,
+            pending: None,
+            ..*self
+        };
+        let stats = pile.drop_symmetric(num_grains)?;
+        let odometer = pile.odometer.take().unwrap();
+
+        // Cells change up to one cell further out than anything toppled.
+        let r = stats.bounds.map_or(0, |(_, hi)| hi[0] as usize + 1);
+        let changed = Bounds {
+            lo: pile.center.map(|c| c - r),
+            hi: pile.center.map(|c| c + r),
+        };
+        let mut tally = Tally::new(pile.size, vec![pile.center], self.odometer.is_some());
+        tally.lost = stats.lost;
+        let mut topples = vec![0; 2 * r + 1];
+        for x in changed.lo[0]..=changed.hi[0] {
+            for y in changed.lo[1]..=changed.hi[1] {
+                for (z, n) in (changed.lo[2]..).zip(&mut topples) {
+                    *n = cell(&odometer, pile.position([x, y, z]));
+                }
+                tally.count_row([x, y, changed.lo[2]], &topples);
+            }
+        }
+        Ok((pile, tally, changed))
+    }
+
+    fn symmetric_limit(&self) -> usize {
+        let (n, c) = (self.size[0], self.center[0]);
+        (c - 1).min(n - 2 - c)
+    }
+}
+
+/// How many times each stored cell topples when `num_grains` are added at the center of an empty walled world,
+/// that can grow as much as it needs to, and how far from the center the furthest of them is.
+pub(super) fn pile_odometer(lattice: Lattice, num_grains: isize) -> (usize, Vec<u32>) {
+    let mut pile = World::symmetric([3; 3], [1 << 20; 3])
+        .with_lattice(lattice)
+        .with_odometer();
+    let stats = pile
+        .drop_symmetric(num_grains)
+        .expect("The pile has room to grow.");
+    let radius = stats.bounds.map_or(0, |(_, hi)| hi[0] as usize);
+    (radius, pile.odometer.unwrap())
+}
@@ -0,0 +1,799 @@
+//! Toppling on a dense grid of heights, stored as in `World`: the last coordinate varies fastest.
+//!
+//! Cells are toppled a row at a time, sweeping through the rows in order.
+//! This is much faster than toppling one cell at a time, because a whole row can be updated at once,
+//! and grains can travel all the way across the world in a single sweep.
+//!
+//! A cell with a hole at least as deep as its number of neighbors untopples instead,
+//! taking a grain from each neighbor. Each cell topples or untopples at most once a sweep,
+//! so that heights stay within three times the threshold, as long as they start within twice it.
+//!
+//! Toppling a plane of cells along the first axis only changes that plane and the planes next to it,
+//! so every third plane can be toppled at the same time. With the `parallel` feature, they are.
+//! By the abelian property, the result is the same either way.
+
+use super::Boundary;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// A box of cells, including both corners.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bounds {
+    pub lo: [usize; 3],
+    pub hi: [usize; 3],
+}
+
+impl Bounds {
+    pub fn cell(loc: [usize; 3]) -> Self {
+        Self { lo: loc, hi: loc }
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        let mut out = self;
+        for i in 0..3 {
+            out.lo[i] = out.lo[i].min(other.lo[i]);
+            out.hi[i] = out.hi[i].max(other.hi[i]);
+        }
+        out
+    }
+
+    pub fn shifted(self, offset: [usize; 3]) -> Self {
+        Self {
+            lo: super::shifted(self.lo, offset),
+            hi: super::shifted(self.hi, offset),
+        }
+    }
+}
+
+const EMPTY: [usize; 2] = [usize::MAX, 0];
+
+/// Rows are toppled in aligned chunks of this many cells.
+const CHUNK: usize = 16;
+
+/// Where a toppling cell sends its grains.
+#[derive(Clone, Debug)]
+pub struct Stencil {
+    /// How many grains a cell needs to topple, which is its number of neighbors.
+    pub(super) threshold: i8,
+    /// The rows with neighbors of a cell, by their offsets along the first two axes.
+    /// For each, how many of those neighbors come before the cell along the last axis, are level with it, and come after.
+    pub(super) rows: Vec<([isize; 2], [i8; 3])>,
+}
+
+impl Stencil {
+    /// Every neighbor must be within one cell along each axis.
+    pub fn new(neighbors: &[[isize; 3]]) -> Self {
+        let mut rows: Vec<([isize; 2], [i8; 3])> = vec![([0, 0], [0; 3])];
+        for &[dx, dy, dz] in neighbors {
+            assert!([dx, dy, dz].iter().all(|d| d.abs() <= 1) && [dx, dy, dz] != [0; 3]);
+            let i = match rows.iter().position(|&(offset, _)| offset == [dx, dy]) {
+                Some(i) => i,
+                None => {
+                    rows.push(([dx, dy], [0; 3]));
+                    rows.len() - 1
+                }
+            };
+            rows[i].1[(dz + 1) as usize] += 1;
+        }
+        Self {
+            threshold: neighbors.len() as i8,
+            rows,
+        }
+    }
+}
+
+/// What an avalanche has done so far.
+#[derive(Clone, Debug)]
+pub struct Tally {
+    size: [usize; 3],
+    /// Whether to count how many times each cell topples, rather than only which cells have.
+    counting: bool,
+    /// What has toppled in each plane along the first axis, by `x`, once anything has.
+    planes: Vec<PlaneTally>,
+    /// Contains every cell that has toppled, if any has.
+    pub toppled_bounds: Option<Bounds>,
+    pub topples: u64,
+    /// The cells where grains were dropped, in order.
+    pub seeds: Vec<[usize; 3]>,
+    /// How many times the seeds have toppled.
+    pub waves: u64,
+    /// How many grains have fallen into a sink, less any holes that have.
+    pub lost: isize,
+}
+
+/// What has toppled in a plane along the first axis.
+/// Nothing is allocated until one of its cells topples, so small avalanches stay cheap in big worlds.
+#[derive(Clone, Debug, Default)]
+struct PlaneTally {
+    /// A bit for each cell that has toppled, stored like the heights.
+    toppled: Vec<u64>,
+    /// How many times each cell has toppled, if the tally is counting.
+    counts: Vec<u32>,
+}
+
+impl PlaneTally {
+    /// Count the topples of the cells from `start` on, where each cell toppled `topples` times, or untoppled.
+    fn add<T: Copy + Into<i64>>(
+        &mut self,
+        plane_len: usize,
+        counting: bool,
+        start: usize,
+        topples: &[T],
+    ) {
+        if self.toppled.is_empty() {
+            self.toppled = vec![0; plane_len.div_ceil(64)];
+            if counting {
+                self.counts = vec![0; plane_len];
+            }
+        }
+        for (i, chunk) in (start..).step_by(64).zip(topples.chunks(64)) {
+            let bits = chunk
+                .iter()
+                .rev()
+                .fold(0, |bits, &t| bits << 1 | (t.into() != 0) as u64);
+            let (word, shift) = (i / 64, i % 64);
+            self.toppled[word] |= bits << shift;
+            if shift > 0 && bits >> (64 - shift) != 0 {
+                self.toppled[word + 1] |= bits >> (64 - shift);
+            }
+        }
+        if counting {
+            for (n, &t) in self.counts[start..].iter_mut().zip(topples) {
+                *n += t.into().unsigned_abs() as u32;
+            }
+        }
+    }
+}
+
+impl Tally {
+    /// If `counting`, the tally keeps how many times each cell topples, for the world's odometer.
+    pub fn new(size: [usize; 3], mut seeds: Vec<[usize; 3]>, counting: bool) -> Self {
+        seeds.sort_unstable();
+        Self {
+            size,
+            counting,
+            planes: Vec::new(),
+            toppled_bounds: None,
+            topples: 0,
+            seeds,
+            waves: 0,
+            lost: 0,
+        }
+    }
+
+    /// Count everything twice, as if the avalanche so far had happened twice over.
+    /// The same cells have toppled.
+    pub fn double(&mut self) {
+        self.topples *= 2;
+        self.waves *= 2;
+        self.lost *= 2;
+        for plane in &mut self.planes {
+            for n in &mut plane.counts {
+                *n *= 2;
+            }
+        }
+    }
+
+    /// Count one topple of `cell`, or untopple.
+    pub fn count_topple(&mut self, cell: [usize; 3]) {
+        let plane_len = self.size[1] * self.size[2];
+        let start = cell[1] * self.size[2] + cell[2];
+        self.allocate();
+        self.planes[cell[0]].add(plane_len, self.counting, start, &[1]);
+        self.topples += 1;
+        let bounds = Bounds::cell(cell);
+        self.toppled_bounds = Some(self.toppled_bounds.map_or(bounds, |b| b.union(bounds)));
+        if self.seeds.binary_search(&cell).is_ok() {
+            self.waves += 1;
+        }
+    }
+
+    /// Count the topples of a row of cells along the last axis, starting at `first`,
+    /// where each cell toppled `topples` times, or untoppled.
+    pub fn count_row<T: Copy + Into<i64>>(&mut self, first: [usize; 3], topples: &[T]) {
+        let (first_z, last_z) = match topples.iter().position(|&t| t.into() != 0) {
+            Some(i) => (i, topples.iter().rposition(|&t| t.into() != 0).unwrap()),
+            None => return,
+        };
+        let [x, y, z] = first;
+        let plane_len = self.size[1] * self.size[2];
+        self.allocate();
+        self.planes[x].add(plane_len, self.counting, y * self.size[2] + z, topples);
+        self.topples += topples
+            .iter()
+            .map(|&t| t.into().unsigned_abs())
+            .sum::<u64>();
+        let bounds = Bounds {
+            lo: [x, y, z + first_z],
+            hi: [x, y, z + last_z],
+        };
+        self.toppled_bounds = Some(self.toppled_bounds.map_or(bounds, |b| b.union(bounds)));
+        let from = self.seeds.partition_point(|&seed| seed < bounds.lo);
+        for &[_, _, seed_z] in self.seeds[from..]
+            .iter()
+            .take_while(|&&seed| seed <= bounds.hi)
+        {
+            self.waves += topples[seed_z - z].into().unsigned_abs();
+        }
+    }
+
+    /// Allocate the tally of each plane, which waits until something might topple.
+    fn allocate(&mut self) {
+        if self.planes.is_empty() {
+            self.planes = vec![PlaneTally::default(); self.size[0]];
+        }
+    }
+
+    /// How many different cells have toppled.
+    pub fn sites(&self) -> usize {
+        self.planes
+            .iter()
+            .flat_map(|plane| &plane.toppled)
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    /// Add how many times each cell has toppled to `odometer`, which is stored like the heights.
+    /// Does nothing unless the tally is counting.
+    pub fn add_to(&self, odometer: &mut [u32]) {
+        let plane_len = self.size[1] * self.size[2];
+        for (plane, out) in self.planes.iter().zip(odometer.chunks_exact_mut(plane_len)) {
+            for (n, &t) in out.iter_mut().zip(&plane.counts) {
+                *n += t;
+            }
+        }
+    }
+
+    /// Move every cell, after the world grows to `size`.
+    pub fn resize(&mut self, size: [usize; 3], offset: [usize; 3]) {
+        let (old_size, plane_len) = (self.size, size[1] * size[2]);
+        let mut planes = vec![PlaneTally::default(); size[0]];
+        for (x, plane) in self.planes.iter().enumerate() {
+            if plane.toppled.is_empty() {
+                continue;
+            }
+            let out = &mut planes[x + offset[0]];
+            out.add::<i8>(plane_len, self.counting, 0, &[]);
+            for i in 0..old_size[1] * old_size[2] {
+                if plane.toppled[i / 64] >> (i % 64) & 1 == 1 {
+                    let y = i / old_size[2] + offset[1];
+                    let z = i % old_size[2] + offset[2];
+                    let j = y * size[2] + z;
+                    out.toppled[j / 64] |= 1 << (j % 64);
+                }
+            }
+            if self.counting {
+                let [_, ny, nz] = old_size;
+                out.counts = super::regrown(
+                    &plane.counts,
+                    [1, ny, nz],
+                    [1, size[1], size[2]],
+                    [0, offset[1], offset[2]],
+                );
+            }
+        }
+        self.planes = planes;
+        self.size = size;
+        self.toppled_bounds = self.toppled_bounds.map(|b| b.shifted(offset));
+        for seed in &mut self.seeds {
+            *seed = super::shifted(*seed, offset);
+        }
+    }
+}
+
+/// An avalanche in progress.
+pub struct Toppling {
+    size: [usize; 3],
+    stencil: Stencil,
+    boundary: Boundary,
+    /// The cells along the last axis that might be unstable, in each row by `y`, in each plane by `x`.
+    /// A plane's rows aren't allocated until one of them is marked, so small avalanches stay cheap in big worlds.
+    rows: Vec<Vec<[usize; 2]>>,
+    /// The rows that might be unstable, in each plane by `x`.
+    planes: Vec<[usize; 2]>,
+    /// Sweeps alternate between going forwards and backwards through the rows,
+    /// so that grains travel quickly in every direction.
+    backwards: bool,
+    /// Whether the last sweep found nothing to topple.
+    done: bool,
+    /// Contains every cell that has changed.
+    changed: Bounds,
+    tally: Tally,
+}
+
+impl Toppling {
+    /// `region` must contain every unstable cell.
+    /// The avalanche is added to `tally`, which must be for a world of the same size.
+    pub fn new(
+        size: [usize; 3],
+        stencil: Stencil,
+        boundary: Boundary,
+        region: Bounds,
+        tally: Tally,
+    ) -> Self {
+        let mut out = Self::empty(size, stencil, boundary, region, tally);
+        for x in region.lo[0]..=region.hi[0] {
+            for y in region.lo[1]..=region.hi[1] {
+                out.mark([x, y], [region.lo[2], region.hi[2]]);
+            }
+        }
+        out
+    }
+
+    fn empty(
+        size: [usize; 3],
+        stencil: Stencil,
+        boundary: Boundary,
+        changed: Bounds,
+        tally: Tally,
+    ) -> Self {
+        Self {
+            size,
+            stencil,
+            boundary,
+            rows: vec![Vec::new(); size[0]],
+            planes: vec![EMPTY; size[0]],
+            backwards: false,
+            done: false,
+            changed,
+            tally,
+        }
+    }
+
+    /// Contains every cell that has changed.
+    pub fn changed(&self) -> Bounds {
+        self.changed
+    }
+
+    pub fn into_tally(self) -> Tally {
+        self.tally
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// How many cells of `data`, the world being toppled, are unstable.
+    pub fn num_unstable(&self, data: &[i8]) -> usize {
+        let threshold = self.stencil.threshold;
+        let mut out = 0;
+        for (x, rows) in self.rows.iter().enumerate() {
+            for (y, &[lo, hi]) in rows.iter().enumerate() {
+                if lo <= hi {
+                    let start = (x * self.size[1] + y) * self.size[2];
+                    out += data[start + lo..=start + hi]
+                        .iter()
+                        .filter(|&&h| h >= threshold || h <= -threshold)
+                        .count();
+                }
+            }
+        }
+        out
+    }
+
+    /// Move every cell, after the world grows to `size`.
+    pub fn resize(&mut self, size: [usize; 3], offset: [usize; 3]) {
+        let mut tally = std::mem::replace(&mut self.tally, Tally::new([0; 3], Vec::new(), false));
+        tally.resize(size, offset);
+        let mut out = Self::empty(
+            size,
+            self.stencil.clone(),
+            self.boundary,
+            self.changed.shifted(offset),
+            tally,
+        );
+        for (x, rows) in self.rows.iter().enumerate() {
+            for (y, &[lo, hi]) in rows.iter().enumerate() {
+                if lo <= hi {
+                    out.mark(
+                        [x + offset[0], y + offset[1]],
+                        [lo + offset[2], hi + offset[2]],
+                    );
+                }
+            }
+        }
+        *self = out;
+    }
+
+    fn mark(&mut self, [x, y]: [usize; 2], range: [usize; 2]) {
+        union(&mut plane_rows(&mut self.rows[x], self.size)[y], range);
+        union(&mut self.planes[x], [y, y]);
+    }
+
+    /// Topple every row that might be unstable.
+    ///
+    /// `before_change` is called with each row and its heights, before any of them change.
+    /// Heights must be within twice the threshold beforehand, and stay within three times it.
+    ///
+    /// If some cell next to a wall is unstable, this stops, and returns the boundary cell
+    /// that it would topple onto. The avalanche can continue after the world grows.
+    pub fn sweep(
+        &mut self,
+        data: &mut [i8],
+        before_change: &(impl Fn(usize, &[i8]) + Sync),
+    ) -> Result<(), [usize; 3]> {
+        self.tally.allocate();
+        let size = self.size;
+        let stencil = &self.stencil;
+        let boundary = self.boundary;
+        let seeds = &self.tally.seeds;
+        let counting = self.tally.counting;
+        let backwards = self.backwards;
+        let mut toppled = false;
+
+        let mut firsts = [1, 2, 3];
+        if backwards {
+            firsts.reverse();
+        }
+        for &first in &firsts {
+            // Only the planes from the first to the last with rows to topple, so small avalanches stay cheap.
+            let mut marked = (first..size[0] - 1)
+                .step_by(3)
+                .filter(|&x| self.planes[x][0] <= self.planes[x][1]);
+            let lo = match marked.next() {
+                Some(lo) => lo,
+                None => continue,
+            };
+            let hi = marked.next_back().unwrap_or(lo);
+
+            // Each task gets the plane it topples, along with the planes on either side.
+            let plane_len = size[1] * size[2];
+            let planes = Planes {
+                data: &mut data[(lo - 1) * plane_len..(hi + 2) * plane_len],
+                tallies: &mut self.tally.planes[lo - 1..hi + 2],
+                rows: &mut self.rows[lo - 1..hi + 2],
+                planes: &mut self.planes[lo - 1..hi + 2],
+            };
+            let results = planes.map(size, |i, planes| {
+                let x = lo + 3 * i;
+                let mut plane = Plane {
+                    size,
+                    stencil,
+                    boundary,
+                    seeds,
+                    counting,
+                    x,
+                    planes,
+                };
+                plane.sweep(backwards, |row, heights| {
+                    before_change((x - 1) * size[1] + row, heights)
+                })
+            });
+
+            let mut edge = None;
+            for result in results {
+                if let Some(changed) = result.changed {
+                    self.changed = self.changed.union(changed);
+                    toppled = true;
+                }
+                if let Some(bounds) = result.toppled {
+                    let old = self.tally.toppled_bounds;
+                    self.tally.toppled_bounds = Some(old.map_or(bounds, |b| b.union(bounds)));
+                }
+                self.tally.topples += result.topples;
+                self.tally.waves += result.waves;
+                self.tally.lost += result.lost;
+                edge = edge.or(result.edge);
+            }
+            if let Some(cell) = edge {
+                return Err(cell);
+            }
+        }
+
+        self.done = !toppled;
+        self.backwards = !backwards;
+        Ok(())
+    }
+}
+
+/// Planes along the first axis, with what is known about each of their cells and rows.
+/// These start out covering many planes, and are split into groups of three.
+struct Planes<'a> {
+    data: &'a mut [i8],
+    tallies: &'a mut [PlaneTally],
+    rows: &'a mut [Vec<[usize; 2]>],
+    planes: &'a mut [[usize; 2]],
+}
+
+impl<'a> Planes<'a> {
+    /// Call `f` on every third plane, along with the planes on either side of each.
+    #[cfg(not(feature = "parallel"))]
+    fn map<T>(self, size: [usize; 3], f: impl Fn(usize, Planes) -> T) -> Vec<T> {
+        let plane_len = size[1] * size[2];
+        self.data
+            .chunks_exact_mut(3 * plane_len)
+            .zip(self.tallies.chunks_exact_mut(3))
+            .zip(self.rows.chunks_exact_mut(3))
+            .zip(self.planes.chunks_exact_mut(3))
+            .enumerate()
+            .map(|(i, (((data, tallies), rows), planes))| {
+                f(
+                    i,
+                    Planes {
+                        data,
+                        tallies,
+                        rows,
+                        planes,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Call `f` on every third plane, along with the planes on either side of each.
+    #[cfg(feature = "parallel")]
+    fn map<T: Send>(self, size: [usize; 3], f: impl Fn(usize, Planes) -> T + Sync) -> Vec<T> {
+        let plane_len = size[1] * size[2];
+        self.data
+            .par_chunks_exact_mut(3 * plane_len)
+            .zip(self.tallies.par_chunks_exact_mut(3))
+            .zip(self.rows.par_chunks_exact_mut(3))
+            .zip(self.planes.par_chunks_exact_mut(3))
+            .enumerate()
+            .map(|(i, (((data, tallies), rows), planes))| {
+                f(
+                    i,
+                    Planes {
+                        data,
+                        tallies,
+                        rows,
+                        planes,
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+struct SweepResult {
+    /// Contains every cell that changed, if any did.
+    changed: Option<Bounds>,
+    /// The boundary cell that a cell would have toppled onto, if the sweep stopped early.
+    edge: Option<[usize; 3]>,
+    /// As in `Tally`, but only for this sweep.
+    toppled: Option<Bounds>,
+    topples: u64,
+    waves: u64,
+    lost: isize,
+}
+
+/// A plane along the first axis, along with the planes on either side.
+struct Plane<'a> {
+    size: [usize; 3],
+    stencil: &'a Stencil,
+    boundary: Boundary,
+    seeds: &'a [[usize; 3]],
+    /// As in `Tally`.
+    counting: bool,
+    /// The position of the middle plane.
+    x: usize,
+    planes: Planes<'a>,
+}
+
+impl Plane<'_> {
+    /// Topple every row of the middle plane that might be unstable, in order.
+    /// Each cell topples at most once.
+    fn sweep(&mut self, backwards: bool, before_change: impl Fn(usize, &[i8])) -> SweepResult {
+        let mut out = SweepResult {
+            changed: None,
+            edge: None,
+            toppled: None,
+            topples: 0,
+            waves: 0,
+            lost: 0,
+        };
+
+        // Rows that are marked ahead of the sweep are toppled during it; the rest wait until next time.
+        let [lo_y, hi_y] = self.planes.planes[1];
+        if lo_y > hi_y {
+            return out;
+        }
+
+        let mut next = EMPTY;
+        let mut topples = Vec::with_capacity(self.size[2] + 4);
+
+        let mut y = if backwards { hi_y } else { lo_y };
+        while lo_y.min(next[0]) <= y && y <= hi_y.max(next[1]) {
+            if let Err(cell) = self.topple_row(y, &mut next, &mut topples, &mut out, &before_change)
+            {
+                union(&mut self.planes.planes[1], next);
+                out.edge = Some(cell);
+                return out;
+            }
+            y = if backwards { y.wrapping_sub(1) } else { y + 1 };
+        }
+
+        self.planes.planes[1] = next;
+        out
+    }
+
+    /// Topple row `y` of the middle plane, if it might be unstable, marking the rows that might be unstable afterwards.
+    /// Adds what happened to `out`.
+    fn topple_row(
+        &mut self,
+        y: usize,
+        next: &mut [usize; 2],
+        topples: &mut Vec<i8>,
+        out: &mut SweepResult,
+        before_change: impl Fn(usize, &[i8]),
+    ) -> Result<(), [usize; 3]> {
+        let size = self.size;
+        let Planes {
+            data,
+            tallies,
+            rows,
+            planes,
+        } = &mut self.planes;
+        let row = size[1] + y;
+        let start = row * size[2];
+        let [lo, hi] = std::mem::replace(&mut plane_rows(&mut rows[1], size)[y], EMPTY);
+        if lo > hi {
+            return Ok(());
+        }
+
+        // Whole chunks are cheaper to update than the exact range,
+        // and the extra cells are stable, so they don't topple.
+        let lo = (lo & !(CHUNK - 1)).max(1);
+        let hi = (hi | (CHUNK - 1)).min(size[2] - 2);
+        let len = hi + 1 - lo;
+
+        // Padded with two zeros at each end, for the neighbors along the row.
+        // Untopples count as -1.
+        topples.clear();
+        topples.extend_from_slice(&[0, 0]);
+        let threshold = self.stencil.threshold;
+        topples.extend(
+            data[start + lo..start + hi + 1]
+                .iter()
+                .map(|&h| (h >= threshold) as i8 - (h <= -threshold) as i8),
+        );
+        topples.extend_from_slice(&[0, 0]);
+        let here = &topples[2..len + 2];
+
+        let (first, last) = match here.iter().position(|&t| t != 0) {
+            Some(first) => (lo + first, lo + here.iter().rposition(|&t| t != 0).unwrap()),
+            None => return Ok(()),
+        };
+        let sink = self.boundary == Boundary::Sink;
+        if !sink {
+            if let Some(cell) = edge_cell(size, [self.x, y, first], last) {
+                rows[1][y] = [lo, hi];
+                return Err(cell);
+            }
+        }
+
+        // Keep track of what toppled, for the tally.
+        tallies[1].add(size[1] * size[2], self.counting, y * size[2] + lo, here);
+        let row_topples: u64 = here.iter().map(|&t| t.unsigned_abs() as u64).sum();
+        let bounds = Bounds {
+            lo: [self.x, y, first],
+            hi: [self.x, y, last],
+        };
+        out.toppled = Some(out.toppled.map_or(bounds, |b| b.union(bounds)));
+        out.topples += row_topples;
+        let (x, seeds) = (self.x, self.seeds);
+        let from = seeds.partition_point(|&seed| seed < [x, y, first]);
+        for &[_, _, z] in seeds[from..]
+            .iter()
+            .take_while(|&&seed| seed <= [x, y, last])
+        {
+            out.waves += here[z - lo].unsigned_abs() as u64;
+        }
+
+        for &([dx, dy], counts) in &self.stencil.rows {
+            let plane = (1 + dx) as usize;
+            let y = (y as isize + dy) as usize;
+            let neighbor = plane * size[1] + y;
+            let start = neighbor * size[2];
+
+            // Every cell of a row in the boundary layer is in the sink.
+            let x = self.x + plane - 1;
+            if sink && (x == 0 || x == size[0] - 1 || y == 0 || y == size[1] - 1) {
+                let num_grains: isize = topples.iter().map(|&t| t as isize).sum();
+                out.lost += num_grains * counts.iter().map(|&c| c as isize).sum::<isize>();
+                continue;
+            }
+
+            before_change(neighbor, &data[start..start + size[2]]);
+            spread(
+                &mut data[start + lo - 1..start + hi + 2],
+                topples,
+                if neighbor == row {
+                    self.stencil.threshold
+                } else {
+                    0
+                },
+                counts,
+            );
+            if sink {
+                for z in &[0, size[2] - 1] {
+                    out.lost += std::mem::take(&mut data[start + z]) as isize;
+                }
+            }
+
+            let reach = [(counts[0] > 0) as usize, (counts[2] > 0) as usize];
+            union(
+                &mut plane_rows(&mut rows[plane], size)[y],
+                [first - reach[0], last + reach[1]],
+            );
+            if plane == 1 {
+                union(next, [y, y]);
+            } else {
+                union(&mut planes[plane], [y, y]);
+            }
+        }
+
+        let changed = Bounds {
+            lo: [self.x - 1, y - 1, first - 1],
+            hi: [self.x + 1, y + 1, last + 1],
+        };
+        out.changed = Some(out.changed.map_or(changed, |b| b.union(changed)));
+        Ok(())
+    }
+}
+
+/// Update a row of cells, given how many times each cell next to them toppled.
+/// `topples` has one more cell on either end than `cells`, and each cell loses `lost` grains per topple.
+/// `counts` are as in `Stencil`: how many neighbors each toppling cell has in this row,
+/// before it, level with it, and after it.
+pub(super) fn spread(cells: &mut [i8], topples: &[i8], lost: i8, counts: [i8; 3]) {
+    let len = cells.len();
+    let from_before = &topples[..len];
+    let from_beside = &topples[1..len + 1];
+    let from_after = &topples[2..len + 2];
+    // A neighbor before a cell sends grains to the cell after it, and vice versa.
+    let [after, beside, before] = counts;
+
+    // The common cases are much faster without multiplying.
+    if [lost, after, beside, before] == [0, 0, 1, 0] {
+        // The cells on either end are next to cells that didn't topple.
+        for (h, &t) in cells[1..len - 1].iter_mut().zip(&from_beside[1..len - 1]) {
+            *h += t;
+        }
+    } else if [after, beside, before] == [1, 0, 1] {
+        for i in 0..len {
+            cells[i] = cells[i] - lost * from_beside[i] + from_before[i] + from_after[i];
+        }
+    } else {
+        for i in 0..len {
+            cells[i] = cells[i] - lost * from_beside[i]
+                + before * from_before[i]
+                + beside * from_beside[i]
+                + after * from_after[i];
+        }
+    }
+}
+
+/// The rows of a plane, as in `Toppling`, allocated if they weren't yet.
+fn plane_rows(rows: &mut Vec<[usize; 2]>, size: [usize; 3]) -> &mut [[usize; 2]] {
+    if rows.is_empty() {
+        *rows = vec![EMPTY; size[1]];
+    }
+    rows
+}
+
+fn union(range: &mut [usize; 2], other: [usize; 2]) {
+    range[0] = range[0].min(other[0]);
+    range[1] = range[1].max(other[1]);
+}
+
+/// The boundary cell that the cells from `first` to `[first[0], first[1], last]` would topple onto, if any.
+pub(super) fn edge_cell(size: [usize; 3], first: [usize; 3], last: usize) -> Option<[usize; 3]> {
+    let [x, y, z] = first;
+    if x == 1 {
+        Some([0, y, z])
+    } else if x == size[0] - 2 {
+        Some([x + 1, y, z])
+    } else if y == 1 {
+        Some([x, 0, z])
+    } else if y == size[1] - 2 {
+        Some([x, y + 1, z])
+    } else if z == 1 {
+        Some([x, y, 0])
+    } else if last == size[2] - 2 {
+        Some([x, y, last + 1])
+    } else {
+        None
+    }
+}
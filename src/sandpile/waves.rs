@@ -0,0 +1,128 @@
+//! Avalanches split into waves, as Ivashkevich, Ktitarev and Priezzhev did.
+//!
+//! In each wave, the cell the grains were dropped on topples once,
+//! and everything that sets off is relaxed, with that cell held back, before it topples again.
+//! Starting from a stable world, each cell topples at most once in a wave,
+//! so a wave is just the set of cells that toppled in it.
+//! By the abelian property, the world ends up the same as with `World::add_sand_at`,
+//! as long as it has no holes.
+
+use super::topple::Tally;
+use super::{AvalancheStats, Backup, OverflowError, World};
+
+use std::collections::VecDeque;
+
+/// The cells that toppled in a wave, relative to the center, in increasing order.
+pub type Wave = Vec<[isize; 3]>;
+
+impl World {
+    /// Add grains to the cell at `pos`, relative to the center, and topple a wave at a time until stable.
+    /// Returns the cells that toppled in each wave.
+    /// Panics if the avalanche reaches the boundary layer; see `try_add_sand_in_waves`.
+    pub fn add_sand_in_waves(
+        &mut self,
+        pos: [isize; 3],
+        num_grains: usize,
+    ) -> (AvalancheStats, Vec<Wave>) {
+        self.try_add_sand_in_waves(pos, num_grains)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `add_sand_in_waves`, but if the avalanche reaches the boundary layer,
+    /// and the world cannot grow any further, the world is left as it was before the call.
+    /// The world must not be symmetric or packed, or use the Manna rule.
+    pub fn try_add_sand_in_waves(
+        &mut self,
+        pos: [isize; 3],
+        num_grains: usize,
+    ) -> Result<(AvalancheStats, Vec<Wave>), OverflowError> {
+        assert!(
+            !self.is_symmetric() && !self.is_packed() && !self.is_manna(),
+            "Only abelian worlds that are not symmetric or packed can be toppled a wave at a time."
+        );
+        let (old_size, old_center) = (self.size, self.center);
+
+        self.backup = Some(Backup::new(self));
+        let result = self.drop_in_waves(pos, num_grains);
+        let backup = self.backup.take().unwrap();
+
+        match result {
+            Ok((tally, waves)) => {
+                self.total_grains += num_grains as isize - tally.lost;
+                Ok((self.finish(old_size, old_center, tally), waves))
+            }
+            Err(cell) => {
+                self.restore(backup);
+                Err(OverflowError {
+                    total_grains: self.total_grains + num_grains as isize,
+                    cell,
+                })
+            }
+        }
+    }
+
+    /// Drop grains on the world, and topple it a wave at a time.
+    /// On failure, returns the boundary cell the avalanche reached.
+    fn drop_in_waves(
+        &mut self,
+        pos: [isize; 3],
+        num_grains: usize,
+    ) -> Result<(Tally, Vec<Wave>), [usize; 3]> {
+        self.fit(&[(pos, num_grains as isize)])?;
+        let mut seed = self.grid_index(pos);
+        let mut tally = Tally::new(self.size, vec![seed], self.odometer.is_some());
+        let neighbors = self.lattice.neighbors();
+        let threshold = neighbors.len() as i8;
+
+        // The seed can hold far more grains than fit in a cell, so its height is kept here,
+        // and its cell only collects the grains that come back to it during a wave.
+        let mut height = self[seed] as isize + num_grains as isize;
+        self.change(seed, -self[seed]);
+
+        let mut waves = Vec::new();
+        // Cells topple in the order they became unstable, so none gets too high while it waits.
+        let mut unstable = VecDeque::new();
+        while height >= threshold as isize {
+            let mut wave = Vec::new();
+            let mut cell = seed;
+            loop {
+                self.make_room(&mut cell, |size, offset| {
+                    tally.resize(size, offset);
+                    seed = super::shifted(seed, offset);
+                    for cell in &mut unstable {
+                        *cell = super::shifted(*cell, offset);
+                    }
+                })?;
+                if cell == seed {
+                    height -= threshold as isize;
+                } else if self.change(cell, -threshold) >= threshold {
+                    unstable.push_back(cell);
+                }
+                for &d in &neighbors {
+                    let to = [0, 1, 2].map(|i| (cell[i] as isize + d[i]) as usize);
+                    if (0..3).any(|i| to[i] == 0 || to[i] == self.size[i] - 1) {
+                        tally.lost += 1;
+                    } else if self.change(to, 1) == threshold && to != seed {
+                        unstable.push_back(to);
+                    }
+                }
+                tally.count_topple(cell);
+                wave.push(self.position(cell));
+
+                match unstable.pop_front() {
+                    Some(next) => cell = next,
+                    None => break,
+                }
+            }
+
+            let returned = self[seed];
+            height += returned as isize;
+            self.change(seed, -returned);
+            wave.sort_unstable();
+            wave.dedup();
+            waves.push(wave);
+        }
+        self.change(seed, height as i8);
+        Ok((tally, waves))
+    }
+}
@@ -0,0 +1,1327 @@
+use sandpile_3d::sandpile::{
+    ArithmeticError, AvalancheHistograms, Boundary, DivisibleWorld, FixedEnergyWorld, Lattice,
+    RandomDrive, World,
+};
+
+/// Stabilize by toppling one cell at a time, as `World` once did.
+/// The drops are relative to the center, and grains that reach the boundary layer are lost.
+/// Negative drops dig holes, which untopple.
+fn one_cell_at_a_time(
+    size: [usize; 3],
+    lattice: Lattice,
+    drops: &[([isize; 3], isize)],
+) -> Vec<i8> {
+    let mut data = vec![0; size[0] * size[1] * size[2]];
+    topple_one_cell_at_a_time(size, lattice, &mut data, drops);
+    data
+}
+
+/// Drops of sand, as taken by `one_cell_at_a_time`.
+fn signed(drops: &[([isize; 3], usize)]) -> Vec<([isize; 3], isize)> {
+    drops.iter().map(|&(pos, n)| (pos, n as isize)).collect()
+}
+
+/// Like `one_cell_at_a_time`, but starting from `data`.
+/// Returns how many times each cell toppled.
+fn topple_one_cell_at_a_time(
+    size: [usize; 3],
+    lattice: Lattice,
+    data: &mut [i8],
+    drops: &[([isize; 3], isize)],
+) -> Vec<u64> {
+    let threshold = lattice.threshold() as isize;
+    let mut odometer = vec![0; data.len()];
+    let mut todo: Vec<_> = drops
+        .iter()
+        .map(|&(pos, num_grains)| {
+            let mut loc = [0; 3];
+            for i in 0..3 {
+                loc[i] = (size[i] as isize / 2 + pos[i]) as usize;
+            }
+            (loc, num_grains)
+        })
+        .collect();
+
+    while let Some((loc, num_grains)) = todo.pop() {
+        if !loc.iter().zip(&size).all(|(&x, &n)| 0 < x && x < n - 1) {
+            continue;
+        }
+        let index = (loc[0] * size[1] + loc[1]) * size[2] + loc[2];
+        let pile = &mut data[index];
+
+        let pile_grains = *pile as isize + num_grains;
+        let num_topples = pile_grains / threshold;
+        *pile = (pile_grains % threshold) as i8;
+        odometer[index] += num_topples.unsigned_abs() as u64;
+
+        if num_topples != 0 {
+            for offset in lattice.neighbors() {
+                let mut loc = loc;
+                for i in 0..3 {
+                    loc[i] = (loc[i] as isize + offset[i]) as usize;
+                }
+                todo.push((loc, num_topples));
+            }
+        }
+    }
+    odometer
+}
+
+// A pile at the center is stabilized using the cube's symmetries, rather than by sweeping the world,
+// so toppling on many threads is checked by `parallel_same_as_one_cell_at_a_time`.
+#[test]
+fn same_as_one_cell_at_a_time() {
+    for &num_grains in &[0, 1, 5, 6, 7, 100, 12_345] {
+        let mut world = World::new([32; 3]);
+        world.add_sand(num_grains);
+        assert_eq!(
+            *world.to_color_array(),
+            *one_cell_at_a_time([32; 3], Lattice::Cubic6, &[([0; 3], num_grains as isize)]),
+            "{} grains",
+            num_grains,
+        );
+    }
+}
+
+// Drops away from the center, and too small to estimate, are stabilized by sweeping the world,
+// toppling every third plane at the same time, on many threads, which must change nothing.
+#[cfg(feature = "parallel")]
+#[test]
+fn parallel_same_as_one_cell_at_a_time() {
+    let drops = [
+        ([-6, 0, 2], 300),
+        ([5, 1, -3], 2_000),
+        ([0, 3, 0], 40),
+        ([2, -4, 1], 7),
+    ];
+    for &lattice in &Lattice::ALL {
+        let worlds = [
+            World::new([32; 3]),
+            World::new([23; 3]).with_boundary(Boundary::Sink),
+            World::growable([8; 3], [64; 3]),
+        ];
+        for world in worlds {
+            let mut world = world.with_lattice(lattice).with_odometer();
+            world.add_sand_multi(&drops);
+
+            let size = world.size();
+            let mut data = vec![0; size[0] * size[1] * size[2]];
+            let odometer = topple_one_cell_at_a_time(size, lattice, &mut data, &signed(&drops));
+            assert_eq!(*world.to_color_array(), *data, "{:?} {:?}", lattice, size);
+            let odometer: Vec<_> = odometer.iter().map(|&t| t as u32).collect();
+            assert_eq!(world.odometer().unwrap(), &odometer[..]);
+        }
+    }
+}
+
+#[test]
+fn same_after_growing() {
+    let mut world = World::growable([8; 3], [64; 3]);
+    for _ in 0..10 {
+        world.add_sand(2_000);
+    }
+    assert_eq!(world.size(), [32; 3]);
+    assert_eq!(
+        *world.to_color_array(),
+        *one_cell_at_a_time([32; 3], Lattice::Cubic6, &[([0; 3], 20_000)])
+    );
+
+    let mut world = World::chunked([8; 3], [64; 3]);
+    world.add_sand(20_000);
+    assert_eq!(
+        *world.to_color_array(),
+        *one_cell_at_a_time([32; 3], Lattice::Cubic6, &[([0; 3], 20_000)])
+    );
+}
+
+#[test]
+fn chunked_same_as_dense() {
+    for &lattice in &Lattice::ALL {
+        for boundary in [Boundary::Wall, Boundary::Sink] {
+            let (size, max_size) = match boundary {
+                Boundary::Wall => ([9, 12, 10], [80; 3]),
+                Boundary::Sink => ([21, 18, 25], [21, 18, 25]),
+            };
+            let worlds = [
+                World::growable(size, max_size),
+                World::chunked(size, max_size),
+            ];
+            let [mut dense, mut chunked] = worlds.map(|world| {
+                world
+                    .with_lattice(lattice)
+                    .with_boundary(boundary)
+                    .with_odometer()
+            });
+
+            // Chunks are keyed by signed positions, so the drops go on every side of the center.
+            let drives: [&dyn Fn(&mut World) -> _; 4] = [
+                &|world| world.add_sand_multi(&[([-5, -3, -7], 300), ([4, -6, 2], 200)]),
+                &|world| world.add_sand_at([-3, 2, -3], 3_000),
+                &|world| world.add_sand(5),
+                &|world| world.add_sand_multi(&[([-1, -1, -1], 2), ([7, 0, -8], 1)]),
+            ];
+            for drive in &drives {
+                assert_eq!(drive(&mut chunked), drive(&mut dense));
+                assert_eq!(chunked.size(), dense.size());
+                assert_eq!(chunked.center(), dense.center());
+                assert_eq!(chunked.to_color_array(), dense.to_color_array());
+                assert_eq!(chunked.odometer(), dense.odometer());
+                assert_eq!(chunked.total_grains(), dense.total_grains());
+            }
+            RandomDrive::uniform(5).drive(&mut chunked, 300).unwrap();
+            RandomDrive::uniform(5).drive(&mut dense, 300).unwrap();
+            assert_eq!(
+                chunked.to_color_array(),
+                dense.to_color_array(),
+                "{:?}",
+                lattice
+            );
+            assert_eq!(chunked.odometer(), dense.odometer());
+            assert!(chunked.is_chunked());
+        }
+    }
+}
+
+#[test]
+fn packed_same_as_dense() {
+    for &lattice in &Lattice::ALL {
+        for boundary in [Boundary::Wall, Boundary::Sink] {
+            let (size, max_size) = match boundary {
+                Boundary::Wall => ([9, 12, 10], [80; 3]),
+                Boundary::Sink => ([21, 18, 25], [21, 18, 25]),
+            };
+            let worlds = [
+                World::growable(size, max_size),
+                World::packed(size, max_size),
+            ];
+            let [mut dense, mut packed] = worlds.map(|world| {
+                world
+                    .with_lattice(lattice)
+                    .with_boundary(boundary)
+                    .with_odometer()
+            });
+
+            // Small avalanches stay in a small window, and big ones widen it, and grow the world.
+            let drives: [&dyn Fn(&mut World) -> _; 5] = [
+                &|world| world.add_sand(5),
+                &|world| world.add_sand_multi(&[([-5, -3, -7], 300), ([4, -6, 2], 200)]),
+                &|world| world.add_sand_at([-3, 2, -3], 3_000),
+                &|world| world.remove_sand_at([2, 1, 0], 400),
+                &|world| world.add_sand_multi(&[([-1, -1, -1], 2), ([7, 0, -8], 1)]),
+            ];
+            for drive in &drives {
+                assert_eq!(drive(&mut packed), drive(&mut dense), "{:?}", lattice);
+                assert_eq!(packed.size(), dense.size());
+                assert_eq!(packed.center(), dense.center());
+                assert_eq!(packed.to_color_array(), dense.to_color_array());
+                assert_eq!(packed.odometer(), dense.odometer());
+                assert_eq!(packed.total_grains(), dense.total_grains());
+            }
+            RandomDrive::uniform(5).drive(&mut packed, 300).unwrap();
+            RandomDrive::uniform(5).drive(&mut dense, 300).unwrap();
+            assert_eq!(packed.to_color_array(), dense.to_color_array());
+            assert!(packed.is_packed());
+            assert!(packed.memory_size() < dense.memory_size());
+
+            let [nx, ny, nz] = dense.size();
+            let mut slab = vec![0; ny * nz];
+            for x in 0..nx {
+                packed.unpack_slab(x, &mut slab);
+                assert_eq!(slab, dense.to_color_array()[x * ny * nz..(x + 1) * ny * nz]);
+                for y in 0..ny {
+                    for z in 0..nz {
+                        assert_eq!(packed[[x, y, z]], dense[[x, y, z]]);
+                    }
+                }
+            }
+        }
+    }
+
+    // The Manna rule adds sand in the window the same way.
+    let mut manna = World::growable([9; 3], [64; 3]).with_manna(3);
+    let mut packed_manna = World::packed([9; 3], [64; 3]).with_manna(3);
+    for &n in &[1, 40, 3_000, 20_000] {
+        assert_eq!(packed_manna.add_sand(n), manna.add_sand(n));
+        assert_eq!(packed_manna.to_color_array(), manna.to_color_array());
+    }
+}
+
+#[test]
+fn same_on_every_lattice() {
+    for &lattice in &Lattice::ALL {
+        let mut world = World::growable([8; 3], [64; 3]).with_lattice(lattice);
+        world.add_sand(1_000);
+        world.add_sand(9_000);
+        assert_eq!(
+            *world.to_color_array(),
+            *one_cell_at_a_time(world.size(), lattice, &[([0; 3], 10_000)]),
+            "{:?}",
+            lattice,
+        );
+    }
+}
+
+#[test]
+fn same_as_one_cell_at_a_time_with_many_drops() {
+    let drops = [([-6, 0, 2], 3_000), ([5, 1, -3], 4_000), ([0, 0, 0], 500)];
+
+    let mut world = World::new([32; 3]);
+    world.add_sand_multi(&drops);
+    assert_eq!(
+        *world.to_color_array(),
+        *one_cell_at_a_time([32; 3], Lattice::Cubic6, &signed(&drops))
+    );
+
+    // By the abelian property, the order of the drops doesn't matter.
+    let mut world = World::new([32; 3]);
+    for &(pos, num_grains) in drops.iter().rev() {
+        world.add_sand_at(pos, num_grains);
+    }
+    assert_eq!(
+        *world.to_color_array(),
+        *one_cell_at_a_time([32; 3], Lattice::Cubic6, &signed(&drops))
+    );
+}
+
+#[test]
+fn overflow_leaves_the_world_as_it_was() {
+    let worlds = [
+        World::new([9; 3]),
+        World::growable([9; 3], [20; 3]),
+        World::chunked([9; 3], [20; 3]),
+        World::symmetric([9; 3], [21; 3]),
+        World::packed([9; 3], [20; 3]),
+    ];
+    for world in worlds {
+        let mut world = world.with_odometer();
+        world.add_sand(200);
+        let before = world.clone();
+
+        let err = world.try_add_sand(100_000).unwrap_err();
+        assert_eq!(err.total_grains, 100_200);
+        assert_eq!(world.size(), before.size());
+        assert_eq!(world.center(), before.center());
+        assert_eq!(*world.to_color_array(), *before.to_color_array());
+        assert_eq!(world.odometer(), before.odometer());
+        assert_eq!(world.total_grains(), before.total_grains());
+        assert_eq!(world.memory_size(), before.memory_size());
+
+        // Sand added afterwards topples as if nothing had happened.
+        let mut expected = before.clone();
+        assert_eq!(world.add_sand(50), expected.add_sand(50));
+        assert_eq!(*world.to_color_array(), *expected.to_color_array());
+    }
+}
+
+#[test]
+fn growing_keeps_the_pile_centered() {
+    let max_size = [80, 33, 64];
+    let mut world = World::growable([9, 5, 12], max_size);
+    let mut fixed = World::new([65; 3]);
+    for _ in 0..20 {
+        let (old_size, old_center) = (world.size(), world.center());
+        world.add_sand(500);
+        fixed.add_sand(500);
+
+        // Each axis doubles when the pile reaches it, up to its maximum, and the pile stays in the middle.
+        let (size, center) = (world.size(), world.center());
+        for i in 0..3 {
+            assert!(size[i] == old_size[i] || size[i] == (2 * old_size[i]).min(max_size[i]));
+            assert_eq!(center[i] - old_center[i], (size[i] - old_size[i]) / 2);
+        }
+
+        let mut num_grains = 0;
+        for x in 0..size[0] {
+            for y in 0..size[1] {
+                for z in 0..size[2] {
+                    let pos = [x, y, z];
+                    let pos = [0, 1, 2].map(|i| pos[i] as isize - center[i] as isize);
+                    assert_eq!(world[pos], fixed[pos], "{:?}", pos);
+                    num_grains += world[pos] as isize;
+                }
+            }
+        }
+        assert_eq!(num_grains, fixed.total_grains());
+        assert_eq!(world.total_grains(), fixed.total_grains());
+    }
+    assert_eq!(world.size(), [36, 33, 24]);
+}
+
+#[test]
+fn grows_to_fit_drops() {
+    let mut world = World::growable([8; 3], [32; 3]);
+    world.add_sand_at([10, 0, 0], 10);
+    assert_eq!(world.size(), [32, 8, 8]);
+    assert_eq!(world.total_grains(), 10);
+
+    let mut world = World::growable([8; 3], [16; 3]);
+    assert!(world.try_add_sand_at([10, 0, 0], 10).is_err());
+    assert_eq!(world.size(), [8; 3]);
+    assert_eq!(world.total_grains(), 0);
+}
+
+#[test]
+fn same_with_a_sink() {
+    for &lattice in &Lattice::ALL {
+        let mut world = World::new([12; 3])
+            .with_lattice(lattice)
+            .with_boundary(Boundary::Sink);
+        world.add_sand(5_000);
+        world.add_sand_at([3, -2, 1], 5);
+        assert_eq!(
+            *world.to_color_array(),
+            *one_cell_at_a_time([12; 3], lattice, &[([0; 3], 5_000), ([3, -2, 1], 5)]),
+            "{:?}",
+            lattice,
+        );
+        let heights: isize = world.to_color_array().iter().map(|&h| h as isize).sum();
+        assert_eq!(world.total_grains(), heights);
+    }
+}
+
+#[test]
+fn random_drive_is_reproducible() {
+    let driven = |seed| {
+        let mut world = World::new([16; 3]).with_boundary(Boundary::Sink);
+        RandomDrive::uniform(seed)
+            .drive(&mut world, 20_000)
+            .unwrap();
+        world.to_color_array().into_owned()
+    };
+    assert_eq!(driven(1), driven(1));
+    assert_ne!(driven(1), driven(2));
+
+    let mut world = World::new([16; 3]);
+    RandomDrive::weighted(3, &[([2, 0, 0], 1.), ([-2, 0, 0], 0.)])
+        .drive(&mut world, 500)
+        .unwrap();
+    let mut expected = World::new([16; 3]);
+    expected.add_sand_at([2, 0, 0], 500);
+    assert_eq!(*world.to_color_array(), *expected.to_color_array());
+}
+
+#[test]
+fn avalanche_stats() {
+    let size = [16; 3];
+    let check = |world: &mut World, drops: &[([isize; 3], usize)]| {
+        let mut data = world.to_color_array().into_owned();
+        let odometer = topple_one_cell_at_a_time(size, world.lattice(), &mut data, &signed(drops));
+        let stats = world.add_sand_multi(drops);
+        assert_eq!(*world.to_color_array(), *data);
+
+        let index = |pos: [isize; 3]| {
+            let loc: Vec<_> = (0..3)
+                .map(|i| (size[i] as isize / 2 + pos[i]) as usize)
+                .collect();
+            (loc[0] * size[1] + loc[1]) * size[2] + loc[2]
+        };
+        assert_eq!(stats.topples, odometer.iter().sum::<u64>());
+        assert_eq!(stats.sites, odometer.iter().filter(|&&n| n > 0).count());
+        assert_eq!(
+            stats.waves,
+            drops
+                .iter()
+                .map(|&(pos, _)| odometer[index(pos)])
+                .sum::<u64>()
+        );
+        if let Some((lo, hi)) = stats.bounds {
+            for i in 0..3 {
+                assert!(lo[i] <= hi[i]);
+            }
+        }
+        assert_eq!(stats.bounds.is_some(), stats.topples > 0);
+        stats
+    };
+
+    let mut world = World::new(size).with_boundary(Boundary::Sink);
+    let stats = check(&mut world, &[([0; 3], 30_000)]);
+    assert!(stats.lost > 0);
+    assert_eq!(stats.bounds, Some(([-7; 3], [6; 3])));
+    check(&mut world, &[([2, -1, 0], 1)]);
+    check(&mut world, &[([-3, 0, 0], 5), ([4, 1, 0], 200)]);
+
+    let mut histograms = AvalancheHistograms::new();
+    let mut drive = RandomDrive::uniform(7);
+    for _ in 0..1_000 {
+        let pos = drive.next_site(&world);
+        histograms.record(&check(&mut world, &[(pos, 1)]));
+    }
+    assert_eq!(histograms.topples.total(), 1_000);
+    assert!(histograms.topples.bins().count() > 4);
+}
+
+// A big pile at the center of a cube is stabilized using the cube's symmetries,
+// and a small avalanche a cell at a time, so each is checked against the stack, along with what it counts.
+#[test]
+fn big_piles_same_as_one_cell_at_a_time() {
+    for &lattice in &Lattice::ALL {
+        let threshold = lattice.threshold() as isize;
+        let drops = [
+            ([2, -1, 3], 300),
+            ([0; 3], 10_000),
+            ([1, 0, 0], 1),
+            ([0; 3], threshold),
+            ([-1, 2, 0], -threshold),
+        ];
+        let worlds = [
+            World::growable([8; 3], [64; 3]),
+            World::chunked([8; 3], [64; 3]),
+            World::new([23; 3]).with_boundary(Boundary::Sink),
+            World::new([24; 3]).with_boundary(Boundary::Sink),
+        ];
+        for world in worlds {
+            let mut world = world.with_lattice(lattice).with_odometer();
+            let stats: Vec<_> = drops
+                .iter()
+                .map(|&drop| world.add_signed_multi(&[drop]))
+                .collect();
+
+            let size = world.size();
+            let mut data = vec![0; size[0] * size[1] * size[2]];
+            let mut expected = vec![0; data.len()];
+            for (&drop, stats) in drops.iter().zip(&stats) {
+                let odometer = topple_one_cell_at_a_time(size, lattice, &mut data, &[drop]);
+                assert_eq!(stats.topples, odometer.iter().sum::<u64>());
+                assert_eq!(stats.sites, odometer.iter().filter(|&&n| n > 0).count());
+                for (n, t) in expected.iter_mut().zip(odometer) {
+                    *n += t as u32;
+                }
+            }
+            assert_eq!(*world.to_color_array(), *data, "{:?} {:?}", lattice, size);
+            assert_eq!(world.odometer().unwrap(), &expected[..]);
+            let heights: isize = data.iter().map(|&h| h as isize).sum();
+            assert_eq!(world.total_grains(), heights);
+        }
+    }
+}
+
+// Big piles away from the center are stabilized on their own from an estimate, and then added,
+// so they are checked against the stack, and against the same grains dropped a little at a time.
+#[test]
+fn estimated_piles_same_as_one_cell_at_a_time() {
+    let drops = [
+        vec![([1, 0, 0], 8_000)],
+        vec![([3, -1, 2], 9_000), ([-4, 2, 0], 8_000), ([0, 1, -1], 700)],
+        vec![([2, 0, -1], -9_000), ([-1, -3, 1], -8_500)],
+    ];
+    for &lattice in &Lattice::ALL {
+        for drops in &drops {
+            let worlds = [
+                World::growable([8; 3], [64; 3]),
+                World::chunked([8; 3], [64; 3]),
+                World::new([21; 3]).with_boundary(Boundary::Sink),
+            ];
+            for world in worlds {
+                let mut world = world.with_lattice(lattice).with_odometer();
+                let mut pieces = world.clone();
+                let stats = world.add_signed_multi(drops);
+                for &(pos, num_grains) in drops {
+                    for _ in 0..4 {
+                        pieces.add_signed_multi(&[(pos, num_grains / 4)]);
+                    }
+                }
+                assert_eq!(world.size(), pieces.size(), "{:?} {:?}", lattice, drops);
+
+                let size = world.size();
+                let mut data = vec![0; size[0] * size[1] * size[2]];
+                let odometer = topple_one_cell_at_a_time(size, lattice, &mut data, drops);
+                assert_eq!(*world.to_color_array(), *data, "{:?} {:?}", lattice, drops);
+                assert_eq!(*pieces.to_color_array(), *data, "{:?} {:?}", lattice, drops);
+                assert_eq!(stats.topples, odometer.iter().sum::<u64>());
+                assert_eq!(stats.sites, odometer.iter().filter(|&&n| n > 0).count());
+                let odometer: Vec<_> = odometer.iter().map(|&t| t as u32).collect();
+                assert_eq!(world.odometer().unwrap(), &odometer[..]);
+            }
+        }
+    }
+}
+
+#[test]
+fn sandpile_arithmetic() {
+    let a_drops = [([0, 0, 0], 3_000), ([4, -2, 1], 7)];
+    let b_drops = [([-3, 1, 0], 2_000)];
+    let both: Vec<_> = a_drops.iter().chain(&b_drops).cloned().collect();
+    let times = |drops: &[([isize; 3], usize)], factor| -> Vec<_> {
+        drops.iter().map(|&(pos, n)| (pos, n * factor)).collect()
+    };
+
+    for &boundary in &[Boundary::Wall, Boundary::Sink] {
+        let world = || World::new([32; 3]).with_boundary(boundary);
+        let mut a = world();
+        a.add_sand_multi(&a_drops);
+        let mut b = world();
+        b.add_sand_multi(&b_drops);
+        let mut expected = world();
+        expected.add_sand_multi(&both);
+
+        let sum = World::stabilized_sum(&a, &b);
+        assert_eq!(*sum.to_color_array(), *expected.to_color_array());
+        assert_eq!(sum.total_grains(), expected.total_grains());
+        assert_eq!(*(b + a).to_color_array(), *expected.to_color_array());
+
+        let mut expected = world();
+        expected.add_sand_multi(&times(&both, 2));
+        assert_eq!(*(sum * 2).to_color_array(), *expected.to_color_array());
+    }
+
+    // Worlds of different sizes are lined up by their centers, and the sum grows to fit.
+    let mut a = World::growable([8; 3], [64; 3]);
+    a.add_sand(10);
+    let mut b = World::growable([8; 3], [64; 3]);
+    b.add_sand_at([5, 0, 0], 3_000);
+    let mut expected = World::growable([8; 3], [64; 3]);
+    expected.add_sand_multi(&[([0; 3], 10), ([5, 0, 0], 3_000)]);
+    a.add_world(&b);
+    assert_eq!(a.size(), expected.size());
+    assert_eq!(*a.to_color_array(), *expected.to_color_array());
+
+    let mut chunked = World::chunked([8; 3], [64; 3]);
+    chunked.add_sand_multi(&a_drops);
+    chunked.multiply(5);
+    let mut expected = World::chunked([8; 3], [64; 3]);
+    expected.add_sand_multi(&times(&a_drops, 5));
+    assert_eq!(chunked.size(), expected.size());
+    assert_eq!(*chunked.to_color_array(), *expected.to_color_array());
+    assert_eq!(chunked.total_grains(), 5 * 3_007);
+
+    let mut small = World::new([8; 3]);
+    small.add_sand(100);
+    assert!(small.try_multiply(1_000).is_err());
+    assert_eq!(small.total_grains(), 100);
+    small.multiply(0);
+    assert_eq!(small.total_grains(), 0);
+}
+
+#[test]
+fn identity() {
+    for &lattice in &Lattice::ALL {
+        let size = [12, 10, 11];
+        let identity = World::identity(size, lattice);
+        let heights: isize = identity.to_color_array().iter().map(|&h| h as isize).sum();
+        assert_eq!(identity.total_grains(), heights);
+
+        let mut max_stable = World::new(size)
+            .with_lattice(lattice)
+            .with_boundary(Boundary::Sink);
+        let center = max_stable.center();
+        let mut drops = Vec::new();
+        for x in 1..size[0] - 1 {
+            for y in 1..size[1] - 1 {
+                for z in 1..size[2] - 1 {
+                    let pos = [x, y, z];
+                    let pos = [0, 1, 2].map(|i| pos[i] as isize - center[i] as isize);
+                    drops.push((pos, lattice.threshold() as usize - 1));
+                }
+            }
+        }
+        max_stable.add_sand_multi(&drops);
+
+        for world in &[identity.clone(), max_stable] {
+            let sum = World::stabilized_sum(world, &identity);
+            assert_eq!(
+                *sum.to_color_array(),
+                *world.to_color_array(),
+                "{:?}",
+                lattice
+            );
+        }
+    }
+}
+
+#[test]
+fn recurrence() {
+    let size = [10, 9, 8];
+    for &lattice in &Lattice::ALL {
+        let identity = World::identity(size, lattice);
+        assert!(identity.is_recurrent(), "{:?}", lattice);
+
+        let empty = World::new(size).with_lattice(lattice);
+        assert!(!empty.is_recurrent());
+        assert_eq!(empty.unburnt().len(), 8 * 7 * 6);
+    }
+
+    // Two neighboring empty cells can never be reached by adding sand, so they never burn.
+    let mut world = World::new(size).with_boundary(Boundary::Sink);
+    let center = world.center();
+    let mut drops = Vec::new();
+    for x in 1..size[0] - 1 {
+        for y in 1..size[1] - 1 {
+            for z in 1..size[2] - 1 {
+                let pos = [x, y, z];
+                let pos = [0, 1, 2].map(|i| pos[i] as isize - center[i] as isize);
+                if pos != [0; 3] && pos != [1, 0, 0] {
+                    drops.push((pos, 5));
+                }
+            }
+        }
+    }
+    world.add_sand_multi(&drops);
+    let mut unburnt = world.unburnt();
+    unburnt.sort_unstable();
+    assert_eq!(unburnt, vec![[0; 3], [1, 0, 0]]);
+
+    // Driven piles settle into the recurrent configurations.
+    let mut world = World::new(size).with_boundary(Boundary::Sink);
+    RandomDrive::uniform(4).drive(&mut world, 20_000).unwrap();
+    assert!(world.is_recurrent());
+}
+
+#[test]
+fn odometer() {
+    let drops = [([0; 3], 2_000), ([3, -1, 2], 500), ([0, 1, 0], 3)];
+    let worlds = [
+        World::growable([8; 3], [64; 3]),
+        World::chunked([8; 3], [64; 3]),
+        World::new([12; 3]).with_boundary(Boundary::Sink),
+    ];
+    for world in worlds {
+        let mut world = world.with_odometer();
+        for drop in &drops {
+            world.add_sand_multi(&[*drop]);
+        }
+
+        let size = world.size();
+        let mut data = vec![0; size[0] * size[1] * size[2]];
+        let mut expected = vec![0; data.len()];
+        for drop in &drops {
+            let odometer =
+                topple_one_cell_at_a_time(size, Lattice::Cubic6, &mut data, &signed(&[*drop]));
+            for (n, t) in expected.iter_mut().zip(odometer) {
+                *n += t as u32;
+            }
+        }
+        assert_eq!(*world.to_color_array(), *data);
+        assert_eq!(world.odometer().unwrap(), &expected[..]);
+    }
+    assert_eq!(World::new([8; 3]).odometer(), None);
+
+    // Counting can start later, and doesn't change the avalanches.
+    let mut world = World::new([16; 3]).with_odometer();
+    let mut counted = World::new([16; 3]);
+    assert_eq!(world.add_sand(300), counted.add_sand(300));
+    let before = world.odometer().unwrap().into_owned();
+    counted.set_odometer(true);
+    assert_eq!(world.add_sand(700), counted.add_sand(700));
+    let since: Vec<_> = world
+        .odometer()
+        .unwrap()
+        .iter()
+        .zip(&before)
+        .map(|(n, b)| n - b)
+        .collect();
+    assert_eq!(counted.odometer().unwrap(), &since[..]);
+    counted.set_odometer(false);
+    assert_eq!(counted.odometer(), None);
+}
+
+#[test]
+fn symmetric() {
+    for &lattice in &Lattice::ALL {
+        for boundary in [Boundary::Wall, Boundary::Sink] {
+            let mut dense = World::new([31; 3])
+                .with_lattice(lattice)
+                .with_boundary(boundary)
+                .with_odometer();
+            let mut symmetric = World::symmetric([31; 3], [31; 3])
+                .with_lattice(lattice)
+                .with_boundary(boundary)
+                .with_odometer();
+            for &n in &[1, 40, 3_000, 20_000] {
+                let stats = dense.try_add_sand(n);
+                assert_eq!(symmetric.try_add_sand(n).ok(), stats.ok(), "{:?}", lattice);
+                assert_eq!(*symmetric.to_color_array(), *dense.to_color_array());
+                assert_eq!(symmetric.total_grains(), dense.total_grains());
+                assert_eq!(symmetric.odometer(), dense.odometer());
+            }
+        }
+    }
+
+    let mut growable = World::growable([9; 3], [64; 3]);
+    let mut symmetric = World::symmetric([9; 3], [64; 3]);
+    for &n in &[5_000, 30_000] {
+        assert_eq!(symmetric.add_sand(n), growable.add_sand(n));
+    }
+    let r = symmetric.size()[0] as isize / 2 - 1;
+    for x in -r..=r {
+        for y in -r..=r {
+            for z in -r..=r {
+                assert_eq!(symmetric[[x, y, z]], growable[[x, y, z]]);
+            }
+        }
+    }
+    assert_eq!(symmetric.total_grains(), growable.total_grains());
+
+    // Sums and products would need sand away from the center.
+    let before = symmetric.clone();
+    assert_eq!(symmetric.try_multiply(2), Err(ArithmeticError::Symmetric));
+    assert_eq!(
+        symmetric.try_add_world(&growable),
+        Err(ArithmeticError::Symmetric)
+    );
+    assert_eq!(
+        symmetric.try_add_sand_at([1, 0, 0], 10),
+        Err(ArithmeticError::Symmetric)
+    );
+    assert_eq!(
+        symmetric.try_add_sand_multi(&[([0; 3], 10), ([0, 0, -2], 10)]),
+        Err(ArithmeticError::Symmetric)
+    );
+    assert_eq!(
+        symmetric.try_remove_sand_at([0, 3, 0], 10),
+        Err(ArithmeticError::Symmetric)
+    );
+    assert_eq!(*symmetric.to_color_array(), *before.to_color_array());
+    assert_eq!(symmetric.total_grains(), before.total_grains());
+    growable.add_world(&symmetric);
+    assert!(World::symmetric([9; 3], [64; 3]).try_multiply(3).is_ok());
+}
+
+#[test]
+fn holes() {
+    for &lattice in &Lattice::ALL {
+        // Digging a hole is like piling up sand, upside down.
+        let mut pile = World::growable([8; 3], [64; 3]).with_lattice(lattice);
+        let mut hole = World::growable([8; 3], [64; 3]).with_lattice(lattice);
+        let mut symmetric = World::symmetric([9; 3], [64; 3]).with_lattice(lattice);
+        pile.add_sand(5_000);
+        let stats = hole.remove_sand_at([0; 3], 5_000);
+        assert_eq!(stats, symmetric.remove_sand_at([0; 3], 5_000));
+        assert_eq!(hole.total_grains(), -5_000);
+        let negated: Vec<i8> = pile.to_color_array().iter().map(|&h| -h).collect();
+        assert_eq!(*hole.to_color_array(), *negated, "{:?}", lattice);
+        assert_eq!(
+            *hole.to_color_array(),
+            *one_cell_at_a_time(hole.size(), lattice, &[([0; 3], -5_000)]),
+        );
+
+        // Piles and holes next to each other end up stable, and no grains go missing.
+        let drops = [([0; 3], 3_000), ([4, 0, 0], -3_000), ([0, -3, 2], -100)];
+        let mut worlds = [World::new([20; 3]), World::chunked([20; 3], [20; 3])]
+            .map(|world| world.with_lattice(lattice).with_boundary(Boundary::Sink));
+        for world in &mut worlds {
+            let stats = world.add_signed_multi(&drops);
+            world.add_signed_multi(&[([-2, 1, 0], 800), ([1, 1, 1], -2)]);
+            let threshold = lattice.threshold() as i8;
+            assert!(world.to_color_array().iter().all(|&h| h.abs() < threshold));
+            let heights: isize = world.to_color_array().iter().map(|&h| h as isize).sum();
+            assert_eq!(world.total_grains(), heights);
+            assert!(stats.topples > 0);
+        }
+        assert_eq!(worlds[0].to_color_array(), worlds[1].to_color_array());
+    }
+}
+
+#[test]
+fn divisible() {
+    for &lattice in &Lattice::ALL {
+        let mut world = DivisibleWorld::growable([9; 3], [64; 3]).with_lattice(lattice);
+        world.add_mass(3_000.);
+        assert_eq!(world.size(), [36; 3]);
+        assert!(world.masses().iter().all(|&m| m <= 1.001), "{:?}", lattice);
+        let total: f64 = world.masses().iter().map(|&m| m as f64).sum();
+        assert!((total - 3_000.).abs() < 0.01, "{:?}", lattice);
+        assert_eq!(world.total_mass(), 3_000.);
+
+        // The result has the symmetries of the lattice, and is nearly a ball of full cells.
+        let full = world.masses().iter().filter(|&&m| m >= 0.5).count();
+        assert!((full as f64 - 3_000.).abs() < 150., "{:?}", lattice);
+        let r = world.size()[0] as isize / 2 - 1;
+        for x in -r..=r {
+            for y in -r..=r {
+                for z in -r..=r {
+                    let m = world[[x, y, z]];
+                    for &pos in &[[-x, y, z], [y, x, z], [z, y, x]] {
+                        assert!((m - world[pos]).abs() < 0.002, "{:?}", lattice);
+                    }
+                }
+            }
+        }
+    }
+
+    // Unlike grains, mass that overflows a world cannot be partly kept.
+    let mut world = DivisibleWorld::new([9; 3]);
+    world.add_mass(100.);
+    let masses = world.masses().to_vec();
+    let err = world.try_add_mass_at([1, 0, 0], 1_000.).unwrap_err();
+    assert_eq!(err.total_grains, 1_100);
+    assert_eq!(world.masses(), &*masses);
+    assert_eq!(world.total_mass(), 100.);
+
+    // A sink takes whatever reaches it.
+    let mut world = DivisibleWorld::new([15; 3]).with_boundary(Boundary::Sink);
+    world.add_mass_at([2, -1, 0], 5_000.);
+    world.add_mass_at([-3, 0, 4], 100.);
+    assert!(world.masses().iter().all(|&m| m <= 1.001));
+    let total: f64 = world.masses().iter().map(|&m| m as f64).sum();
+    assert!((total - world.total_mass()).abs() < 0.01);
+    assert!(world.total_mass() < 13. * 13. * 13. + 1.);
+
+    // The abelian pile is narrower, since its cells hold more than 1 on average.
+    let mut pile = World::growable([9; 3], [64; 3]);
+    pile.add_sand(3_000);
+    let mut world = DivisibleWorld::growable([9; 3], [64; 3]);
+    world.add_mass(3_000.);
+    let abelian = (0..pile.size()[0] as isize / 2)
+        .filter(|&x| pile[[x, 0, 0]] != 0)
+        .max()
+        .unwrap();
+    let divisible = (0..world.size()[0] as isize / 2)
+        .filter(|&x| world[[x, 0, 0]] >= 0.5)
+        .max()
+        .unwrap();
+    assert_eq!(divisible, 9);
+    assert!(abelian < divisible && divisible < 2 * abelian);
+}
+
+#[test]
+fn manna() {
+    for &lattice in &Lattice::ALL {
+        // The seed decides everything, whether or not the world has to grow, and however it is stored.
+        let worlds = [
+            World::growable([9; 3], [128; 3]),
+            World::new([64; 3]),
+            World::chunked([9; 3], [128; 3]),
+        ];
+        let mut worlds =
+            worlds.map(|world| world.with_lattice(lattice).with_manna(7).with_odometer());
+        let stats: Vec<_> = worlds
+            .iter_mut()
+            .map(|world| world.add_sand_multi(&[([0; 3], 5_000), ([3, 0, -1], 100)]))
+            .collect();
+        assert!(stats.iter().all(|s| *s == stats[0]), "{:?}", lattice);
+        let r = worlds[0].size()[0] as isize / 2 - 1;
+        for x in -r..=r {
+            for y in -r..=r {
+                for z in -r..=r {
+                    let h = worlds[0][[x, y, z]];
+                    assert!(worlds.iter().all(|world| world[[x, y, z]] == h));
+                }
+            }
+        }
+
+        let world = &worlds[0];
+        let threshold = lattice.threshold() as i8;
+        assert!(world
+            .to_color_array()
+            .iter()
+            .all(|&h| 0 <= h && h < threshold));
+        let heights: isize = world.to_color_array().iter().map(|&h| h as isize).sum();
+        assert_eq!(heights, 5_100);
+        let odometer = world.odometer().unwrap();
+        assert_eq!(
+            stats[0].topples,
+            odometer.iter().map(|&n| n as u64).sum::<u64>()
+        );
+        assert_eq!(stats[0].sites, odometer.iter().filter(|&&n| n > 0).count());
+        assert!(stats[0].waves > 0 && stats[0].waves < stats[0].topples);
+
+        // Grains go somewhere else with another seed.
+        let mut other = World::growable([9; 3], [128; 3])
+            .with_lattice(lattice)
+            .with_manna(8);
+        other.add_sand_multi(&[([0; 3], 5_000), ([3, 0, -1], 100)]);
+        assert_ne!(other.to_color_array(), world.to_color_array());
+    }
+
+    // Copies of a world carry on the same way, and holes untopple.
+    let mut world = World::new([20; 3])
+        .with_boundary(Boundary::Sink)
+        .with_manna(1);
+    world.add_sand(3_000);
+    let mut copy = world.clone();
+    let drops = [([0; 3], -2_000), ([2, 1, 0], 900)];
+    let stats = world.add_signed_multi(&drops);
+    assert_eq!(copy.add_signed_multi(&drops), stats);
+    assert_eq!(copy.to_color_array(), world.to_color_array());
+    assert!(world.to_color_array().iter().all(|&h| h.abs() < 6));
+    let heights: isize = world.to_color_array().iter().map(|&h| h as isize).sum();
+    assert_eq!(world.total_grains(), heights);
+
+    // A random drive gives the same histograms every time.
+    let histograms = || {
+        let mut world = World::new([16; 3])
+            .with_boundary(Boundary::Sink)
+            .with_manna(2);
+        let mut drive = RandomDrive::uniform(3);
+        let mut histograms = AvalancheHistograms::new();
+        for _ in 0..20_000 {
+            let pos = drive.next_site(&world);
+            histograms.record(&world.add_sand_at(pos, 1));
+        }
+        histograms
+    };
+    let first = histograms();
+    assert_eq!(first, histograms());
+    assert!(first.topples.bins().count() > 4);
+    assert!(first.lost.total() > 0);
+}
+
+#[test]
+fn fixed_energy() {
+    for &lattice in &Lattice::ALL {
+        let threshold = lattice.threshold() as i32;
+        let mut world = FixedEnergyWorld::random([12, 10, 8], lattice, 0.8 * threshold as f64, 3);
+        let [nx, ny, nz] = world.size();
+        let index = |x: usize, y: usize, z: usize| (x * ny + y) * nz + z;
+        assert_eq!(
+            world.heights().iter().map(|&h| h as usize).sum::<usize>(),
+            world.total_grains()
+        );
+
+        // Every unstable cell topples at once, and grains wrap around the edges.
+        for _ in 0..20 {
+            let before = world.heights().to_vec();
+            let active = before.iter().filter(|&&h| h >= threshold).count();
+            assert_eq!(world.active_density(), active as f64 / before.len() as f64);
+            let mut expected = before.clone();
+            for x in 0..nx {
+                for y in 0..ny {
+                    for z in 0..nz {
+                        if before[index(x, y, z)] < threshold {
+                            continue;
+                        }
+                        expected[index(x, y, z)] -= threshold;
+                        for d in lattice.neighbors() {
+                            let [x, y, z] = [(x, nx, d[0]), (y, ny, d[1]), (z, nz, d[2])]
+                                .map(|(c, n, d)| (c as isize + d).rem_euclid(n as isize) as usize);
+                            expected[index(x, y, z)] += 1;
+                        }
+                    }
+                }
+            }
+            assert_eq!(world.step(), active as f64 / before.len() as f64);
+            assert_eq!(world.heights(), &expected[..], "{:?}", lattice);
+        }
+        assert_eq!(world.steps(), 20);
+    }
+
+    // Activity dies out at a low density, but not at a high one, and never loses grains.
+    let series = |density, manna| {
+        let world = FixedEnergyWorld::random([12; 3], Lattice::Cubic6, density, 5);
+        let mut world = if manna { world.with_manna() } else { world };
+        let series: Vec<f64> = world.activity().take(200).collect();
+        let heights: usize = world.heights().iter().map(|&h| h as usize).sum();
+        assert_eq!(heights, world.total_grains());
+        series
+    };
+    for &manna in &[false, true] {
+        let low = series(1.5, manna);
+        assert!(low.len() < 200 && low.iter().all(|&a| a > 0.));
+        let high = series(5.5, manna);
+        assert_eq!(high.len(), 200);
+        assert!(high.iter().all(|&a| a > 0.1));
+    }
+
+    // The seed decides everything, even by the Manna rule.
+    assert_eq!(series(4., true), series(4., true));
+    let other: Vec<f64> = FixedEnergyWorld::random([12; 3], Lattice::Cubic6, 4., 6)
+        .with_manna()
+        .activity()
+        .take(100)
+        .collect();
+    assert_ne!(series(4., true)[..100], other[..]);
+}
+
+#[test]
+fn playback() {
+    // Toppling a generation at a time, or a few cells at a time, ends up the same as all at once,
+    // whether adding sand to a pile, or digging holes in an empty world.
+    for &lattice in &Lattice::ALL {
+        let worlds = [
+            World::growable([9; 3], [64; 3]),
+            World::chunked([9; 3], [64; 3]),
+            World::new([24; 3]).with_boundary(Boundary::Sink),
+        ];
+        for world in worlds {
+            let world = world.with_lattice(lattice).with_odometer();
+            let mut pile = world.clone();
+            pile.add_sand(300);
+            for (world, drops) in [
+                (pile, vec![([0; 3], 2_000), ([2, -1, 0], 300)]),
+                (world, vec![([0; 3], -1_000)]),
+            ] {
+                let mut expected = world.clone();
+                let expected_stats = expected.add_signed_multi(&drops);
+
+                let mut by_generation = world.clone();
+                let mut avalanche = by_generation.start_avalanche(&drops).unwrap();
+                let mut generations = 0;
+                while !avalanche.next_generation(&mut by_generation).unwrap() {
+                    generations += 1;
+                    assert_eq!(avalanche.generations(), generations);
+                }
+                assert_eq!(avalanche.topples(), expected_stats.topples);
+                assert_eq!(avalanche.finish(&mut by_generation), expected_stats);
+
+                let mut by_topples = world.clone();
+                let mut avalanche = by_topples.start_avalanche(&drops).unwrap();
+                while !avalanche.advance(&mut by_topples, 1_000).unwrap() {
+                    assert!(avalanche.unstable() > 0 || avalanche.grains_left() > 0);
+                }
+                assert_eq!(avalanche.finish(&mut by_topples), expected_stats);
+
+                for other in [&by_generation, &by_topples] {
+                    assert_eq!(other.size(), expected.size(), "{:?}", lattice);
+                    assert_eq!(other.to_color_array(), expected.to_color_array());
+                    assert_eq!(other.odometer(), expected.odometer());
+                    assert_eq!(other.total_grains(), expected.total_grains());
+                    assert_eq!(other.memory_size(), expected.memory_size());
+                }
+            }
+        }
+    }
+
+    // The first generation only topples the cell the grains are dropped on.
+    let mut world = World::new([9; 3]);
+    let mut avalanche = world.start_avalanche(&[([0; 3], 12)]).unwrap();
+    assert!(!avalanche.next_generation(&mut world).unwrap());
+    assert_eq!((avalanche.topples(), avalanche.unstable()), (1, 0));
+    assert_eq!(avalanche.grains_left(), 6);
+    assert_eq!(world[[0isize, 0, 0]], 0);
+    assert_eq!(world[[1isize, 0, 0]], 1);
+
+    // Cancelling, or failing, leaves the world as it was.
+    let mut world = World::chunked([9; 3], [32; 3]);
+    world.add_sand(100);
+    let before = world.clone();
+    let mut avalanche = world.start_avalanche(&[([0; 3], 1_000)]).unwrap();
+    avalanche.advance(&mut world, 5_000).unwrap();
+    assert_ne!(world.to_color_array(), before.to_color_array());
+    avalanche.cancel(&mut world);
+    let mut avalanche = world.start_avalanche(&[([0; 3], 100_000)]).unwrap();
+    let err = avalanche.advance(&mut world, u64::MAX).unwrap_err();
+    assert_eq!(err.total_grains, 100_100);
+    for world in [&world, &world.clone()] {
+        assert_eq!(world.size(), before.size());
+        assert_eq!(world.to_color_array(), before.to_color_array());
+        assert_eq!(world.memory_size(), before.memory_size());
+        assert_eq!(world.total_grains(), 100);
+    }
+}
+
+#[test]
+fn adding_a_little_at_a_time() {
+    let worlds = [
+        World::growable([9; 3], [64; 3]),
+        World::chunked([9; 3], [64; 3]),
+        World::new([20; 3]).with_boundary(Boundary::Sink),
+        World::growable([9; 3], [64; 3]).with_manna(3),
+        World::symmetric([9; 3], [65; 3]),
+    ];
+    for world in worlds {
+        let mut world = world.with_lattice(Lattice::Fcc12).with_odometer();
+        world.add_sand(100);
+        let drops = if world.is_symmetric() {
+            vec![([0; 3], 10_000)]
+        } else {
+            vec![([0; 3], 10_000), ([3, 1, 0], -2_000)]
+        };
+        let mut expected = world.clone();
+        let expected_stats = expected.add_signed_multi(&drops);
+
+        // Doing a sweep at a time gives the same result as doing it all at once.
+        world.start_adding(&drops);
+        let total = drops.iter().map(|(_, n)| n.unsigned_abs()).sum();
+        let mut grains_processed = 0;
+        let mut calls = 0;
+        let stats = loop {
+            calls += 1;
+            if let Some(result) = world.keep_adding(|| true) {
+                break result.unwrap();
+            }
+            let progress = world.progress().unwrap();
+            assert_eq!(progress.total, total);
+            assert!(progress.grains_processed >= grains_processed);
+            grains_processed = progress.grains_processed;
+        };
+        assert!(calls > 10);
+        assert!(!world.is_adding() && world.progress().is_none());
+        assert_eq!(stats, expected_stats);
+        assert_eq!(world.size(), expected.size());
+        assert_eq!(world.to_color_array(), expected.to_color_array());
+        assert_eq!(world.odometer(), expected.odometer());
+        assert_eq!(world.total_grains(), expected.total_grains());
+    }
+
+    // Partway through, cells are unstable, and cancelling puts the world back the way it was.
+    // A pile at the center would be stabilized all at once, using its symmetries, so this one is off center,
+    // and a bigger one would be stabilized on its own first, from an estimate, so this one is small.
+    for world in [
+        World::growable([9; 3], [64; 3]),
+        World::chunked([9; 3], [64; 3]),
+    ] {
+        let mut world = world;
+        world.add_sand(100);
+        let before = world.clone();
+        world.start_adding(&[([1, 0, 0], 4_000)]);
+        for _ in 0..40 {
+            assert!(world.keep_adding(|| true).is_none());
+        }
+        // Between bits of the pile, nothing is unstable for a sweep or two.
+        while world.progress().unwrap().unstable == 0 {
+            assert!(world.keep_adding(|| true).is_none());
+        }
+        let progress = world.progress().unwrap();
+        assert!(progress.unstable > 0 && progress.grains_processed > 0);
+        assert!(progress.grains_processed < 4_000);
+        world.cancel_adding();
+        assert!(!world.is_adding());
+        assert_eq!(world.size(), before.size());
+        assert_eq!(world.to_color_array(), before.to_color_array());
+        assert_eq!(world.memory_size(), before.memory_size());
+        assert_eq!(world.total_grains(), 100);
+
+        // The world doesn't change until the estimated pile is stable.
+        world.start_adding(&[([1, 0, 0], 20_000)]);
+        assert!(world.keep_adding(|| true).is_none());
+        world.cancel_adding();
+        assert_eq!(world.size(), before.size());
+        assert_eq!(world.to_color_array(), before.to_color_array());
+        assert_eq!(world.total_grains(), 100);
+        world.add_sand(1);
+    }
+
+    // A symmetric world grows as it goes, but cancelling shrinks it back.
+    let mut world = World::symmetric([9; 3], [65; 3]);
+    world.add_sand(100);
+    let before = world.clone();
+    world.start_adding(&[([0; 3], 200_000)]);
+    while world.size() == before.size() {
+        assert!(world.keep_adding(|| true).is_none());
+    }
+    assert!(world.progress().unwrap().unstable > 0);
+    world.cancel_adding();
+    assert_eq!(world.size(), before.size());
+    assert_eq!(world.to_color_array(), before.to_color_array());
+    assert_eq!(world.total_grains(), 100);
+
+    // So does failing.
+    let mut world = World::growable([9; 3], [16; 3]);
+    world.add_sand(10);
+    let before = world.clone();
+    world.start_adding(&[([0; 3], 100_000)]);
+    let err = loop {
+        if let Some(result) = world.keep_adding(|| true) {
+            break result.unwrap_err();
+        }
+    };
+    assert_eq!(err.total_grains, 100_010);
+    assert_eq!(world.to_color_array(), before.to_color_array());
+}
+
+#[test]
+fn waves() {
+    for &lattice in &Lattice::ALL {
+        let worlds = [
+            World::growable([9; 3], [64; 3]),
+            World::chunked([9; 3], [64; 3]),
+            World::new([24; 3]).with_boundary(Boundary::Sink),
+        ];
+        for world in worlds {
+            let mut world = world.with_lattice(lattice).with_odometer();
+            world.add_sand(300);
+            for num_grains in [1, 50, 2_000] {
+                let mut expected = world.clone();
+                let expected_stats = expected.add_sand_at([1, 0, 0], num_grains);
+                let mut by_waves = world.clone();
+                let (stats, waves) = by_waves.add_sand_in_waves([1, 0, 0], num_grains);
+
+                // The seed starts every wave, and each cell topples at most once in it.
+                assert_eq!(stats, expected_stats, "{:?}", lattice);
+                assert_eq!(waves.len() as u64, stats.waves);
+                assert!(waves.iter().all(|wave| wave.contains(&[1, 0, 0])));
+                let topples: usize = waves.iter().map(|wave| wave.len()).sum();
+                assert_eq!(topples as u64, stats.topples);
+
+                assert_eq!(by_waves.size(), expected.size());
+                assert_eq!(by_waves.to_color_array(), expected.to_color_array());
+                assert_eq!(by_waves.odometer(), expected.odometer());
+                assert_eq!(by_waves.total_grains(), expected.total_grains());
+                assert_eq!(by_waves.memory_size(), expected.memory_size());
+            }
+        }
+    }
+
+    // Failing leaves the world as it was.
+    let mut world = World::chunked([9; 3], [32; 3]);
+    world.add_sand(100);
+    let before = world.clone();
+    let err = world.try_add_sand_in_waves([0; 3], 100_000).unwrap_err();
+    assert_eq!(err.total_grains, 100_100);
+    assert_eq!(world.size(), before.size());
+    assert_eq!(world.to_color_array(), before.to_color_array());
+    assert_eq!(world.memory_size(), before.memory_size());
+}
+
+#[test]
+fn packed() {
+    let mut with_holes = World::growable([16; 3], [64; 3]);
+    with_holes.add_sand(2_000);
+    with_holes.remove_sand_at([3, 0, 0], 500);
+    let cases = [
+        (World::growable([9; 3], [64; 3]), 3),
+        (with_holes, 4),
+        (
+            World::chunked([9; 3], [64; 3]).with_lattice(Lattice::Cubic26),
+            5,
+        ),
+        (
+            World::symmetric([9; 3], [65; 3]).with_lattice(Lattice::Fcc12),
+            4,
+        ),
+    ];
+    for (world, bits) in cases {
+        let mut world = world.with_odometer();
+        world.add_sand(5_000);
+        let packed = world.to_packed();
+        assert_eq!(packed.bits(), bits, "{:?}", world.lattice());
+        assert_eq!(packed.size(), world.size());
+        assert_eq!(packed.to_color_array(), &world.to_color_array()[..]);
+
+        let [nx, ny, nz] = world.size();
+        let center = world.center().map(|c| c as isize);
+        let mut slab = vec![0; ny * nz];
+        for x in 0..nx {
+            packed.unpack_slab(x, &mut slab);
+            for y in 0..ny {
+                for z in 0..nz {
+                    let pos = [x as isize, y as isize, z as isize];
+                    let pos = [0, 1, 2].map(|i| pos[i] - center[i]);
+                    assert_eq!(packed.get(pos), world[pos]);
+                    assert_eq!(packed[[x, y, z]], world[pos]);
+                    assert_eq!(slab[y * nz + z], world[pos]);
+                }
+            }
+        }
+
+        // Unpacked, it carries on the same way.
+        let mut unpacked = packed.to_world();
+        let stats = unpacked.add_sand(1_000);
+        assert_eq!(stats, world.add_sand(1_000));
+        assert_eq!(unpacked.to_color_array(), world.to_color_array());
+        assert_eq!(unpacked.odometer(), world.odometer());
+        assert_eq!(unpacked.total_grains(), world.total_grains());
+    }
+
+    // A pile with no holes is well under half the size. Heights that don't fit need it packed again.
+    let mut world = World::new([32; 3]);
+    world.add_sand(20_000);
+    let mut packed = world.to_packed();
+    assert!(2 * packed.memory_size() < world.memory_size());
+    assert!(packed.fits(5) && !packed.fits(-20));
+    packed.repack(-20, 0);
+    assert_eq!(packed.bits(), 5);
+    packed.set([1, 2, 3], -20);
+    packed.set([0; 3], 0);
+    assert_eq!(packed.get([1, 2, 3]), -20);
+    assert_eq!(packed.get([0; 3]), 0);
+    let center = world.center();
+    assert_eq!(packed[[center[0] + 1, center[1] + 2, center[2] + 3]], -20);
+    let mut expected = world.to_color_array().into_owned();
+    let [_, ny, nz] = world.size();
+    expected[((center[0] + 1) * ny + center[1] + 2) * nz + center[2] + 3] = -20;
+    expected[(center[0] * ny + center[1]) * nz + center[2]] = 0;
+    assert_eq!(packed.to_color_array(), expected);
+    packed.repack(0, 0);
+    assert_eq!(packed.bits(), 5);
+}